@@ -0,0 +1,94 @@
+// Per-frame cost of the map queries on a large map. Run with `cargo bench`
+// from the repository root, so that test.tmx and its tileset are found.
+
+use {
+    std::{
+        hint::black_box,
+        time::{Duration, Instant},
+    },
+    rust_game::{
+        alg::P2,
+        game::map::{Map, TILE_SIZE},
+    },
+};
+
+const SIZE: i32 = 1024;
+
+// Rough screen size in tiles at the default camera scale.
+const VIEW_W: i32 = 80;
+const VIEW_H: i32 = 45;
+
+// test.tmx tiled out to SIZE by SIZE cells, with a scatter of gaps and a
+// solid floor.
+fn big_map() -> Map {
+    let mut map = Map::load_headless("test.tmx").expect("loading test.tmx");
+
+    let b = map.bounds();
+    let index = (b.bottom .. b.top)
+        .flat_map(|y| (b.left .. b.right).map(move |x| (x, y)))
+        .find_map(|(x, y)| map.tile_at(x, y).map(|(_, _, index)| index))
+        .expect("test.tmx has no tiles");
+
+    for y in 0 .. SIZE {
+        for x in 0 .. SIZE {
+            let hash = (x.wrapping_mul(73_856_093) ^ y.wrapping_mul(19_349_663)) & 7;
+            if y < 4 || hash == 0 {
+                map.set_tile(x, y, index);
+            }
+        }
+    }
+    map
+}
+
+fn px(x: i32, y: i32) -> P2 {
+    P2::new((x * TILE_SIZE) as f32, (y * TILE_SIZE) as f32)
+}
+
+// Runs `f` in doubling batches until a batch takes long enough to time, and
+// prints the time per call.
+fn bench<R>(name: &str, mut f: impl FnMut() -> R) {
+    let mut iters: u32 = 1;
+    loop {
+        let start = Instant::now();
+        for _ in 0 .. iters {
+            black_box(f());
+        }
+        let elapsed = start.elapsed();
+        if elapsed >= Duration::from_millis(500) {
+            println!("{:<24} {:>12.1} ns/iter", name, elapsed.as_nanos() as f64 / iters as f64);
+            return;
+        }
+        iters *= 2;
+    }
+}
+
+fn main() {
+    let map = big_map();
+
+    let (min, max) = (px(500, 500), px(500 + VIEW_W, 500 + VIEW_H));
+    bench("colliders_in_view", || map.colliders_in(min, max).count());
+
+    let (min, max) = (px(500, 500), px(502, 503));
+    bench("colliders_near_body", || map.colliders_in(min, max).count());
+
+    let (from, to) = (px(10, 900), px(1000, 20));
+    bench("tiles_along_long_ray", || map.tiles_along(from, to).count());
+
+    let (from, to) = (px(512, 1000), px(520, 0));
+    bench("tiles_along_first_hit", || map.tiles_along(from, to).next());
+
+    let p = px(700, 700) + nalgebra::Vector2::new(3.5, 7.25);
+    bench("nearest_solid", || map.nearest_solid(p, 160.0));
+
+    bench("tile_at_visible", || {
+        let mut count = 0;
+        for y in 500 .. 500 + VIEW_H {
+            for x in 500 .. 500 + VIEW_W {
+                if map.tile_at(x, y).is_some() {
+                    count += 1;
+                }
+            }
+        }
+        count
+    });
+}
@@ -113,21 +113,11 @@ impl Linear2 for Seg2 {
 }
 
 impl Seg2 {
-    fn new_from_ray(ray: Ray2, dist: f32) -> Seg2 {
-        Seg2 { ray, dist }
-    }
-
-    fn new_from_points(a: P2, b: P2) -> Seg2 {
+    pub fn new_from_points(a: P2, b: P2) -> Seg2 {
         let (dir, dist) = (b - a).unit_and_norm();
         let ray = Ray2::new(a, dir);
         Seg2 { ray, dist }
     }
-
-    fn new_from_displacement(p: P2, d: V2) -> Seg2 {
-        let (dir, dist) = d.unit_and_norm();
-        let ray = Ray2::new(p, dir);
-        Seg2 { ray, dist }
-    }
 }
 
 
@@ -140,9 +130,9 @@ pub enum Side {
     Right
 }
 
-impl Into<usize> for Side {
-    fn into(self) -> usize {
-        match self {
+impl From<Side> for usize {
+    fn from(side: Side) -> usize {
+        match side {
             Side::On    => 0,
             Side::Left  => 1,
             Side::Right => 2
@@ -178,6 +168,45 @@ impl Shape {
     pub fn new_from_vec(verts: Vec<V2>) -> Shape {
         Shape { verts }
     }
+
+    // positive for counter-clockwise winding in a y-up frame
+    pub fn signed_area(&self) -> f32 {
+        let n = self.verts.len();
+        (0..n)
+            .map(|i| {
+                let a = self.verts[i];
+                let b = self.verts[(i + 1) % n];
+                a.x * b.y - b.x * a.y
+            })
+            .sum::<f32>() * 0.5
+    }
+
+    // Every corner must turn the same way, and the turns must add up to a
+    // single revolution; a star turns the same way at each point but winds
+    // around twice.
+    pub fn is_convex(&self) -> bool {
+        let n = self.verts.len();
+        let mut sign = 0.0;
+        let mut turned = 0.0;
+        for i in 0..n {
+            let a = self.verts[i];
+            let b = self.verts[(i + 1) % n];
+            let c = self.verts[(i + 2) % n];
+            let (ab, bc) = (b - a, c - b);
+            let cross = ab.perp(&bc);
+            turned += cross.atan2(ab.dot(&bc));
+            if cross.abs() < EPSILON {
+                continue;
+            }
+            else if sign == 0.0 {
+                sign = cross.signum();
+            }
+            else if cross.signum() != sign {
+                return false;
+            }
+        }
+        (turned.abs() - 2.0 * std::f32::consts::PI).abs() < 1e-3
+    }
 }
 
 pub fn shape_side_of_line(l: Line2, p: P2, s: &Shape) -> Side {
@@ -199,3 +228,32 @@ pub fn shape_side_of_line(l: Line2, p: P2, s: &Shape) -> Side {
     prev_side
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shape(points: &[(f32, f32)]) -> Shape {
+        Shape::new_from_vec(points.iter().map(|&(x, y)| V2::new(x, y)).collect())
+    }
+
+    #[test]
+    fn convexity() {
+        let square = shape(&[(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)]);
+        assert!(square.is_convex());
+        let clockwise = Shape::new_from_vec(square.verts.iter().rev().cloned().collect());
+        assert!(clockwise.is_convex());
+
+        let notch = shape(&[(0.0, 0.0), (2.0, 0.0), (2.0, 2.0), (1.0, 1.0), (0.0, 2.0)]);
+        assert!(!notch.is_convex());
+
+        // every corner turns left, but the outline crosses itself
+        let star: Vec<(f32, f32)> = (0..5)
+            .map(|i| {
+                let angle = (i * 2) as f32 * std::f32::consts::PI * 2.0 / 5.0;
+                (angle.cos(), angle.sin())
+            })
+            .collect();
+        assert!(!shape(&star).is_convex());
+    }
+}
@@ -0,0 +1,37 @@
+
+// Converts a .tmx map and its tileset into a cooked map for fast loading.
+//
+//     cook level.tmx [level.rgmap]
+
+use {
+    std::{
+        path::PathBuf,
+        process,
+    },
+    rust_game::game::map::cook,
+};
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (src, dst) = match args.as_slice() {
+        [src] => {
+            let src = PathBuf::from(src);
+            let dst = src.with_extension(cook::EXTENSION);
+            (src, dst)
+        }
+
+        [src, dst] => (PathBuf::from(src), PathBuf::from(dst)),
+
+        _ => {
+            eprintln!("usage: cook MAP.tmx [OUT.{}]", cook::EXTENSION);
+            process::exit(2);
+        }
+    };
+
+    if let Err(e) = cook::cook_to_file(&src, &dst) {
+        eprintln!("{}: {}", src.display(), e);
+        process::exit(1);
+    }
+
+    eprintln!("cooked {} -> {}", src.display(), dst.display());
+}
@@ -0,0 +1,310 @@
+
+// Checks .tmx maps for problems that would make the game misbehave or panic
+// at load time. Needs no window or GL context, so it can run in CI.
+//
+//     maplint level.tmx [more.tmx ...]
+//
+// Exits with 1 if any map has errors, 2 if a map could not be read at all.
+
+use {
+    std::{
+        collections::BTreeSet,
+        path::Path,
+        process,
+    },
+    rust_game::game::map::{self, tmx, Tileset, TILE_SIZE},
+};
+
+// Problems found in a map, printed as they're found and kept for the
+// summary.
+#[derive(Default)]
+struct Report {
+    errors:   Vec<String>,
+    warnings: Vec<String>,
+}
+
+impl Report {
+    fn error(&mut self, path: &Path, msg: String) {
+        println!("{}: error: {}", path.display(), msg);
+        self.errors.push(msg);
+    }
+
+    fn warn(&mut self, path: &Path, msg: String) {
+        println!("{}: warning: {}", path.display(), msg);
+        self.warnings.push(msg);
+    }
+}
+
+fn tile_count(ts: &tmx::Tileset) -> u32 {
+    if ts.tile_count != 0 {
+        return ts.tile_count;
+    }
+    ts.image.as_ref()
+        .map(|im| (im.width / ts.tile_width) * (im.height / ts.tile_height))
+        .unwrap_or(0)
+}
+
+// Returns the tileset as the game would build it, unless that fails.
+fn check_tileset(report: &mut Report, path: &Path, ts_ref: &tmx::TilesetRef)
+    -> Option<Tileset>
+{
+    let ts = &ts_ref.tileset;
+    let ts_path = ts_ref.path.as_deref().unwrap_or(path);
+
+    if ts.tile_width != TILE_SIZE as u32 || ts.tile_height != TILE_SIZE as u32 {
+        report.error(ts_path, format!(
+            "tile size is {}x{}, expected {}x{}",
+            ts.tile_width, ts.tile_height, TILE_SIZE, TILE_SIZE
+        ));
+    }
+
+    match &ts.image {
+        None => report.error(ts_path, "tileset has no image".to_string()),
+
+        Some(image) => match image::open(&image.path) {
+            Err(e) => report.error(ts_path, format!(
+                "unreadable image {}: {}", image.path.display(), e
+            )),
+
+            Ok(im) => {
+                use image::GenericImageView;
+                let (w, h) = im.dimensions();
+                if w % ts.tile_width != 0 || h % ts.tile_height != 0 {
+                    report.warn(ts_path, format!(
+                        "image {} is {}x{}, not a whole number of tiles",
+                        image.path.display(), w, h
+                    ));
+                }
+            }
+        }
+    }
+
+    for tile in &ts.tiles {
+        let group = match &tile.objects {
+            Some(group) => group,
+            None        => continue,
+        };
+
+        if group.objects.len() > 1 {
+            report.warn(ts_path, format!(
+                "tile {}: {} collider objects will be merged into one shape",
+                tile.id, group.objects.len()
+            ));
+        }
+
+        if group.objects.iter().any(|obj| obj.rotation != 0.0) {
+            report.warn(ts_path, format!(
+                "tile {}: collider rotation is ignored", tile.id
+            ));
+        }
+
+        let (_, ignored) = map::read_collider(group);
+        for obj in ignored {
+            report.warn(ts_path, format!(
+                "tile {}: unsupported collider shape {:?} is ignored",
+                tile.id, obj.shape
+            ));
+        }
+    }
+
+    // the colliders the game will use, drawn or traced
+    let tileset = match Tileset::load_headless(ts_ref) {
+        Ok(tileset) => tileset,
+        Err(e) => {
+            report.error(ts_path, format!("could not build colliders: {}", e));
+            return None;
+        }
+    };
+
+    for (id, tile) in tileset.tiles() {
+        let shape = tile.collider();
+        if shape.verts.len() < 3 || shape.signed_area().abs() < 0.5 {
+            report.error(ts_path, format!(
+                "tile {}: degenerate collider", id
+            ));
+        }
+        else if !shape.is_convex() {
+            report.error(ts_path, format!(
+                "tile {}: concave collider", id
+            ));
+        }
+    }
+
+    Some(tileset)
+}
+
+fn lint(path: &Path) -> Result<Report, tmx::TmxError> {
+    let doc = tmx::Document::load(path)?;
+    let mut report = Report::default();
+
+    if doc.tilesets.len() != 1 {
+        report.error(path, format!(
+            "expected exactly one tileset, found {}", doc.tilesets.len()
+        ));
+    }
+
+    let tilesets: Vec<Option<Tileset>> = doc.tilesets.iter()
+        .map(|ts_ref| check_tileset(&mut report, path, ts_ref))
+        .collect();
+
+    let main_layer = doc.tile_layer("main");
+    if main_layer.is_none() {
+        report.error(path, "layer \"main\" is missing".to_string());
+    }
+
+    let mut used_gids = BTreeSet::new();
+    for layer in &doc.layers {
+        if let tmx::Layer::Tiles(layer) = layer {
+            for chunk in &layer.chunks {
+                used_gids.extend(chunk.gids.iter().cloned().filter(|&gid| gid != 0));
+            }
+        }
+    }
+
+    for &gid in &used_gids {
+        let owner = doc.tilesets.iter()
+            .find(|ts| gid >= ts.first_gid && gid - ts.first_gid < tile_count(&ts.tileset));
+        if owner.is_none() {
+            report.error(path, format!("gid {} is not in any tileset", gid));
+        }
+    }
+
+    // every tile placed in the main layer needs a collider, drawn or traced,
+    // or the map treats the cell as empty
+    if let (Some(layer), Some(ts_ref), Some(Some(tileset))) =
+        (main_layer, doc.tilesets.first(), tilesets.first())
+    {
+        let main_gids: BTreeSet<u32> = layer.chunks.iter()
+            .flat_map(|chunk| chunk.gids.iter().cloned())
+            .filter(|&gid| gid != 0)
+            .collect();
+
+        for gid in main_gids {
+            if gid < ts_ref.first_gid { continue; }
+            let id = gid - ts_ref.first_gid;
+            // gids past the end were reported above
+            if id < tile_count(&ts_ref.tileset) && !tileset.has_tile(id) {
+                report.error(path, format!(
+                    "tile {} (gid {}) is used in \"main\" but has no collider",
+                    id, gid
+                ));
+            }
+        }
+    }
+
+    let has_spawn = doc.object_group("entities")
+        .map(|group| group.objects.iter().any(|obj| obj.name == "start"))
+        .unwrap_or(false);
+    if !has_spawn {
+        report.error(path, "no \"start\" object in group \"entities\"".to_string());
+    }
+
+    Ok(report)
+}
+
+fn main() {
+    let paths: Vec<String> = std::env::args().skip(1).collect();
+    if paths.is_empty() {
+        eprintln!("usage: maplint MAP.tmx [MAP.tmx ...]");
+        process::exit(2);
+    }
+
+    let mut status = 0;
+    for path in &paths {
+        let path = Path::new(path);
+        match lint(path) {
+            Err(e) => {
+                println!("{}: error: could not read map: {}", path.display(), e);
+                status = 2;
+            }
+
+            Ok(report) => {
+                println!(
+                    "{}: {} error(s), {} warning(s)",
+                    path.display(), report.errors.len(), report.warnings.len()
+                );
+                if !report.errors.is_empty() && status == 0 {
+                    status = 1;
+                }
+            }
+        }
+    }
+
+    process::exit(status);
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::{fs, path::PathBuf},
+    };
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("maplint-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Tile 0 is fine, 1 is a sliver, 2 is concave and 3 has an ellipse
+    // along with its box. Tile 4 has no collider.
+    const TILESET: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<tileset version="1.2" name="broken" tilewidth="16" tileheight="16" tilecount="16" columns="4">
+ <image source="{image}" width="64" height="64"/>
+ <tile id="0"><objectgroup><object id="1" x="0" y="0" width="16" height="16"/></objectgroup></tile>
+ <tile id="1"><objectgroup><object id="1" x="0" y="0"><polygon points="0,0 16,0 16,0.01"/></object></objectgroup></tile>
+ <tile id="2"><objectgroup><object id="1" x="0" y="0"><polygon points="0,0 16,0 8,8 16,16 0,16"/></object></objectgroup></tile>
+ <tile id="3"><objectgroup>
+  <object id="1" x="0" y="0" width="16" height="16"/>
+  <object id="2" x="4" y="4" width="8" height="8"><ellipse/></object>
+ </objectgroup></tile>
+</tileset>
+"#;
+
+    // Uses every tile in "main", plus a gid past the end of the tileset,
+    // and has no start.
+    const MAP: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<map version="1.2" orientation="orthogonal" renderorder="right-down" width="6" height="1" tilewidth="16" tileheight="16" infinite="0">
+ <tileset firstgid="1" source="broken.tsx"/>
+ <layer id="1" name="main" width="6" height="1">
+  <data encoding="csv">1,2,3,4,5,99</data>
+ </layer>
+ <objectgroup id="2" name="entities"/>
+</map>
+"#;
+
+    #[test]
+    fn finds_known_problems() {
+        let dir = temp_dir("broken");
+        let image = std::env::current_dir().unwrap().join("tiles.png");
+        fs::write(dir.join("broken.tsx"), TILESET.replace("{image}", &image.display().to_string()))
+            .unwrap();
+        fs::write(dir.join("broken.tmx"), MAP).unwrap();
+
+        let report = lint(&dir.join("broken.tmx")).unwrap();
+        let mut errors = report.errors.clone();
+        errors.sort();
+        assert_eq!(errors, [
+            "gid 99 is not in any tileset",
+            "no \"start\" object in group \"entities\"",
+            "tile 1: degenerate collider",
+            "tile 2: concave collider",
+            "tile 4 (gid 5) is used in \"main\" but has no collider",
+        ]);
+
+        let mut warnings = report.warnings.clone();
+        warnings.sort();
+        assert_eq!(warnings, [
+            "tile 3: 2 collider objects will be merged into one shape",
+            "tile 3: unsupported collider shape Ellipse is ignored",
+        ]);
+    }
+
+    #[test]
+    fn passes_the_test_map() {
+        let report = lint(Path::new("test.tmx")).unwrap();
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+    }
+}
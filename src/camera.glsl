@@ -0,0 +1,12 @@
+
+// World space to clip space, for every program drawing in world space.
+
+layout(std140, binding = 0) uniform Camera {
+    mat4 view_projection;
+};
+
+// Layers run from 0 to 1, higher ones nearer the camera. Depths are kept
+// clear of the near and far planes.
+vec4 world_to_clip(vec2 world, float layer) {
+    return view_projection * vec4(world, 0.5 - layer, 1.0);
+}
@@ -0,0 +1,240 @@
+
+// Picks edge and corner tile variants for painted regions, using the Wang
+// sets or terrain types defined in a tileset.
+
+use {
+    super::tmx,
+};
+
+// Positions in a Wang ID, clockwise from the top edge. Even positions are
+// edges, odd ones corners.
+const TOP:          usize = 0;
+const TOP_RIGHT:    usize = 1;
+const RIGHT:        usize = 2;
+const BOTTOM_RIGHT: usize = 3;
+const BOTTOM:       usize = 4;
+const BOTTOM_LEFT:  usize = 5;
+const LEFT:         usize = 6;
+const TOP_LEFT:     usize = 7;
+
+// neighbour offsets for each position, y-up
+const OFFSETS: [(i32, i32); 8] = [
+    ( 0,  1), ( 1,  1), ( 1,  0), ( 1, -1),
+    ( 0, -1), (-1, -1), (-1,  0), (-1,  1),
+];
+
+// colour per position; 0 means unset
+pub type WangId = [u8; 8];
+
+#[derive(Clone, Debug)]
+pub struct WangSet {
+    pub name:     String,
+    pub tiles:    Vec<(u32, WangId)>,
+    uses_edges:   bool,
+    uses_corners: bool,
+}
+
+// Tiled before 1.5 packs Wang IDs into a hex number, one nibble per position
+// starting from the least significant; later versions write a list.
+fn parse_wang_id(s: &str) -> Option<WangId> {
+    let mut id = [0; 8];
+    if s.starts_with("0x") || s.starts_with("0X") {
+        let packed = u32::from_str_radix(&s[2..], 16).ok()?;
+        for (i, colour) in id.iter_mut().enumerate() {
+            *colour = ((packed >> (i * 4)) & 0xf) as u8;
+        }
+    }
+    else {
+        let colours: Vec<u8> = s.split(',')
+            .map(|c| c.trim().parse().ok())
+            .collect::<Option<_>>()?;
+        if colours.len() != 8 {
+            return None;
+        }
+        id.copy_from_slice(&colours);
+    }
+    Some(id)
+}
+
+// Terrains assign a terrain to each corner: top-left, top-right,
+// bottom-left, bottom-right.
+fn terrain_to_wang_id(s: &str) -> WangId {
+    let mut id = [0; 8];
+    let corners = [TOP_LEFT, TOP_RIGHT, BOTTOM_LEFT, BOTTOM_RIGHT];
+    for (corner, terrain) in corners.iter().zip(s.split(',')) {
+        if let Ok(terrain) = terrain.trim().parse::<u8>() {
+            id[*corner] = terrain + 1;
+        }
+    }
+    id
+}
+
+impl WangSet {
+    pub fn new(name: String, tiles: Vec<(u32, WangId)>) -> WangSet {
+        let any_at = |positions: &[usize]| tiles.iter()
+            .any(|(_, id)| positions.iter().any(|&p| id[p] != 0));
+        let uses_edges   = any_at(&[TOP, RIGHT, BOTTOM, LEFT]);
+        let uses_corners = any_at(&[TOP_RIGHT, BOTTOM_RIGHT, BOTTOM_LEFT, TOP_LEFT]);
+        WangSet { name, tiles, uses_edges, uses_corners }
+    }
+
+    // All Wang sets in a tileset. Terrain types are presented as one
+    // corner-only set named "terrain".
+    pub fn read_all(ts: &tmx::Tileset) -> Vec<WangSet> {
+        let mut sets = Vec::new();
+
+        for wangsets in ts.extra.iter().filter(|el| el.name == "wangsets") {
+            for set in wangsets.children_named("wangset") {
+                let tiles = set.children_named("wangtile")
+                    .filter_map(|tile| {
+                        let id = tile.attr("tileid")?.parse().ok()?;
+                        let wang_id = parse_wang_id(tile.attr("wangid")?)?;
+                        Some((id, wang_id))
+                    })
+                    .collect();
+                let name = set.attr("name").unwrap_or("").to_string();
+                sets.push(WangSet::new(name, tiles));
+            }
+        }
+
+        let terrain_tiles: Vec<(u32, WangId)> = ts.tiles.iter()
+            .filter_map(|tile| tile.terrain.as_ref()
+                .map(|terrain| (tile.id, terrain_to_wang_id(terrain))))
+            .collect();
+        if !terrain_tiles.is_empty() {
+            sets.push(WangSet::new("terrain".to_string(), terrain_tiles));
+        }
+
+        sets
+    }
+
+    pub fn retain_tiles(&mut self, mut keep: impl FnMut(u32) -> bool) {
+        self.tiles.retain(|(id, _)| keep(*id));
+    }
+
+    // The tile that best fits the cell at (x, y) when every cell for which
+    // `filled` is true is painted with `colour`. Of equally good tiles, the
+    // one listed first wins.
+    pub fn pick(&self, colour: u8, x: i32, y: i32, filled: impl Fn(i32, i32) -> bool)
+        -> Option<u32>
+    {
+        let around: Vec<bool> = OFFSETS.iter()
+            .map(|(dx, dy)| filled(x + dx, y + dy))
+            .collect();
+
+        // edges take the colour of the neighbour across them; corners only
+        // if the edges on both sides and the diagonal are all filled
+        let mut want = [false; 8];
+        for (p, want) in want.iter_mut().enumerate() {
+            *want = if p % 2 == 0 { around[p] }
+                    else { around[p - 1] && around[p] && around[(p + 1) % 8] };
+        }
+
+        let score = |id: &WangId| (0..8)
+            .filter(|&p| if p % 2 == 0 { self.uses_edges } else { self.uses_corners })
+            .filter(|&p| (id[p] == colour) == want[p])
+            .count();
+
+        let mut best: Option<(usize, u32)> = None;
+        for (tile, id) in &self.tiles {
+            let s = score(id);
+            // strictly better only, so ties keep the earlier tile
+            if best.is_none_or(|(best_score, _)| s > best_score) {
+                best = Some((s, *tile));
+            }
+        }
+        best.map(|(_, tile)| tile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::collections::HashSet};
+
+    fn filled(cells: &[(i32, i32)]) -> impl Fn(i32, i32) -> bool {
+        let cells: HashSet<(i32, i32)> = cells.iter().cloned().collect();
+        move |x, y| cells.contains(&(x, y))
+    }
+
+    // a 3x3 block with its bottom-left cell at the origin
+    fn block() -> Vec<(i32, i32)> {
+        (0..3).flat_map(|y| (0..3).map(move |x| (x, y))).collect()
+    }
+
+    #[test]
+    fn parses_both_wang_id_encodings() {
+        let expected = Some([1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(parse_wang_id("0x87654321"), expected);
+        assert_eq!(parse_wang_id("1,2,3,4,5,6,7,8"), expected);
+        assert_eq!(parse_wang_id("1, 2, 3, 4, 5, 6, 7, 8"), expected);
+        assert_eq!(parse_wang_id("0x0"), Some([0; 8]));
+
+        assert_eq!(parse_wang_id("0xnope"), None);
+        assert_eq!(parse_wang_id("1,2,3"), None);
+        assert_eq!(parse_wang_id("1,2,3,4,5,6,7,x"), None);
+    }
+
+    #[test]
+    fn maps_terrains_to_corners() {
+        let id = terrain_to_wang_id("0,1,,2");
+        assert_eq!(id[TOP_LEFT], 1);
+        assert_eq!(id[TOP_RIGHT], 2);
+        assert_eq!(id[BOTTOM_LEFT], 0);
+        assert_eq!(id[BOTTOM_RIGHT], 3);
+        for &edge in &[TOP, RIGHT, BOTTOM, LEFT] {
+            assert_eq!(id[edge], 0);
+        }
+    }
+
+    #[test]
+    fn picks_edge_tiles() {
+        let set = WangSet::new("edges".to_string(), vec![
+            (0, [1, 0, 1, 0, 1, 0, 1, 0]),
+            (1, [0, 0, 1, 0, 1, 0, 1, 0]),
+            (2, [0, 0, 0, 0, 1, 0, 1, 0]),
+            (3, [0; 8]),
+        ]);
+        assert!(set.uses_edges && !set.uses_corners);
+
+        let block = filled(&block());
+        assert_eq!(set.pick(1, 1, 1, &block), Some(0));
+        assert_eq!(set.pick(1, 1, 2, &block), Some(1));
+        assert_eq!(set.pick(1, 2, 2, &block), Some(2));
+        assert_eq!(set.pick(1, 0, 0, filled(&[(0, 0)])), Some(3));
+        // no tile has the colour, so they all tie
+        assert_eq!(set.pick(2, 1, 1, &block), Some(0));
+    }
+
+    #[test]
+    fn picks_corner_tiles() {
+        let set = WangSet::new("terrain".to_string(), vec![
+            (10, terrain_to_wang_id("0,0,0,0")),
+            (11, terrain_to_wang_id(",,0,0")),
+            (12, terrain_to_wang_id(",,0,")),
+            (13, terrain_to_wang_id(",,,")),
+        ]);
+        assert!(set.uses_corners && !set.uses_edges);
+
+        let block = filled(&block());
+        assert_eq!(set.pick(1, 1, 1, &block), Some(10));
+        assert_eq!(set.pick(1, 1, 2, &block), Some(11));
+        assert_eq!(set.pick(1, 2, 2, &block), Some(12));
+
+        // a diagonal neighbour alone doesn't fill a corner
+        let diagonal = filled(&[(1, 1), (2, 2)]);
+        assert_eq!(set.pick(1, 1, 1, &diagonal), Some(13));
+    }
+
+    #[test]
+    fn keeps_the_first_of_equal_tiles() {
+        let set = WangSet::new("ties".to_string(), vec![
+            (5, [1; 8]),
+            (6, [1; 8]),
+            (7, [0; 8]),
+        ]);
+        assert_eq!(set.pick(1, 1, 1, filled(&block())), Some(5));
+
+        let empty = WangSet::new("empty".to_string(), Vec::new());
+        assert_eq!(empty.pick(1, 0, 0, filled(&[])), None);
+    }
+}
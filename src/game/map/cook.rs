@@ -0,0 +1,561 @@
+
+// Cooked maps: a .tmx and its tileset converted offline into a binary file
+// that loads without XML parsing, decompression or image slicing.
+//
+// Layout, all little-endian:
+//
+//     magic   [u8; 8]
+//     version u32
+//     sections, each:
+//         tag    [u8; 4]
+//         length u32
+//         crc32  u32   (of the body)
+//         body   [u8; length]
+//
+// Cooked maps only keep the main layer and the tileset reference, so saving
+// one back to TMX drops any other layers and objects. The WANG section is
+// only present for autotiled maps.
+
+use {
+    super::{
+        grid::{Grid, CHUNK_SIZE},
+        autotile::WangSet,
+        tmx, Map, Tile, Tileset, Autotile, LoadMapError, TILE_SIZE,
+    },
+    crate::{
+        alg::{V2, Shape},
+        damage,
+        gfx::{slice_atlas, AtlasImage, Assets},
+    },
+    byteorder::{LittleEndian as LE, ReadBytesExt, WriteBytesExt},
+    std::{
+        collections::HashMap,
+        error::Error,
+        fs,
+        io::{self, Read},
+        path::Path,
+    },
+};
+
+pub const EXTENSION: &str = "rgmap";
+
+const MAGIC: &[u8; 8] = b"RGMAP\r\n\0";
+const VERSION: u32 = 3;
+
+type Tag = [u8; 4];
+
+const META:  &Tag = b"META";
+const TSET:  &Tag = b"TSET";
+const ATLAS: &Tag = b"ATLS";
+const TILES: &Tag = b"TILE";
+const WANG:  &Tag = b"WANG";
+
+#[derive(Debug)]
+pub enum CookedMapError {
+    BadMagic,
+    UnsupportedVersion(u32),
+    MissingSection(String),
+    ChecksumMismatch(String),
+    Truncated,
+    // well formed, but with values no map could have
+    Corrupt,
+}
+
+impl std::fmt::Display for CookedMapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for CookedMapError { }
+
+impl From<io::Error> for CookedMapError {
+    fn from(_: io::Error) -> CookedMapError {
+        // the only reads are from in-memory slices
+        CookedMapError::Truncated
+    }
+}
+
+fn tag_name(tag: &Tag) -> String {
+    String::from_utf8_lossy(tag).into_owned()
+}
+
+fn write_section(out: &mut Vec<u8>, tag: &Tag, body: &[u8]) {
+    out.extend_from_slice(tag);
+    out.write_u32::<LE>(body.len() as u32).unwrap();
+    out.write_u32::<LE>(crc32fast::hash(body)).unwrap();
+    out.extend_from_slice(body);
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    out.write_u32::<LE>(s.len() as u32).unwrap();
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn read_string(src: &mut &[u8]) -> Result<String, CookedMapError> {
+    let len = src.read_u32::<LE>()? as usize;
+    if src.len() < len {
+        return Err(CookedMapError::Truncated);
+    }
+    let (bytes, rest) = src.split_at(len);
+    *src = rest;
+    Ok(String::from_utf8_lossy(bytes).into_owned())
+}
+
+// Checks that `count` items of at least `item_size` bytes each fit in what's
+// left of `src`, so a corrupt count can't make us allocate without bound.
+fn read_count(src: &mut &[u8], item_size: usize) -> Result<usize, CookedMapError> {
+    let count = src.read_u32::<LE>()? as usize;
+    match count.checked_mul(item_size) {
+        Some(size) if size <= src.len() => Ok(count),
+        _                               => Err(CookedMapError::Truncated),
+    }
+}
+
+fn read_sections(data: &[u8])
+    -> Result<HashMap<Tag, &[u8]>, CookedMapError>
+{
+    if data.len() < MAGIC.len() || &data[..MAGIC.len()] != MAGIC {
+        return Err(CookedMapError::BadMagic);
+    }
+
+    let mut src = &data[MAGIC.len()..];
+    let version = src.read_u32::<LE>()?;
+    if version != VERSION {
+        return Err(CookedMapError::UnsupportedVersion(version));
+    }
+
+    let mut sections = HashMap::new();
+    while !src.is_empty() {
+        let mut tag: Tag = [0; 4];
+        src.read_exact(&mut tag)?;
+        let len = src.read_u32::<LE>()? as usize;
+        let crc = src.read_u32::<LE>()?;
+
+        if src.len() < len {
+            return Err(CookedMapError::Truncated);
+        }
+        let (body, rest) = src.split_at(len);
+        src = rest;
+
+        if crc32fast::hash(body) != crc {
+            return Err(CookedMapError::ChecksumMismatch(tag_name(&tag)));
+        }
+        sections.insert(tag, body);
+    }
+
+    Ok(sections)
+}
+
+fn section<'a>(sections: &HashMap<Tag, &'a [u8]>, tag: &Tag)
+    -> Result<&'a [u8], CookedMapError>
+{
+    sections.get(tag)
+        .cloned()
+        .ok_or_else(|| CookedMapError::MissingSection(tag_name(tag)))
+}
+
+// Converts a .tmx file and its tileset into cooked form.
+pub fn cook(path: impl AsRef<Path>) -> Result<Vec<u8>, LoadMapError> {
+    let (doc, grid) = Map::read_tmx(path.as_ref())?;
+    let ts_ref = &doc.tilesets[0];
+    let ts = &ts_ref.tileset;
+
+    let image = ts.image.as_ref()
+        .ok_or(LoadMapError::ImageMissing)?;
+    let atlas = slice_atlas(&image.path, TILE_SIZE, TILE_SIZE)
+        .map_err(LoadMapError::nest)?;
+
+    let tiles = Tileset::read_tiles(ts, Some(&atlas));
+    let autotile = Autotile::from_doc(&doc, |id| tiles.iter().any(|(tid, _)| *tid == id));
+
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.write_u32::<LE>(VERSION).unwrap();
+
+    {   let mut body = Vec::new();
+        body.write_u8(doc.infinite as u8).unwrap();
+        body.write_u32::<LE>(doc.width).unwrap();
+        body.write_u32::<LE>(doc.height).unwrap();
+        body.write_u32::<LE>(doc.tile_width).unwrap();
+        body.write_u32::<LE>(doc.tile_height).unwrap();
+        write_string(&mut body, ts_ref.source.as_deref().unwrap_or(""));
+        write_section(&mut out, META, &body);
+    }
+
+    {   let mut body = Vec::new();
+        body.write_u32::<LE>(ts_ref.first_gid).unwrap();
+        body.write_u32::<LE>(tiles.len() as u32).unwrap();
+        for (id, tile) in &tiles {
+            body.write_u32::<LE>(*id).unwrap();
+            body.write_u32::<LE>(tile.collider.verts.len() as u32).unwrap();
+            for v in &tile.collider.verts {
+                body.write_f32::<LE>(v.x).unwrap();
+                body.write_f32::<LE>(v.y).unwrap();
+            }
+            body.write_i32::<LE>(tile.hit_points.unwrap_or(-1)).unwrap();
+            body.write_f64::<LE>(tile.resistance.kinetic).unwrap();
+            body.write_f64::<LE>(tile.resistance.thermal).unwrap();
+            body.write_f64::<LE>(tile.resistance.explosive).unwrap();
+        }
+        write_section(&mut out, TSET, &body);
+    }
+
+    {   let mut body = Vec::new();
+        body.write_u32::<LE>(atlas.tile_width as u32).unwrap();
+        body.write_u32::<LE>(atlas.tile_height as u32).unwrap();
+        body.write_u32::<LE>(atlas.tile_count as u32).unwrap();
+        body.extend_from_slice(&atlas.pixels);
+        write_section(&mut out, ATLAS, &body);
+    }
+
+    {   let mut chunks: Vec<(i32, i32)> = grid.chunks().collect();
+        chunks.sort();
+
+        let mut body = Vec::new();
+        body.write_u32::<LE>(chunks.len() as u32).unwrap();
+        for (cx, cy) in chunks {
+            body.write_i32::<LE>(cx).unwrap();
+            body.write_i32::<LE>(cy).unwrap();
+            for y in 0..CHUNK_SIZE {
+                for x in 0..CHUNK_SIZE {
+                    let gid = grid.get(cx * CHUNK_SIZE + x, cy * CHUNK_SIZE + y);
+                    body.write_u32::<LE>(gid.unwrap_or(0)).unwrap();
+                }
+            }
+        }
+        write_section(&mut out, TILES, &body);
+    }
+
+    if let Some(auto) = &autotile {
+        let mut body = Vec::new();
+        body.write_u8(auto.colour).unwrap();
+        write_string(&mut body, &auto.set.name);
+        body.write_u32::<LE>(auto.set.tiles.len() as u32).unwrap();
+        for (id, wang_id) in &auto.set.tiles {
+            body.write_u32::<LE>(*id).unwrap();
+            body.extend_from_slice(wang_id);
+        }
+        write_section(&mut out, WANG, &body);
+    }
+
+    Ok(out)
+}
+
+pub fn cook_to_file(src: impl AsRef<Path>, dst: impl AsRef<Path>)
+    -> Result<(), LoadMapError>
+{
+    let data = cook(src)?;
+    fs::write(dst, data).map_err(LoadMapError::nest)
+}
+
+impl Map {
+    // Without `assets` the atlas isn't uploaded and the tileset has no
+    // texture. The cooked atlas is cached under the cooked file's path.
+    pub fn load_cooked(path: impl AsRef<Path>, assets: Option<&mut Assets>)
+        -> Result<Map, LoadMapError>
+    {
+        let path = path.as_ref();
+        let data = fs::read(path).map_err(LoadMapError::nest)?;
+        Map::from_cooked(path, &data, assets).map_err(LoadMapError::nest)
+    }
+
+    fn from_cooked(path: &Path, data: &[u8], assets: Option<&mut Assets>)
+        -> Result<Map, CookedMapError>
+    {
+        let sections = read_sections(data)?;
+
+        let meta = {
+            let mut src = section(&sections, META)?;
+            let infinite    = src.read_u8()? != 0;
+            let width       = src.read_u32::<LE>()?;
+            let height      = src.read_u32::<LE>()?;
+            let tile_width  = src.read_u32::<LE>()?;
+            let tile_height = src.read_u32::<LE>()?;
+            let source      = read_string(&mut src)?;
+            (infinite, width, height, tile_width, tile_height, source)
+        };
+
+        let (base_gid, tiles) = {
+            let mut src = section(&sections, TSET)?;
+            let base_gid = src.read_u32::<LE>()?;
+            // id, vertex count, hit points and resistances
+            let count    = read_count(&mut src, 4 + 4 + 4 + 3 * 8)?;
+
+            let mut tiles = Vec::with_capacity(count);
+            for _ in 0..count {
+                let id         = src.read_u32::<LE>()?;
+                let vert_count = read_count(&mut src, 2 * 4)?;
+                let mut verts = Vec::with_capacity(vert_count);
+                for _ in 0..vert_count {
+                    let x = src.read_f32::<LE>()?;
+                    let y = src.read_f32::<LE>()?;
+                    verts.push(V2::new(x, y));
+                }
+                let hit_points = src.read_i32::<LE>()?;
+                let resistance = damage::Scales {
+                    kinetic:   src.read_f64::<LE>()?,
+                    thermal:   src.read_f64::<LE>()?,
+                    explosive: src.read_f64::<LE>()?,
+                };
+                tiles.push((id, Tile {
+                    collider:   Shape::new_from_vec(verts),
+                    hit_points: if hit_points < 0 { None } else { Some(hit_points) },
+                    resistance,
+                }));
+            }
+            (base_gid, tiles)
+        };
+
+        let atlas = {
+            let mut src = section(&sections, ATLAS)?;
+            let tile_width  = src.read_u32::<LE>()?;
+            let tile_height = src.read_u32::<LE>()?;
+            let tile_count  = src.read_u32::<LE>()?;
+            // the atlas is sized in i32s when it's uploaded
+            let expected = tile_width.checked_mul(tile_height)
+                .and_then(|n| n.checked_mul(tile_count))
+                .and_then(|n| n.checked_mul(4))
+                .filter(|&n| n <= i32::MAX as u32);
+            if expected != Some(src.len() as u32) || src.len() > i32::MAX as usize {
+                return Err(CookedMapError::Truncated);
+            }
+            AtlasImage {
+                tile_width:  tile_width  as i32,
+                tile_height: tile_height as i32,
+                tile_count:  tile_count  as i32,
+                pixels:      src.to_vec(),
+            }
+        };
+
+        let mut grid = Grid::new();
+        {   let mut src = section(&sections, TILES)?;
+            let chunk_size = 2 * 4 + (CHUNK_SIZE * CHUNK_SIZE) as usize * 4;
+            let count = read_count(&mut src, chunk_size)?;
+            for _ in 0..count {
+                let cx = src.read_i32::<LE>()?;
+                let cy = src.read_i32::<LE>()?;
+                let x0 = cx.checked_mul(CHUNK_SIZE).ok_or(CookedMapError::Corrupt)?;
+                let y0 = cy.checked_mul(CHUNK_SIZE).ok_or(CookedMapError::Corrupt)?;
+                for y in 0..CHUNK_SIZE {
+                    for x in 0..CHUNK_SIZE {
+                        let gid = src.read_u32::<LE>()?;
+                        if gid == 0 { continue; }
+                        grid.set(x0 + x, y0 + y, Some(gid));
+                    }
+                }
+            }
+        }
+
+        let autotile = match sections.get(WANG) {
+            None       => None,
+            Some(body) => {
+                let mut src = *body;
+                let colour = src.read_u8()?;
+                let name   = read_string(&mut src)?;
+                let count  = read_count(&mut src, 4 + 8)?;
+                let mut tiles = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let id = src.read_u32::<LE>()?;
+                    let mut wang_id = [0; 8];
+                    src.read_exact(&mut wang_id)?;
+                    tiles.push((id, wang_id));
+                }
+                Some(Autotile { set: WangSet::new(name, tiles), colour })
+            }
+        };
+
+        let (infinite, width, height, tile_width, tile_height, source) = meta;
+        let doc = tmx::Document {
+            version:        "1.2".to_string(),
+            tiled_version:  None,
+            orientation:    "orthogonal".to_string(),
+            render_order:   "right-down".to_string(),
+            width,
+            height,
+            tile_width,
+            tile_height,
+            infinite,
+            next_layer_id:  2,
+            next_object_id: 1,
+            background:     None,
+            properties:     tmx::Properties::new(),
+            tilesets: vec![tmx::TilesetRef {
+                first_gid: base_gid,
+                source:    if source.is_empty() { None } else { Some(source) },
+                path:      None,
+                tileset:   tmx::Tileset {
+                    name:        String::new(),
+                    tile_width,
+                    tile_height,
+                    tile_count:  atlas.tile_count as u32,
+                    columns:     0,
+                    spacing:     0,
+                    margin:      0,
+                    image:       None,
+                    properties:  tmx::Properties::new(),
+                    tiles:       Vec::new(),
+                    extra:       Vec::new(),
+                },
+            }],
+            layers: vec![tmx::Layer::Tiles(tmx::TileLayer {
+                id:         Some(1),
+                name:       "main".to_string(),
+                width,
+                height,
+                display:    tmx::LayerDisplay::default(),
+                properties: tmx::Properties::new(),
+                chunks:     Vec::new(),
+            })],
+        };
+
+        let texture = assets.map(|assets| assets.atlas_from_image(path, &atlas));
+        let tileset = Tileset::new(texture, base_gid, tiles);
+
+        Ok(Map::new(path, tileset, grid, doc, autotile))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::{convert::TryInto, path::PathBuf}};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("cook-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // test.tmx, autotiled with a small Wang set added to its tileset.
+    fn autotiled_test_map(dir: &Path) -> PathBuf {
+        let cwd = std::env::current_dir().unwrap();
+        let image = cwd.join("tiles.png");
+        let tsx = fs::read_to_string("tiles.tsx").unwrap()
+            .replace("source=\"tiles.png\"", &format!("source=\"{}\"", image.display()))
+            .replace("</tileset>", concat!(
+                " <wangsets>\n",
+                "  <wangset name=\"ground\" tile=\"-1\">\n",
+                "   <wangtile tileid=\"0\" wangid=\"0x11111111\"/>\n",
+                "   <wangtile tileid=\"1\" wangid=\"0x11111000\"/>\n",
+                "   <wangtile tileid=\"4\" wangid=\"0x00011111\"/>\n",
+                "  </wangset>\n",
+                " </wangsets>\n",
+                "</tileset>"));
+        let tsx_path = dir.join("tiles.tsx");
+        fs::write(&tsx_path, tsx).unwrap();
+
+        let tmx = fs::read_to_string("test.tmx").unwrap()
+            .replace("source=\"tiles.tsx\"", &format!("source=\"{}\"", tsx_path.display()))
+            .replace("<layer id=\"1\" name=\"main\" width=\"80\" height=\"15\">", concat!(
+                "<layer id=\"1\" name=\"main\" width=\"80\" height=\"15\">\n",
+                "  <properties>\n",
+                "   <property name=\"autotile\" value=\"ground\"/>\n",
+                "  </properties>"));
+        let tmx_path = dir.join("test.tmx");
+        fs::write(&tmx_path, tmx).unwrap();
+        tmx_path
+    }
+
+    // `data` with the body of section `tag` replaced, checksum and all.
+    fn with_section(data: &[u8], tag: &Tag, new_body: &[u8]) -> Vec<u8> {
+        let header = MAGIC.len() + 4;
+        let mut out = data[..header].to_vec();
+        let mut src = &data[header..];
+        while !src.is_empty() {
+            let len = u32::from_le_bytes([src[4], src[5], src[6], src[7]]) as usize;
+            let (section_tag, body) = (&src[..4], &src[12 .. 12 + len]);
+            let body = if section_tag == tag { new_body } else { body };
+            write_section(&mut out, section_tag.try_into().unwrap(), body);
+            src = &src[12 + len..];
+        }
+        out
+    }
+
+    fn load(data: &[u8]) -> Result<Map, CookedMapError> {
+        Map::from_cooked(Path::new("test.rgmap"), data, None)
+    }
+
+    #[test]
+    fn round_trips_test_map() {
+        let dir = temp_dir("round-trip");
+        let path = autotiled_test_map(&dir);
+        let source = Map::load_headless(&path).unwrap();
+        let cooked = load(&cook(&path).unwrap()).unwrap();
+
+        let bounds = source.bounds();
+        assert_eq!(bounds, cooked.bounds());
+        for y in bounds.bottom .. bounds.top {
+            for x in bounds.left .. bounds.right {
+                let (a, b) = (source.tile_at(x, y), cooked.tile_at(x, y));
+                assert_eq!(a.is_some(), b.is_some(), "at ({}, {})", x, y);
+                if let (Some((_, a, a_gid)), Some((_, b, b_gid))) = (a, b) {
+                    assert_eq!(a_gid, b_gid);
+                    assert_eq!(a.collider().verts, b.collider().verts);
+                    assert_eq!(a.hit_points(), b.hit_points());
+                    assert_eq!(a.resistance().kinetic, b.resistance().kinetic);
+                }
+            }
+        }
+
+        // chains come out in no particular order
+        let edges = |map: &Map| {
+            let mut edges: Vec<_> = map.outlines().chains()
+                .flat_map(|chain| chain.edges())
+                .map(|e| [e.a.x, e.a.y, e.b.x, e.b.y])
+                .collect();
+            edges.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            edges
+        };
+        assert!(!edges(&source).is_empty());
+        assert_eq!(edges(&source), edges(&cooked));
+
+        let (a, b) = (source.autotile.unwrap(), cooked.autotile.unwrap());
+        assert_eq!(a.set.name, "ground");
+        assert_eq!(a.set.name, b.set.name);
+        assert_eq!(a.set.tiles, b.set.tiles);
+        assert_eq!(a.colour, b.colour);
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let data = cook("test.tmx").unwrap();
+        assert!(load(&data).is_ok());
+
+        let mut bad_magic = data.clone();
+        bad_magic[0] = b'X';
+        assert!(matches!(load(&bad_magic), Err(CookedMapError::BadMagic)));
+        assert!(matches!(load(b"RGM"), Err(CookedMapError::BadMagic)));
+
+        let mut future = data.clone();
+        future[MAGIC.len() .. MAGIC.len() + 4].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(matches!(load(&future), Err(CookedMapError::UnsupportedVersion(v)) if v == VERSION + 1));
+
+        // the tile section is last, so this lands in its body
+        let mut flipped = data.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert!(matches!(load(&flipped), Err(CookedMapError::ChecksumMismatch(ref tag)) if tag == "TILE"));
+
+        assert!(matches!(load(&data[.. data.len() - 1]), Err(CookedMapError::Truncated)));
+        assert!(matches!(load(&data[.. MAGIC.len() + 2]), Err(CookedMapError::Truncated)));
+
+        // a section that checks out but claims more tiles than it holds
+        let mut tiles = Vec::new();
+        tiles.write_u32::<LE>(1000).unwrap();
+        let overlong = with_section(&data, TILES, &tiles);
+        assert!(matches!(load(&overlong), Err(CookedMapError::Truncated)));
+    }
+
+    #[test]
+    fn rejects_chunks_out_of_range() {
+        let data = cook("test.tmx").unwrap();
+        let mut tiles = Vec::new();
+        tiles.write_u32::<LE>(1).unwrap();
+        tiles.write_i32::<LE>(i32::MAX).unwrap();
+        tiles.write_i32::<LE>(0).unwrap();
+        tiles.resize(tiles.len() + (CHUNK_SIZE * CHUNK_SIZE) as usize * 4, 0);
+        let far = with_section(&data, TILES, &tiles);
+        assert!(matches!(load(&far), Err(CookedMapError::Corrupt)));
+    }
+}
@@ -0,0 +1,223 @@
+
+use {
+    crate::game::IntRect,
+    std::{
+        collections::HashMap,
+        sync::atomic::{AtomicU64, Ordering},
+    },
+};
+
+pub const CHUNK_SIZE: i32 = 16;
+
+const CHUNK_CELLS: usize = (CHUNK_SIZE * CHUNK_SIZE) as usize;
+
+// Revisions are unique across every grid, so a chunk in a reloaded map never
+// shares a revision with the one it replaces.
+static NEXT_REVISION: AtomicU64 = AtomicU64::new(1);
+
+fn next_revision() -> u64 {
+    NEXT_REVISION.fetch_add(1, Ordering::Relaxed)
+}
+
+pub struct Chunk {
+    cells:    [Option<u32>; CHUNK_CELLS],
+    revision: u64,
+}
+
+impl Chunk {
+    fn new() -> Chunk {
+        Chunk { cells: [None; CHUNK_CELLS], revision: next_revision() }
+    }
+
+    fn index(x: i32, y: i32) -> usize {
+        let x = x.rem_euclid(CHUNK_SIZE);
+        let y = y.rem_euclid(CHUNK_SIZE);
+        (y * CHUNK_SIZE + x) as usize
+    }
+
+    fn is_empty(&self) -> bool {
+        self.cells.iter().all(|cell| cell.is_none())
+    }
+}
+
+pub fn chunk_coords(x: i32, y: i32) -> (i32, i32) {
+    (x.div_euclid(CHUNK_SIZE), y.div_euclid(CHUNK_SIZE))
+}
+
+// Sparse tile storage over signed tile coordinates. Only chunks containing at
+// least one tile are allocated.
+pub struct Grid {
+    chunks: HashMap<(i32, i32), Box<Chunk>>,
+    bounds: IntRect,
+}
+
+impl Grid {
+    pub fn new() -> Grid {
+        let bounds = IntRect { left: 0, bottom: 0, right: 0, top: 0 };
+        Grid { chunks: HashMap::new(), bounds }
+    }
+
+    pub fn get(&self, x: i32, y: i32) -> Option<u32> {
+        self.chunks.get(&chunk_coords(x, y))
+            .and_then(|chunk| chunk.cells[Chunk::index(x, y)])
+    }
+
+    pub fn set(&mut self, x: i32, y: i32, gid: Option<u32>) {
+        let coords = chunk_coords(x, y);
+
+        match gid {
+            Some(_) => {
+                let chunk = self.chunks.entry(coords)
+                    .or_insert_with(|| Box::new(Chunk::new()));
+                chunk.cells[Chunk::index(x, y)] = gid;
+                chunk.revision = next_revision();
+                self.grow_bounds(x, y);
+            }
+
+            None => {
+                if let Some(chunk) = self.chunks.get_mut(&coords) {
+                    chunk.cells[Chunk::index(x, y)] = None;
+                    chunk.revision = next_revision();
+                    if chunk.is_empty() {
+                        self.chunks.remove(&coords);
+                    }
+                }
+            }
+        }
+    }
+
+    fn grow_bounds(&mut self, x: i32, y: i32) {
+        let b = &mut self.bounds;
+        if b.left == b.right || b.bottom == b.top {
+            *b = IntRect { left: x, bottom: y, right: x + 1, top: y + 1 };
+        }
+        else {
+            b.left   = b.left.min(x);
+            b.bottom = b.bottom.min(y);
+            b.right  = b.right.max(x + 1);
+            b.top    = b.top.max(y + 1);
+        }
+    }
+
+    // Bounding rectangle of every tile ever set; clearing tiles never shrinks
+    // it.
+    pub fn bounds(&self) -> IntRect {
+        self.bounds
+    }
+
+    pub fn chunks(&self) -> impl Iterator<Item = (i32, i32)> + '_ {
+        self.chunks.keys().cloned()
+    }
+
+    // Chunk coordinates with a number that changes whenever the chunk's
+    // tiles do.
+    pub fn revisions(&self) -> impl Iterator<Item = ((i32, i32), u64)> + '_ {
+        self.chunks.iter().map(|(&coords, chunk)| (coords, chunk.revision))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = ((i32, i32), u32)> + '_ {
+        self.chunks.keys().flat_map(move |&(cx, cy)| self.chunk_iter(cx, cy))
+    }
+
+    // Tiles in one chunk, given in chunk coordinates.
+    pub fn chunk_iter(&self, cx: i32, cy: i32) -> impl Iterator<Item = ((i32, i32), u32)> + '_ {
+        let cells = self.chunks.get(&(cx, cy))
+            .map_or(&[][..], |chunk| &chunk.cells[..]);
+        cells.iter().enumerate().filter_map(move |(i, cell)| {
+            cell.map(|gid| {
+                let x = cx * CHUNK_SIZE + i as i32 % CHUNK_SIZE;
+                let y = cy * CHUNK_SIZE + i as i32 / CHUNK_SIZE;
+                ((x, y), gid)
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn revision(grid: &Grid, coords: (i32, i32)) -> Option<u64> {
+        grid.revisions().find(|(c, _)| *c == coords).map(|(_, revision)| revision)
+    }
+
+    #[test]
+    fn stores_tiles_at_negative_coordinates() {
+        let mut grid = Grid::new();
+        grid.set(-1, -1, Some(7));
+        grid.set(-16, 0, Some(8));
+        grid.set(-17, 0, Some(9));
+
+        assert_eq!(grid.get(-1, -1), Some(7));
+        assert_eq!(grid.get(-16, 0), Some(8));
+        assert_eq!(grid.get(-17, 0), Some(9));
+        assert_eq!(grid.get(15, 15), None);
+        assert_eq!(grid.get(-1, 0), None);
+
+        assert_eq!(chunk_coords(-1, -1), (-1, -1));
+        assert_eq!(chunk_coords(-16, 0), (-1, 0));
+        assert_eq!(chunk_coords(-17, 0), (-2, 0));
+
+        let mut chunks: Vec<(i32, i32)> = grid.chunks().collect();
+        chunks.sort();
+        assert_eq!(chunks, [(-2, 0), (-1, -1), (-1, 0)]);
+        assert_eq!(grid.chunk_iter(-1, -1).collect::<Vec<_>>(), [((-1, -1), 7)]);
+        assert_eq!(grid.chunk_iter(-1, 0).collect::<Vec<_>>(), [((-16, 0), 8)]);
+        assert_eq!(grid.iter().count(), 3);
+    }
+
+    #[test]
+    fn drops_chunks_once_empty() {
+        let mut grid = Grid::new();
+        grid.set(3, 4, Some(1));
+        grid.set(5, 4, Some(1));
+        grid.set(3, 4, None);
+        assert_eq!(grid.chunks().count(), 1);
+
+        grid.set(5, 4, None);
+        assert_eq!(grid.chunks().count(), 0);
+        assert_eq!(grid.chunk_iter(0, 0).count(), 0);
+
+        // clearing where nothing is doesn't make a chunk
+        grid.set(100, 100, None);
+        assert_eq!(grid.chunks().count(), 0);
+    }
+
+    #[test]
+    fn bounds_grow_but_never_shrink() {
+        let mut grid = Grid::new();
+        assert_eq!(grid.bounds(), IntRect { left: 0, bottom: 0, right: 0, top: 0 });
+
+        grid.set(5, 6, Some(1));
+        assert_eq!(grid.bounds(), IntRect { left: 5, bottom: 6, right: 6, top: 7 });
+
+        grid.set(-3, 10, Some(1));
+        assert_eq!(grid.bounds(), IntRect { left: -3, bottom: 6, right: 6, top: 11 });
+
+        grid.set(-3, 10, None);
+        grid.set(5, 6, None);
+        assert_eq!(grid.bounds(), IntRect { left: -3, bottom: 6, right: 6, top: 11 });
+    }
+
+    #[test]
+    fn bumps_revisions_of_changed_chunks() {
+        let mut grid = Grid::new();
+        grid.set(0, 0, Some(1));
+        grid.set(20, 0, Some(1));
+        let (first, second) = (revision(&grid, (0, 0)).unwrap(), revision(&grid, (1, 0)).unwrap());
+
+        grid.set(1, 0, Some(2));
+        let changed = revision(&grid, (0, 0)).unwrap();
+        assert_ne!(changed, first);
+        assert_eq!(revision(&grid, (1, 0)), Some(second));
+
+        grid.set(1, 0, None);
+        assert_ne!(revision(&grid, (0, 0)), Some(changed));
+
+        // a new grid's chunks don't reuse revisions
+        let mut other = Grid::new();
+        other.set(0, 0, Some(1));
+        let fresh = revision(&other, (0, 0)).unwrap();
+        assert!(![first, second, changed].contains(&fresh));
+    }
+}
@@ -0,0 +1,866 @@
+
+pub mod tmx;
+pub mod cook;
+pub mod outline;
+pub mod autotile;
+mod trace;
+mod query;
+mod grid;
+
+use {
+    self::{
+        grid::Grid,
+        outline::Outlines,
+        autotile::WangSet,
+        trace::Tracing,
+    },
+    crate::{
+        alg::{V2, Shape},
+        gfx::{slice_atlas, AtlasImage, Assets, TextureHandle},
+        game::IntRect,
+        damage,
+    },
+    std::{
+        collections::HashMap,
+        error::Error
+    },
+    gl::types::*,
+};
+
+pub use self::grid::CHUNK_SIZE;
+
+pub const TILE_SIZE: i32 = 16;
+
+#[derive(Debug)]
+pub enum LoadMapError {
+    MainLayerMissing,
+    TooManyTilesets,
+    ImageMissing,
+    Nested(Box<dyn Error>),
+}
+
+impl LoadMapError {
+    fn nest(inner: impl Into<Box<dyn Error>>) -> LoadMapError {
+        LoadMapError::Nested(inner.into())
+    }
+}
+
+impl std::fmt::Display for LoadMapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for LoadMapError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LoadMapError::Nested(e) => Some(e.as_ref() as _),
+            _                       => None
+        }
+    }
+}
+
+pub struct Tile {
+    collider:   Shape,
+    // None for indestructible tiles
+    hit_points: Option<i32>,
+    resistance: damage::Scales,
+}
+
+impl Tile {
+    pub fn collider(&self) -> &Shape {
+        &self.collider
+    }
+
+    pub fn hit_points(&self) -> Option<i32> {
+        self.hit_points
+    }
+
+    pub fn resistance(&self) -> &damage::Scales {
+        &self.resistance
+    }
+}
+
+pub struct Tileset {
+    // None for maps loaded headless
+    atlas_texture: Option<TextureHandle>,
+    base_gid:      u32,
+    // indexed by tile id; None for tiles without a definition
+    tiles:         Vec<Option<Tile>>,
+}
+
+impl std::ops::Index<u32> for Tileset {
+    type Output = Tile;
+    fn index(&self, id: u32) -> &Tile {
+        self.get(id).unwrap()
+    }
+}
+
+impl Tileset {
+    // The atlas as an array texture, one tile per layer; 0 if headless.
+    pub fn texture(&self) -> GLuint {
+        self.atlas_texture.as_ref().map_or(0, |tex| tex.handle())
+    }
+
+    fn new(atlas_texture: Option<TextureHandle>, base_gid: u32, defs: Vec<(u32, Tile)>) -> Tileset {
+        let len = defs.iter().map(|(id, _)| *id as usize + 1).max().unwrap_or(0);
+        let mut tiles: Vec<Option<Tile>> = (0..len).map(|_| None).collect();
+        for (id, tile) in defs {
+            tiles[id as usize] = Some(tile);
+        }
+        Tileset { atlas_texture, base_gid, tiles }
+    }
+
+    // Builds the tileset's tiles and colliders without touching GL, for
+    // tools. The tileset has no texture.
+    pub fn load_headless(ts_ref: &tmx::TilesetRef) -> Result<Tileset, LoadMapError> {
+        Tileset::load(ts_ref, None)
+    }
+
+    // Without `assets` the tileset has no texture, and the image is only read
+    // if colliders are traced from it.
+    fn load(ts_ref: &tmx::TilesetRef, assets: Option<&mut Assets>)
+        -> Result<Tileset, LoadMapError>
+    {
+        let ts = &ts_ref.tileset;
+        let image_path = || ts.image.as_ref()
+            .map(|image| &image.path)
+            .ok_or(LoadMapError::ImageMissing);
+
+        let traces = Tileset::traces_colliders(ts);
+        let (atlas_texture, atlas) = match assets {
+            Some(assets) if traces => {
+                let (texture, atlas) = assets.atlas_with_image(image_path()?, TILE_SIZE, TILE_SIZE)
+                    .map_err(LoadMapError::nest)?;
+                (Some(texture), Some(atlas))
+            }
+            Some(assets) => {
+                let texture = assets.atlas(image_path()?, TILE_SIZE, TILE_SIZE)
+                    .map_err(LoadMapError::nest)?;
+                (Some(texture), None)
+            }
+            None if traces => {
+                let atlas = slice_atlas(image_path()?, TILE_SIZE, TILE_SIZE)
+                    .map_err(LoadMapError::nest)?;
+                (None, Some(std::rc::Rc::new(atlas)))
+            }
+            None => (None, None),
+        };
+
+        let tiles = Tileset::read_tiles(ts, atlas.as_deref());
+        Ok(Tileset::new(atlas_texture, ts_ref.first_gid, tiles))
+    }
+
+    // Tiles with a collider drawn in Tiled, plus, if the tileset asks for it
+    // and `atlas` is given, every other tile with opaque pixels, using a
+    // collider traced from the image.
+    fn read_tiles(ts: &tmx::Tileset, atlas: Option<&AtlasImage>) -> Vec<(u32, Tile)> {
+        let tracing = Tracing::from_properties(&ts.properties);
+        let traced_count = match (&tracing, atlas) {
+            (Some(_), Some(atlas)) => atlas.tile_count as u32,
+            _                      => 0,
+        };
+
+        let mut ids: Vec<u32> = ts.tiles.iter().map(|tile| tile.id).collect();
+        ids.extend(0 .. traced_count);
+        ids.sort_unstable();
+        ids.dedup();
+
+        let mut tiles = Vec::with_capacity(ids.len());
+
+        for id in ids {
+            let in_tile = ts.tiles.iter().find(|tile| tile.id == id);
+
+            let collider_verts = match in_tile.and_then(|tile| tile.objects.as_ref()) {
+                Some(obj_group) => {
+                    let (verts, ignored) = read_collider(obj_group);
+                    if !ignored.is_empty() {
+                        eprintln!("bad collider shape in tile {}", id);
+                    }
+                    verts
+                }
+                None => {
+                    let traced = match (&tracing, atlas) {
+                        (Some(tracing), Some(atlas)) if id < traced_count => {
+                            let size = (atlas.tile_width * atlas.tile_height * 4) as usize;
+                            let pixels = &atlas.pixels[id as usize * size ..][.. size];
+                            tracing.trace(pixels, atlas.tile_width, atlas.tile_height)
+                        }
+                        _ => None,
+                    };
+                    match traced {
+                        Some(verts) => verts,
+                        None        => { continue; }
+                    }
+                }
+            };
+
+            let no_props = Vec::new();
+            let props = in_tile.map_or(&no_props, |tile| &tile.properties);
+            let prop_f64 = |name: &str, default: f64| tmx::property(props, name)
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default);
+
+            let out_tile = Tile {
+                collider:   Shape::new_from_vec(collider_verts),
+                hit_points: tmx::property(props, "hp").and_then(|v| v.parse().ok()),
+                resistance: damage::Scales {
+                    kinetic:   prop_f64("resist_kinetic",   1.0),
+                    thermal:   prop_f64("resist_thermal",   1.0),
+                    explosive: prop_f64("resist_explosive", 1.0),
+                },
+            };
+            tiles.push((id, out_tile));
+        }
+
+        tiles
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Tile> {
+        self.tiles.get(id as usize).and_then(|tile| tile.as_ref())
+    }
+
+    pub fn has_tile(&self, index: u32) -> bool {
+        self.get(index).is_some()
+    }
+
+    // Every defined tile with its id.
+    pub fn tiles(&self) -> impl Iterator<Item = (u32, &Tile)> {
+        self.tiles.iter()
+            .enumerate()
+            .filter_map(|(id, tile)| tile.as_ref().map(|tile| (id as u32, tile)))
+    }
+
+    // Whether tiles without a drawn collider get one traced from the image.
+    pub fn traces_colliders(ts: &tmx::Tileset) -> bool {
+        Tracing::from_properties(&ts.properties).is_some()
+    }
+
+    // Accepts gids with flip bits set.
+    fn gid_to_index(&self, gid: u32) -> Option<u32> {
+        let (gid, _) = tmx::Flip::split(gid);
+        if gid < self.base_gid { return None; }
+        let index = gid - self.base_gid;
+        if index as usize >= self.tiles.len() { None }
+        else { Some(index) }
+    }
+}
+
+// A tile's collider from the objects drawn on it in Tiled, in the tile's
+// pixel space (y-down), along with the objects whose shapes can't be part of
+// one and were left out.
+pub fn read_collider(obj_group: &tmx::ObjectGroup) -> (Vec<V2>, Vec<&tmx::Object>) {
+    let mut collider_verts = Vec::new();
+    let mut ignored = Vec::new();
+
+    for obj in &obj_group.objects {
+        use tmx::ObjectShape::*;
+        match &obj.shape {
+            Rect => {
+                let (width, height) = (obj.width, obj.height);
+                collider_verts.extend_from_slice(&[
+                    V2::new(obj.x,         obj.y),
+                    V2::new(obj.x + width, obj.y),
+                    V2::new(obj.x + width, obj.y + height),
+                    V2::new(obj.x,         obj.y + height),
+                ]);
+            }
+
+            Polygon(points) => {
+                for (x, y) in points.iter() {
+                    collider_verts.push(V2::new(obj.x + x, obj.y + y));
+                }
+            }
+
+            _ => ignored.push(obj),
+        }
+    }
+
+    (collider_verts, ignored)
+}
+
+// Wang set used to pick tile variants in the main layer, chosen with the
+// layer's "autotile" property; "autotile_colour" selects the colour painted
+// cells get (default 1).
+pub struct Autotile {
+    set:    WangSet,
+    colour: u8,
+}
+
+impl Autotile {
+    fn from_doc(doc: &tmx::Document, has_tile: impl Fn(u32) -> bool)
+        -> Option<Autotile>
+    {
+        let props = &doc.tile_layer("main")?.properties;
+        let name = tmx::property(props, "autotile")?;
+        let colour = tmx::property(props, "autotile_colour")
+            .and_then(|c| c.parse().ok())
+            .unwrap_or(1);
+
+        let mut set = WangSet::read_all(&doc.tilesets[0].tileset)
+            .into_iter()
+            .find(|set| set.name == name);
+        if set.is_none() {
+            eprintln!("autotile: no Wang set or terrain named \"{}\"", name);
+        }
+
+        // only tiles with colliders can go in the main layer
+        if let Some(set) = &mut set {
+            set.retain_tiles(has_tile);
+        }
+        set.map(|set| Autotile { set, colour })
+    }
+}
+
+// `shape` as it lies in a tile placed with `flip`; both are in the tile's
+// y-down pixel space.
+fn flip_collider(shape: &Shape, flip: tmx::Flip) -> Shape {
+    let size = TILE_SIZE as f32;
+    let verts = shape.verts.iter()
+        .map(|&v| {
+            let v = if flip.diagonal { V2::new(v.y, v.x) } else { v };
+            let x = if flip.horizontal { size - v.x } else { v.x };
+            let y = if flip.vertical   { size - v.y } else { v.y };
+            V2::new(x, y)
+        })
+        .collect();
+    Shape::new_from_vec(verts)
+}
+
+// The collider of the tile at (x, y), flipped the way the tile is.
+fn placed_collider(tileset: &Tileset, tiles: &Grid, x: i32, y: i32) -> Option<Shape> {
+    let gid = tiles.get(x, y)?;
+    let (_, flip) = tmx::Flip::split(gid);
+    let tile = tileset.get(tileset.gid_to_index(gid)?)?;
+    Some(flip_collider(&tile.collider, flip))
+}
+
+pub struct Map {
+    path:    std::path::PathBuf,
+    tileset: Tileset,
+    // gids with flip bits set the way Tiled writes them
+    tiles:   Grid,
+    // remaining hit points of tiles that have taken damage
+    damage:  HashMap<(i32, i32), i32>,
+    // merged tile colliders, kept up to date as tiles change
+    outlines: Outlines,
+    autotile:       Option<Autotile>,
+    // everything else from the source file, kept for saving; the main
+    // layer's data lives in `tiles` instead
+    doc:     tmx::Document,
+}
+
+impl Map {
+    // Loads a .tmx file, or a cooked map if the path has the cooked
+    // extension.
+    // The tileset's texture comes from `assets`, shared with anything else
+    // using the same image.
+    pub fn load(path: impl AsRef<std::path::Path>, assets: &mut Assets)
+        -> Result<Map, LoadMapError>
+    {
+        Map::load_with(path.as_ref(), Some(assets))
+    }
+
+    // Loads a map without touching GL, for tools and benchmarks. The tileset
+    // has no texture.
+    pub fn load_headless(path: impl AsRef<std::path::Path>) -> Result<Map, LoadMapError> {
+        Map::load_with(path.as_ref(), None)
+    }
+
+    fn load_with(path: &std::path::Path, assets: Option<&mut Assets>)
+        -> Result<Map, LoadMapError>
+    {
+        if path.extension() == Some(std::ffi::OsStr::new(cook::EXTENSION)) {
+            return Map::load_cooked(path, assets);
+        }
+
+        let (doc, tiles) = Map::read_tmx(path)?;
+        let tileset = Tileset::load(&doc.tilesets[0], assets)?;
+        let autotile = Autotile::from_doc(&doc, |id| tileset.has_tile(id));
+        Ok(Map::new(path, tileset, tiles, doc, autotile))
+    }
+
+    fn new(
+        path:     &std::path::Path,
+        tileset:  Tileset,
+        tiles:    Grid,
+        doc:      tmx::Document,
+        autotile: Option<Autotile>)
+        -> Map
+    {
+        let mut map = Map {
+            path:           path.to_owned(),
+            tileset,
+            tiles,
+            damage:         HashMap::new(),
+            outlines:       Outlines::build(std::iter::empty()),
+            autotile,
+            doc,
+        };
+
+        let painted: Vec<(i32, i32)> = map.tiles.iter().map(|(pos, _)| pos).collect();
+        for (x, y) in painted {
+            map.retile(x, y);
+        }
+
+        let (tileset, tiles) = (&map.tileset, &map.tiles);
+        let colliders: Vec<((i32, i32), Shape)> = tiles.iter()
+            .filter_map(|((x, y), _)| {
+                placed_collider(tileset, tiles, x, y).map(|shape| ((x, y), shape))
+            })
+            .collect();
+        map.outlines = Outlines::build(colliders.iter().map(|(pos, shape)| (*pos, shape)));
+        map
+    }
+
+    // Rebuilds the outlines around tiles that changed.
+    fn update_outlines(&mut self, changed: &[(i32, i32)]) {
+        let (tileset, tiles) = (&self.tileset, &self.tiles);
+        self.outlines.update(changed, |x, y| placed_collider(tileset, tiles, x, y));
+    }
+
+    pub fn outlines(&self) -> &Outlines {
+        &self.outlines
+    }
+
+    // Every file the map was built from.
+    pub fn source_paths(&self) -> Vec<std::path::PathBuf> {
+        let mut paths = vec![self.path.clone()];
+        for ts_ref in &self.doc.tilesets {
+            paths.extend(ts_ref.path.iter().cloned());
+            paths.extend(ts_ref.tileset.image.iter().map(|im| im.path.clone()));
+        }
+        paths
+    }
+
+    // Parses a .tmx file, moving the main layer's tiles out of the document
+    // into a grid.
+    fn read_tmx(path: &std::path::Path)
+        -> Result<(tmx::Document, Grid), LoadMapError>
+    {
+        let mut doc = tmx::Document::load(path)
+            .map_err(LoadMapError::nest)?;
+
+        if doc.tilesets.len() != 1 {
+            return Err(LoadMapError::TooManyTilesets);
+        }
+
+        let flip_base = doc.flip_base();
+        let main_layer = doc.tile_layer_mut("main")
+            .ok_or(LoadMapError::MainLayerMissing)?;
+        let chunks = std::mem::take(&mut main_layer.chunks);
+
+        let mut tiles = Grid::new();
+        for chunk in &chunks {
+            for row in 0..chunk.height {
+                for col in 0..chunk.width {
+                    let gid = chunk.gid_at(col, row);
+                    if gid == 0 { continue; }
+
+                    let x = chunk.x + col as i32;
+                    let y = flip_base - (chunk.y + row as i32);
+                    tiles.set(x, y, Some(chunk.flip_at(col, row).join(gid)));
+                }
+            }
+        }
+
+        Ok((doc, tiles))
+    }
+
+    // The source document with the main layer rebuilt from the current
+    // tiles. Finite maps keep their original extent; tiles outside it are
+    // dropped.
+    pub fn to_tmx(&self) -> tmx::Document {
+        let mut doc = self.doc.clone();
+        let flip_base = doc.flip_base();
+
+        let mut rects: Vec<(i32, i32, u32, u32)> = if doc.infinite {
+            let size = CHUNK_SIZE as u32;
+            self.tiles.chunks()
+                .map(|(cx, cy)| {
+                    let top = flip_base - (cy * CHUNK_SIZE + CHUNK_SIZE - 1);
+                    (cx * CHUNK_SIZE, top, size, size)
+                })
+                .collect()
+        }
+        else {
+            vec![(0, 0, doc.width, doc.height)]
+        };
+        rects.sort_by_key(|&(x, y, _, _)| (y, x));
+
+        let chunks = rects.into_iter()
+            .map(|(x, y, width, height)| {
+                let mut gids  = Vec::with_capacity((width * height) as usize);
+                let mut flips = Vec::with_capacity((width * height) as usize);
+                for row in 0..height as i32 {
+                    for col in 0..width as i32 {
+                        let raw = self.tiles.get(x + col, flip_base - (y + row));
+                        let (gid, flip) = tmx::Flip::split(raw.unwrap_or(0));
+                        gids.push(gid);
+                        flips.push(flip);
+                    }
+                }
+                tmx::Chunk { x, y, width, height, gids, flips }
+            })
+            .collect();
+
+        doc.tile_layer_mut("main").unwrap().chunks = chunks;
+        doc
+    }
+
+    pub fn save(&self, path: impl AsRef<std::path::Path>)
+        -> Result<(), tmx::TmxError>
+    {
+        self.to_tmx().save(path)
+    }
+
+    pub fn bounds(&self) -> IntRect {
+        self.tiles.bounds()
+    }
+
+    // Width and height in pixels of a finite map; None if it's infinite.
+    pub fn pixel_size(&self) -> Option<(u32, u32)> {
+        if self.doc.infinite {
+            None
+        }
+        else {
+            Some((self.doc.width * self.doc.tile_width, self.doc.height * self.doc.tile_height))
+        }
+    }
+
+    pub fn tile_at(&self, x: i32, y: i32) -> Option<(&Tileset, &Tile, u32)> {
+        let tileset = &self.tileset;
+        let index = tileset.gid_to_index(self.tiles.get(x, y)?)?;
+        tileset.get(index).map(|tile| (tileset, tile, index))
+    }
+
+    pub fn tileset(&self) -> &Tileset {
+        &self.tileset
+    }
+
+    // Occupied chunks, each with a revision that changes whenever any of its
+    // tiles do. Revisions are never reused, even by a reloaded map.
+    pub fn chunk_revisions(&self) -> impl Iterator<Item = ((i32, i32), u64)> + '_ {
+        self.tiles.revisions()
+    }
+
+    // Tiles in the chunk at chunk coordinates (cx, cy), as tile coordinates,
+    // tileset indices and flips.
+    pub fn chunk_tiles(&self, cx: i32, cy: i32)
+        -> impl Iterator<Item = ((i32, i32), u32, tmx::Flip)> + '_
+    {
+        let tileset = &self.tileset;
+        self.tiles.chunk_iter(cx, cy)
+            .filter_map(move |(coords, gid)| {
+                let index = tileset.gid_to_index(gid)?;
+                let (_, flip) = tmx::Flip::split(gid);
+                if tileset.has_tile(index) { Some((coords, index, flip)) }
+                else                       { None }
+            })
+    }
+
+    // Places a specific tile; autotiled neighbours adapt to it. Returns
+    // false, changing nothing, if the tileset has no tile `index`.
+    pub fn set_tile(&mut self, x: i32, y: i32, index: u32) -> bool {
+        if !self.tileset.has_tile(index) {
+            return false;
+        }
+        let gid = self.tileset.base_gid + index;
+        self.place(x, y, Some(gid), false);
+        true
+    }
+
+    pub fn clear_tile(&mut self, x: i32, y: i32) {
+        self.place(x, y, None, false);
+    }
+
+    // Fills a cell with whichever autotile variant fits, updating its
+    // neighbours. Returns false if the main layer isn't autotiled.
+    pub fn paint(&mut self, x: i32, y: i32) -> bool {
+        let index = match &self.autotile {
+            Some(auto) => match auto.set.tiles.first() {
+                Some((index, _)) => *index,
+                None             => return false,
+            },
+            None => return false,
+        };
+
+        let gid = self.tileset.base_gid + index;
+        self.place(x, y, Some(gid), true);
+        true
+    }
+
+    // Changes one cell, retiles around it and updates collision for every
+    // cell that changed, so queries see the change straight away.
+    fn place(&mut self, x: i32, y: i32, gid: Option<u32>, pick_variant: bool) {
+        self.tiles.set(x, y, gid);
+        self.damage.remove(&(x, y));
+        if pick_variant {
+            self.retile(x, y);
+        }
+
+        let mut changed = vec![(x, y)];
+        changed.extend(self.retile_neighbours(x, y));
+        self.update_outlines(&changed);
+    }
+
+    // Swaps the tile at (x, y) for the autotile variant that fits its
+    // neighbours, returning whether it changed.
+    fn retile(&mut self, x: i32, y: i32) -> bool {
+        let picked = match &self.autotile {
+            Some(auto) if self.tiles.get(x, y).is_some() => {
+                let tiles = &self.tiles;
+                auto.set.pick(auto.colour, x, y, |x, y| tiles.get(x, y).is_some())
+            }
+            _ => None,
+        };
+
+        match picked {
+            Some(index) => {
+                let gid = self.tileset.base_gid + index;
+                let changed = self.tiles.get(x, y) != Some(gid);
+                if changed {
+                    self.tiles.set(x, y, Some(gid));
+                }
+                changed
+            }
+            None => false,
+        }
+    }
+
+    // The neighbours of (x, y) that changed.
+    fn retile_neighbours(&mut self, x: i32, y: i32) -> Vec<(i32, i32)> {
+        let mut changed = Vec::new();
+        if self.autotile.is_none() {
+            return changed;
+        }
+
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                if (dx, dy) != (0, 0) && self.retile(x + dx, y + dy) {
+                    changed.push((x + dx, y + dy));
+                }
+            }
+        }
+        changed
+    }
+
+    // Applies damage to the tile at (x, y) after its resistances, returning
+    // true if it was destroyed. Tiles without an "hp" property are
+    // indestructible.
+    pub fn damage_tile(&mut self, x: i32, y: i32, damage: damage::Values) -> bool {
+        let (max_hp, dealt) = match self.tile_at(x, y) {
+            Some((_, tile, _)) => match tile.hit_points {
+                Some(hp) => (hp, damage.resist(&tile.resistance).sum()),
+                None     => return false,
+            },
+            None => return false,
+        };
+
+        if dealt <= 0 {
+            return false;
+        }
+
+        let hp = self.damage.entry((x, y)).or_insert(max_hp);
+        *hp -= dealt;
+        if *hp <= 0 {
+            self.clear_tile(x, y);
+            true
+        }
+        else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use {
+        super::*,
+        crate::alg::P2,
+        std::{fs, path::{Path, PathBuf}},
+    };
+
+    // An empty directory of its own for each test.
+    pub(crate) fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("map-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Writes a map using the repo's tileset, given its gids a row at a time
+    // from the top as Tiled stores them.
+    pub(crate) fn write_map(path: &Path, rows: &[&[u32]]) {
+        let tileset = std::env::current_dir().unwrap().join("tiles.tsx");
+        write_map_with(path, &tileset, None, rows);
+    }
+
+    // Like `write_map`, with another tileset and optionally autotiled with
+    // one of its Wang sets.
+    pub(crate) fn write_map_with(path: &Path, tileset: &Path, autotile: Option<&str>, rows: &[&[u32]]) {
+        let properties = autotile.map_or(String::new(), |name| format!(
+            "<properties><property name=\"autotile\" value=\"{}\"/></properties>", name
+        ));
+        let csv: Vec<String> = rows.iter()
+            .map(|row| row.iter().map(|gid| gid.to_string()).collect::<Vec<_>>().join(","))
+            .collect();
+        let (width, height) = (rows[0].len(), rows.len());
+        fs::write(path, format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<map version="1.2" orientation="orthogonal" renderorder="right-up" width="{w}" height="{h}" tilewidth="16" tileheight="16" infinite="0">
+ <tileset firstgid="1" source="{ts}"/>
+ <layer id="1" name="main" width="{w}" height="{h}">
+  {props}
+  <data encoding="csv">{csv}</data>
+ </layer>
+</map>
+"#, w = width, h = height, ts = tileset.display(), props = properties, csv = csv.join(",\n")))
+            .unwrap();
+    }
+
+    // Writes a tileset over the repo's tile image whose tiles are all solid
+    // squares, given each tile's properties, and with `wang_ids` as a Wang
+    // set called "ground".
+    fn write_tileset(path: &Path, properties: &[&[(&str, &str)]], wang_ids: &[(u32, &str)]) {
+        let image = std::env::current_dir().unwrap().join("tiles.png");
+        let tiles: String = properties.iter().enumerate()
+            .map(|(id, props)| {
+                let props: String = props.iter()
+                    .map(|(name, value)| format!(
+                        "<property name=\"{}\" value=\"{}\"/>", name, value
+                    ))
+                    .collect();
+                format!(concat!(
+                    " <tile id=\"{}\"><properties>{}</properties>",
+                    "<objectgroup><object id=\"1\" x=\"0\" y=\"0\" width=\"16\" height=\"16\"/>",
+                    "</objectgroup></tile>\n"), id, props)
+            })
+            .collect();
+        let wang_tiles: String = wang_ids.iter()
+            .map(|(id, wang_id)| format!("<wangtile tileid=\"{}\" wangid=\"{}\"/>", id, wang_id))
+            .collect();
+        fs::write(path, format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<tileset version="1.2" name="test" tilewidth="16" tileheight="16" tilecount="16" columns="4">
+ <image source="{image}" width="64" height="64"/>
+{tiles} <wangsets><wangset name="ground" tile="-1">{wang}</wangset></wangsets>
+</tileset>
+"#, image = image.display(), tiles = tiles, wang = wang_tiles)).unwrap();
+    }
+
+    const HIT: damage::Values = damage::Values { kinetic: 10, thermal: 0, explosive: 0 };
+    const BURN: damage::Values = damage::Values { kinetic: 0, thermal: 100, explosive: 0 };
+
+    // A row of a 30 hp tile, a 20 hp tile taking half kinetic damage and no
+    // thermal, and an indestructible one.
+    fn damage_map() -> Map {
+        let dir = temp_dir("damage");
+        write_tileset(&dir.join("tiles.tsx"), &[
+            &[("hp", "30")],
+            &[("hp", "20"), ("resist_kinetic", "0.5"), ("resist_thermal", "0")],
+            &[],
+        ], &[]);
+        write_map_with(&dir.join("map.tmx"), &dir.join("tiles.tsx"), None, &[&[1, 2, 3, 0]]);
+        Map::load_headless(dir.join("map.tmx")).unwrap()
+    }
+
+    fn index_at(map: &Map, x: i32, y: i32) -> Option<u32> {
+        map.tile_at(x, y).map(|(_, _, index)| index)
+    }
+
+    #[test]
+    fn destroys_tiles_once_out_of_hit_points() {
+        let mut map = damage_map();
+        assert!(!map.damage_tile(0, 0, HIT));
+        assert!(!map.damage_tile(0, 0, HIT));
+        assert_eq!(index_at(&map, 0, 0), Some(0));
+        assert!(map.damage_tile(0, 0, HIT));
+        assert_eq!(index_at(&map, 0, 0), None);
+        // and the collider went with it
+        assert!(map.colliders_in(P2::new(1.0, 1.0), P2::new(15.0, 15.0)).next().is_none());
+
+        // the indestructible tile and the empty cell shrug it off
+        for _ in 0 .. 10 {
+            assert!(!map.damage_tile(2, 0, HIT));
+            assert!(!map.damage_tile(3, 0, HIT));
+        }
+        assert_eq!(index_at(&map, 2, 0), Some(2));
+    }
+
+    #[test]
+    fn applies_resistances() {
+        let mut map = damage_map();
+        // immune to heat, and no damage taken means nothing to remember
+        assert!(!map.damage_tile(1, 0, BURN));
+        assert!(map.damage.is_empty());
+
+        // half of each hit gets through
+        for _ in 0 .. 3 {
+            assert!(!map.damage_tile(1, 0, HIT));
+        }
+        assert_eq!(map.damage.get(&(1, 0)), Some(&5));
+        assert!(map.damage_tile(1, 0, HIT));
+    }
+
+    #[test]
+    fn replacing_a_tile_heals_it() {
+        let mut map = damage_map();
+        map.damage_tile(0, 0, HIT);
+        map.damage_tile(0, 0, HIT);
+        assert!(map.set_tile(0, 0, 0));
+        assert!(!map.damage_tile(0, 0, HIT));
+        assert!(!map.damage_tile(0, 0, HIT));
+        assert!(map.damage_tile(0, 0, HIT));
+    }
+
+    #[test]
+    fn refuses_unknown_tiles() {
+        let mut map = damage_map();
+        assert!(!map.set_tile(3, 0, 7));
+        assert_eq!(index_at(&map, 3, 0), None);
+        assert!(map.set_tile(3, 0, 2));
+        assert_eq!(index_at(&map, 3, 0), Some(2));
+    }
+
+    // Autotiled with an edge set: 3 is surrounded, 4 has its top open and
+    // 5 stands alone.
+    fn autotiled_map() -> Map {
+        let dir = temp_dir("retile");
+        let plain: &[(&str, &str)] = &[];
+        write_tileset(&dir.join("tiles.tsx"), &[plain; 6], &[
+            (3, "0x01010101"),
+            (4, "0x01010100"),
+            (5, "0x0"),
+        ]);
+        write_map_with(&dir.join("map.tmx"), &dir.join("tiles.tsx"), Some("ground"), &[
+            &[0, 0, 0],
+            &[0, 0, 0],
+        ]);
+        Map::load_headless(dir.join("map.tmx")).unwrap()
+    }
+
+    #[test]
+    fn retiles_painted_cells_and_their_neighbours() {
+        let mut map = autotiled_map();
+        for x in 0 .. 3 {
+            assert!(map.paint(x, 0));
+        }
+        assert_eq!(index_at(&map, 0, 0), Some(5));
+        assert_eq!(index_at(&map, 1, 0), Some(4));
+        assert_eq!(index_at(&map, 2, 0), Some(5));
+
+        // covering the middle makes it a surrounded tile
+        assert!(map.paint(1, 1));
+        assert_eq!(index_at(&map, 1, 1), Some(5));
+        assert_eq!(index_at(&map, 1, 0), Some(3));
+
+        // and uncovering it puts the surface back
+        map.clear_tile(1, 1);
+        assert_eq!(index_at(&map, 1, 0), Some(4));
+
+        // placing a specific tile keeps it, though neighbours still adapt
+        assert!(map.set_tile(1, 1, 0));
+        assert_eq!(index_at(&map, 1, 1), Some(0));
+        assert_eq!(index_at(&map, 1, 0), Some(3));
+    }
+}
@@ -0,0 +1,586 @@
+
+// Merges the colliders of neighbouring tiles into continuous outlines, so
+// that bodies sliding along a floor or wall don't catch on the edges between
+// tiles.
+
+use {
+    super::TILE_SIZE,
+    crate::alg::{V2, Vu2, P2, Seg2, Shape, EPSILON},
+    std::collections::{HashMap, HashSet},
+};
+
+// vertices are snapped to 1/16 px so that shared edges compare exactly
+const QUANTUM: f32 = 16.0;
+
+type Key = (i64, i64);
+
+fn quantize(v: V2) -> Key {
+    ((v.x * QUANTUM).round() as i64, (v.y * QUANTUM).round() as i64)
+}
+
+fn dequantize(k: Key) -> P2 {
+    P2::new(k.0 as f32 / QUANTUM, k.1 as f32 / QUANTUM)
+}
+
+// One side of solid ground. Edges are one-sided: they only block bodies on
+// the side `normal` points to.
+#[derive(Clone, Copy, Debug)]
+pub struct Edge {
+    pub a:      P2,
+    pub b:      P2,
+    pub normal: Vu2,
+}
+
+impl Edge {
+    fn new(a: P2, b: P2) -> Edge {
+        let d = b - a;
+        let normal = Vu2::new_normalize(V2::new(d.y, -d.x));
+        Edge { a, b, normal }
+    }
+
+    pub fn segment(&self) -> Seg2 {
+        Seg2::new_from_points(self.a, self.b)
+    }
+
+    pub fn faces(&self, p: P2) -> bool {
+        (p - self.a).dot(&self.normal) > 0.0
+    }
+}
+
+// A polygon chain with the solid on its left. Open chains occur where
+// colliders don't close up, e.g. at the edge of a one-way platform.
+#[derive(Clone, Debug)]
+pub struct Outline {
+    pub verts:  Vec<P2>,
+    pub closed: bool,
+}
+
+impl Outline {
+    pub fn edges<'a>(&'a self) -> impl Iterator<Item = Edge> + 'a {
+        let n = self.verts.len();
+        let count = if self.closed { n } else { n.saturating_sub(1) };
+        (0..count).map(move |i| Edge::new(self.verts[i], self.verts[(i + 1) % n]))
+    }
+}
+
+// Where an unmerged edge came from. Edges lying along the side of a cell
+// belong to that side rather than to either cell, so that the two cells'
+// runs along it can cancel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Slot {
+    // off the cell's sides
+    Inside(i32, i32),
+    // the left side of cell (x, y)
+    Left(i32, i32),
+    // the bottom side of cell (x, y)
+    Bottom(i32, i32),
+}
+
+impl Slot {
+    // Every slot a tile at (x, y) can put edges in.
+    fn around(x: i32, y: i32) -> [Slot; 5] {
+        [
+            Slot::Inside(x, y),
+            Slot::Left(x, y),
+            Slot::Left(x + 1, y),
+            Slot::Bottom(x, y),
+            Slot::Bottom(x, y + 1),
+        ]
+    }
+}
+
+type RawEdge = (Key, Key);
+
+struct Chain {
+    outline: Outline,
+    edges:   Vec<Edge>,
+    // the unmerged edges the chain was linked from
+    raw:     Vec<RawEdge>,
+}
+
+pub struct Outlines {
+    // unmerged edges, after opposite runs along cell sides have cancelled
+    slots:      HashMap<Slot, Vec<RawEdge>>,
+    chains:     HashMap<u64, Chain>,
+    next_chain: u64,
+    // chains by the ends of their unmerged edges
+    vertices:   HashMap<Key, Vec<u64>>,
+    // chain and edge index by the tile cells the edge's bounding box touches
+    cells:      HashMap<(i32, i32), Vec<(u64, usize)>>,
+}
+
+fn collinear(a: Key, b: Key, c: Key) -> bool {
+    let (ux, uy) = (b.0 - a.0, b.1 - a.1);
+    let (vx, vy) = (c.0 - b.0, c.1 - b.1);
+    ux * vy - uy * vx == 0 && ux * vx + uy * vy > 0
+}
+
+fn simplify(keys: Vec<Key>, closed: bool) -> Vec<Key> {
+    let mut out: Vec<Key> = Vec::with_capacity(keys.len());
+    for k in keys {
+        while out.len() >= 2 && collinear(out[out.len() - 2], out[out.len() - 1], k) {
+            out.pop();
+        }
+        out.push(k);
+    }
+
+    if closed {
+        while out.len() >= 3 {
+            let n = out.len();
+            if collinear(out[n - 1], out[0], out[1]) {
+                out.remove(0);
+            }
+            else if collinear(out[n - 2], out[n - 1], out[0]) {
+                out.pop();
+            }
+            else {
+                break;
+            }
+        }
+    }
+
+    out
+}
+
+// Cancels opposite-facing runs along one tile boundary line, returning what
+// remains as (from, to) pieces.
+fn cancel_runs(runs: &[(i64, i64)]) -> Vec<(i64, i64)> {
+    let mut events: Vec<(i64, i32)> = Vec::with_capacity(runs.len() * 2);
+    for &(a, b) in runs {
+        if a < b { events.push((a,  1)); events.push((b, -1)); }
+        else     { events.push((b, -1)); events.push((a,  1)); }
+    }
+    events.sort();
+
+    let mut pieces = Vec::new();
+    let mut net = 0;
+    let mut i = 0;
+    while i < events.len() {
+        let pos = events[i].0;
+        while i < events.len() && events[i].0 == pos {
+            net += events[i].1;
+            i += 1;
+        }
+
+        if i < events.len() {
+            let next = events[i].0;
+            match net.signum() {
+                 1 => pieces.push((pos, next)),
+                -1 => pieces.push((next, pos)),
+                 _ => { }
+            }
+        }
+    }
+
+    pieces
+}
+
+// A tile's collider in pixels, quantized and wound anticlockwise, as runs
+// along the tile's sides and the edges off them. None if the collider is
+// degenerate.
+fn tile_edges(x: i32, y: i32, shape: &Shape) -> Option<Vec<(Slot, RawEdge)>> {
+    let tile_size = TILE_SIZE as f32;
+    let cell = (tile_size * QUANTUM) as i64;
+
+    let origin = V2::new(x as f32 * tile_size, y as f32 * tile_size);
+    let mut world = Shape::new_from_vec(
+        shape.verts.iter()
+            .map(|v| V2::new(origin.x + v.x, origin.y + tile_size - v.y))
+            .collect()
+    );
+
+    let area = world.signed_area();
+    if world.verts.len() < 3 || area.abs() < EPSILON {
+        return None;
+    }
+    if area < 0.0 {
+        world.verts.reverse();
+    }
+
+    let (left, bottom) = (x as i64 * cell, y as i64 * cell);
+    let n = world.verts.len();
+    let mut out = Vec::with_capacity(n);
+    for i in 0..n {
+        let a = quantize(world.verts[i]);
+        let b = quantize(world.verts[(i + 1) % n]);
+        let slot =
+            if a == b { continue; }
+            else if a.0 == b.0 && a.0 == left          { Slot::Left(x, y) }
+            else if a.0 == b.0 && a.0 == left + cell   { Slot::Left(x + 1, y) }
+            else if a.1 == b.1 && a.1 == bottom        { Slot::Bottom(x, y) }
+            else if a.1 == b.1 && a.1 == bottom + cell { Slot::Bottom(x, y + 1) }
+            else                                       { Slot::Inside(x, y) };
+        out.push((slot, (a, b)));
+    }
+    Some(out)
+}
+
+// The unmerged edges in `wanted` slots, from the tiles in `cells`, which
+// must include every tile touching those slots.
+fn slot_edges(
+    cells:    impl Iterator<Item = (i32, i32)>,
+    wanted:   impl Fn(&Slot) -> bool,
+    collider: impl Fn(i32, i32) -> Option<Shape>)
+    -> HashMap<Slot, Vec<RawEdge>>
+{
+    let mut slots: HashMap<Slot, Vec<RawEdge>> = HashMap::new();
+    for (x, y) in cells {
+        let shape = match collider(x, y) {
+            Some(shape) => shape,
+            None        => continue,
+        };
+        for (slot, edge) in tile_edges(x, y, &shape).into_iter().flatten() {
+            if wanted(&slot) {
+                slots.entry(slot).or_default().push(edge);
+            }
+        }
+    }
+
+    // runs along a side cancel out where solid meets solid
+    for (slot, edges) in &mut slots {
+        let vertical = match slot {
+            Slot::Inside(..) => continue,
+            Slot::Left(..)   => true,
+            Slot::Bottom(..) => false,
+        };
+        let (coord, runs): (i64, Vec<(i64, i64)>) = if vertical {
+            (edges[0].0 .0, edges.iter().map(|(a, b)| (a.1, b.1)).collect())
+        }
+        else {
+            (edges[0].0 .1, edges.iter().map(|(a, b)| (a.0, b.0)).collect())
+        };
+
+        *edges = cancel_runs(&runs).into_iter()
+            .map(|(from, to)| {
+                if vertical { ((coord, from), (coord, to)) }
+                else        { ((from, coord), (to, coord)) }
+            })
+            .collect();
+    }
+
+    slots.retain(|_, edges| !edges.is_empty());
+    slots
+}
+
+// Joins unmerged edges into chains, each returned with the edges it was made
+// from.
+fn link(edges: &[RawEdge]) -> Vec<(Outline, Vec<RawEdge>)> {
+    // Where chains touch at a vertex, which way a chain goes on depends on
+    // the order edges are found in; sort them so the same edges always link
+    // the same way, whatever order the slots were hashed in.
+    let mut edges = edges.to_vec();
+    edges.sort_unstable();
+
+    let mut outgoing: HashMap<Key, Vec<usize>> = HashMap::new();
+    let mut incoming: HashSet<Key> = HashSet::new();
+    for (i, (a, b)) in edges.iter().enumerate() {
+        outgoing.entry(*a).or_default().push(i);
+        incoming.insert(*b);
+    }
+
+    // start from chain ends first so open chains come out whole
+    let mut order: Vec<usize> = (0..edges.len())
+        .filter(|&i| !incoming.contains(&edges[i].0))
+        .collect();
+    order.extend(0..edges.len());
+
+    let mut used = vec![false; edges.len()];
+    let mut chains = Vec::new();
+
+    for start in order {
+        if used[start] {
+            continue;
+        }
+
+        let mut keys = vec![edges[start].0];
+        let mut raw = Vec::new();
+        let mut cur = start;
+        loop {
+            used[cur] = true;
+            raw.push(edges[cur]);
+            let end = edges[cur].1;
+            keys.push(end);
+
+            let next = outgoing.get(&end)
+                .and_then(|es| es.iter().find(|&&e| !used[e]));
+            match next {
+                Some(&next) => cur = next,
+                None        => break,
+            }
+        }
+
+        let closed = keys.first() == keys.last();
+        if closed {
+            keys.pop();
+        }
+
+        let verts = simplify(keys, closed).into_iter()
+            .map(dequantize)
+            .collect();
+        chains.push((Outline { verts, closed }, raw));
+    }
+
+    chains
+}
+
+fn edge_cells(edge: &Edge) -> impl Iterator<Item = (i32, i32)> {
+    let (x0, x1, y0, y1) = cell_range(
+        edge.a.x.min(edge.b.x), edge.a.y.min(edge.b.y),
+        edge.a.x.max(edge.b.x), edge.a.y.max(edge.b.y),
+    );
+    (y0..=y1).flat_map(move |y| (x0..=x1).map(move |x| (x, y)))
+}
+
+impl Outlines {
+    // Builds outlines from tile colliders, given as tile coordinates and
+    // shapes in the tile's own pixel space (y-down, as drawn in Tiled).
+    pub fn build<'a>(tiles: impl Iterator<Item = ((i32, i32), &'a Shape)>) -> Outlines {
+        let tiles: HashMap<(i32, i32), &Shape> = tiles.collect();
+        let slots = slot_edges(
+            tiles.keys().cloned(),
+            |_| true,
+            |x, y| tiles.get(&(x, y)).map(|shape| (*shape).clone()),
+        );
+
+        let mut outlines = Outlines {
+            slots:      HashMap::new(),
+            chains:     HashMap::new(),
+            next_chain: 0,
+            vertices:   HashMap::new(),
+            cells:      HashMap::new(),
+        };
+
+        let raw: Vec<RawEdge> = slots.values().flatten().cloned().collect();
+        outlines.slots = slots;
+        for (outline, raw) in link(&raw) {
+            outlines.insert(outline, raw);
+        }
+        outlines
+    }
+
+    // Brings the outlines up to date after the tiles at `changed` have, with
+    // `collider` giving the current collider of any tile. Only chains passing
+    // near the changed tiles are rebuilt.
+    pub fn update(
+        &mut self,
+        changed:  &[(i32, i32)],
+        collider: impl Fn(i32, i32) -> Option<Shape>)
+    {
+        let stale: HashSet<Slot> = changed.iter()
+            .flat_map(|&(x, y)| Slot::around(x, y).to_vec())
+            .collect();
+        // every tile touching a stale slot
+        let sources: HashSet<(i32, i32)> = changed.iter()
+            .flat_map(|&(x, y)| {
+                (-1..=1).flat_map(move |dy| (-1..=1).map(move |dx| (x + dx, y + dy)))
+            })
+            .collect();
+        let fresh = slot_edges(sources.into_iter(), |slot| stale.contains(slot), collider);
+
+        let mut removed: HashMap<RawEdge, usize> = HashMap::new();
+        for slot in &stale {
+            for edge in self.slots.remove(slot).into_iter().flatten() {
+                *removed.entry(edge).or_default() += 1;
+            }
+        }
+
+        // chains that lost edges or may join up with new ones
+        let mut touched: HashSet<Key> = HashSet::new();
+        for &(a, b) in removed.keys().chain(fresh.values().flatten()) {
+            touched.insert(a);
+            touched.insert(b);
+        }
+        let mut affected: Vec<u64> = touched.iter()
+            .filter_map(|key| self.vertices.get(key))
+            .flatten()
+            .cloned()
+            .collect();
+        affected.sort_unstable();
+        affected.dedup();
+
+        let mut pool: Vec<RawEdge> = Vec::new();
+        for id in affected {
+            pool.extend(self.remove(id));
+        }
+        pool.retain(|edge| match removed.get_mut(edge) {
+            Some(count) if *count > 0 => { *count -= 1; false }
+            _                         => true,
+        });
+        pool.extend(fresh.values().flatten().cloned());
+        self.slots.extend(fresh);
+
+        for (outline, raw) in link(&pool) {
+            self.insert(outline, raw);
+        }
+    }
+
+    fn insert(&mut self, outline: Outline, raw: Vec<RawEdge>) {
+        let id = self.next_chain;
+        self.next_chain += 1;
+
+        let edges: Vec<Edge> = outline.edges().collect();
+        for (i, edge) in edges.iter().enumerate() {
+            for cell in edge_cells(edge) {
+                self.cells.entry(cell).or_default().push((id, i));
+            }
+        }
+        for &(a, b) in &raw {
+            for key in &[a, b] {
+                let ids = self.vertices.entry(*key).or_default();
+                if ids.last() != Some(&id) {
+                    ids.push(id);
+                }
+            }
+        }
+
+        self.chains.insert(id, Chain { outline, edges, raw });
+    }
+
+    // Drops a chain, returning the edges it was made from.
+    fn remove(&mut self, id: u64) -> Vec<RawEdge> {
+        let chain = match self.chains.remove(&id) {
+            Some(chain) => chain,
+            None        => return Vec::new(),
+        };
+
+        for edge in &chain.edges {
+            for cell in edge_cells(edge) {
+                if let Some(entries) = self.cells.get_mut(&cell) {
+                    entries.retain(|&(chain_id, _)| chain_id != id);
+                    if entries.is_empty() {
+                        self.cells.remove(&cell);
+                    }
+                }
+            }
+        }
+        for &(a, b) in &chain.raw {
+            for key in &[a, b] {
+                if let Some(ids) = self.vertices.get_mut(key) {
+                    ids.retain(|&chain_id| chain_id != id);
+                    if ids.is_empty() {
+                        self.vertices.remove(key);
+                    }
+                }
+            }
+        }
+
+        chain.raw
+    }
+
+    pub fn chains(&self) -> impl Iterator<Item = &Outline> {
+        self.chains.values().map(|chain| &chain.outline)
+    }
+
+    // Edges whose bounding boxes may overlap the box from `min` to `max`, in
+    // pixels.
+    pub fn edges_in(&self, min: P2, max: P2) -> Vec<&Edge> {
+        let (x0, x1, y0, y1) = cell_range(min.x, min.y, max.x, max.y);
+
+        let mut found: Vec<(u64, usize)> = Vec::new();
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                if let Some(cell) = self.cells.get(&(x, y)) {
+                    found.extend_from_slice(cell);
+                }
+            }
+        }
+        found.sort_unstable();
+        found.dedup();
+
+        found.into_iter().map(|(id, i)| &self.chains[&id].edges[i]).collect()
+    }
+}
+
+fn cell_range(min_x: f32, min_y: f32, max_x: f32, max_y: f32) -> (i32, i32, i32, i32) {
+    let tile_size = TILE_SIZE as f32;
+    (
+        (min_x / tile_size).floor() as i32,
+        (max_x / tile_size).floor() as i32,
+        (min_y / tile_size).floor() as i32,
+        (max_y / tile_size).floor() as i32,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::game::map::{placed_collider, Map},
+    };
+
+    fn sorted(mut edges: Vec<RawEdge>) -> Vec<RawEdge> {
+        edges.sort_unstable();
+        edges
+    }
+
+    // Checks the bookkeeping of updated outlines and that they're made of
+    // the same edges as outlines built from scratch.
+    fn check_against_rebuild(map: &Map) {
+        let (tileset, tiles) = (&map.tileset, &map.tiles);
+        let colliders: Vec<((i32, i32), Shape)> = tiles.iter()
+            .filter_map(|((x, y), _)| {
+                placed_collider(tileset, tiles, x, y).map(|shape| ((x, y), shape))
+            })
+            .collect();
+        let built = Outlines::build(colliders.iter().map(|(pos, shape)| (*pos, shape)));
+        let updated = map.outlines();
+
+        let slot_edges = |outlines: &Outlines| {
+            sorted(outlines.slots.values().flatten().cloned().collect())
+        };
+        assert_eq!(slot_edges(updated), slot_edges(&built));
+
+        let chain_edges = sorted(updated.chains.values()
+            .flat_map(|chain| chain.raw.iter().cloned())
+            .collect());
+        assert_eq!(chain_edges, slot_edges(&built));
+
+        for chain in updated.chains.values() {
+            for pair in chain.raw.windows(2) {
+                assert_eq!(pair[0].1, pair[1].0, "chain isn't continuous");
+            }
+        }
+        for (id, i) in updated.cells.values().flatten() {
+            assert!(updated.chains[id].edges.len() > *i, "stale cell entry");
+        }
+        for ids in updated.vertices.values() {
+            assert!(ids.iter().all(|id| updated.chains.contains_key(id)), "stale vertex entry");
+        }
+    }
+
+    #[test]
+    fn updates_match_rebuild() {
+        let mut map = Map::load_headless("test.tmx").expect("loading test.tmx");
+        let b = map.bounds();
+        let (x, y, index) = (b.left .. b.right)
+            .find_map(|x| map.tile_at(x, b.bottom).map(|(_, _, index)| (x, b.bottom, index)))
+            .expect("test.tmx has no tiles on its bottom row");
+
+        // a hole in the floor, then filled back in
+        map.clear_tile(x + 3, y);
+        check_against_rebuild(&map);
+        map.set_tile(x + 3, y, index);
+        check_against_rebuild(&map);
+
+        // a floating block, a tile touching it at a corner, and a bridge
+        // joining it to the floor
+        map.set_tile(x + 5, y + 4, index);
+        check_against_rebuild(&map);
+        map.set_tile(x + 6, y + 5, index);
+        check_against_rebuild(&map);
+        for dy in 1 .. 4 {
+            map.set_tile(x + 5, y + dy, index);
+            check_against_rebuild(&map);
+        }
+        map.clear_tile(x + 5, y + 2);
+        check_against_rebuild(&map);
+
+        // every tile of the map, one at a time
+        let cells: Vec<(i32, i32)> = map.tiles.iter().map(|(pos, _)| pos).collect();
+        for (x, y) in cells {
+            map.clear_tile(x, y);
+        }
+        check_against_rebuild(&map);
+        assert!(map.outlines().chains().next().is_none());
+    }
+}
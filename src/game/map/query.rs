@@ -0,0 +1,269 @@
+
+// Spatial queries over the main layer, in pixel coordinates (y-up).
+
+use {
+    super::{outline::Edge, Map, Tile, TILE_SIZE},
+    crate::alg::P2,
+};
+
+fn cell_of(p: P2) -> (i32, i32) {
+    let tile_size = TILE_SIZE as f32;
+    ((p.x / tile_size).floor() as i32, (p.y / tile_size).floor() as i32)
+}
+
+// Visits the cells crossed by a segment in order, after Amanatides and Woo.
+struct CellWalk {
+    cell:   (i32, i32),
+    step:   (i32, i32),
+    // segment parameter at the next x and y cell boundaries
+    t_max:  (f32, f32),
+    // parameter distance between boundaries along each axis
+    t_step: (f32, f32),
+    left:   u32,
+}
+
+impl CellWalk {
+    fn new(a: P2, b: P2) -> CellWalk {
+        let tile_size = TILE_SIZE as f32;
+        let start = cell_of(a);
+        let end   = cell_of(b);
+        let d = b - a;
+
+        let axis = |from: f32, delta: f32, cell: i32| -> (i32, f32, f32) {
+            if delta > 0.0 {
+                let edge = (cell + 1) as f32 * tile_size;
+                (1, (edge - from) / delta, tile_size / delta)
+            }
+            else if delta < 0.0 {
+                let edge = cell as f32 * tile_size;
+                (-1, (edge - from) / delta, -tile_size / delta)
+            }
+            else {
+                (0, f32::INFINITY, f32::INFINITY)
+            }
+        };
+
+        let (sx, tx, dx) = axis(a.x, d.x, start.0);
+        let (sy, ty, dy) = axis(a.y, d.y, start.1);
+        let left = ((end.0 - start.0).abs() + (end.1 - start.1).abs()) as u32 + 1;
+
+        CellWalk {
+            cell:   start,
+            step:   (sx, sy),
+            t_max:  (tx, ty),
+            t_step: (dx, dy),
+            left,
+        }
+    }
+}
+
+impl Iterator for CellWalk {
+    type Item = (i32, i32);
+
+    fn next(&mut self) -> Option<(i32, i32)> {
+        if self.left == 0 {
+            return None;
+        }
+        self.left -= 1;
+
+        let cell = self.cell;
+        // Through an exact corner the two are equal and y steps first, so
+        // the walk goes via the cell above or below the corner rather than
+        // skipping diagonally.
+        if self.t_max.0 < self.t_max.1 {
+            self.cell.0 += self.step.0;
+            self.t_max.0 += self.t_step.0;
+        }
+        else {
+            self.cell.1 += self.step.1;
+            self.t_max.1 += self.t_step.1;
+        }
+        Some(cell)
+    }
+}
+
+impl Map {
+    // Edges of the merged tile colliders whose bounding boxes overlap the
+    // box from `min` to `max`. Seams between solid tiles have no edges, so
+    // bodies sliding along the ground don't catch on them.
+    pub fn colliders_in(&self, min: P2, max: P2) -> impl Iterator<Item = &Edge> + '_ {
+        self.outlines.edges_in(min, max)
+            .into_iter()
+            .filter(move |edge| {
+                edge.a.x.min(edge.b.x) <= max.x && edge.a.x.max(edge.b.x) >= min.x
+                    && edge.a.y.min(edge.b.y) <= max.y && edge.a.y.max(edge.b.y) >= min.y
+            })
+    }
+
+    // Tiles in the cells crossed by the segment from `a` to `b`, nearest to
+    // `a` first.
+    pub fn tiles_along(&self, a: P2, b: P2)
+        -> impl Iterator<Item = ((i32, i32), &Tile)> + '_
+    {
+        CellWalk::new(a, b).filter_map(move |(x, y)| {
+            self.tile_at(x, y).map(|(_, tile, _)| ((x, y), tile))
+        })
+    }
+
+    // The occupied cell closest to `p` and the distance from `p` to it, if
+    // any lies within `max_dist` pixels. Distances are measured to the cell's
+    // square, not to its collider.
+    pub fn nearest_solid(&self, p: P2, max_dist: f32) -> Option<((i32, i32), f32)> {
+        let tile_size = TILE_SIZE as f32;
+        let (cx, cy) = cell_of(p);
+
+        let dist_to = |x: i32, y: i32| {
+            let left   = x as f32 * tile_size;
+            let bottom = y as f32 * tile_size;
+            let dx = (left - p.x).max(p.x - (left + tile_size)).max(0.0);
+            let dy = (bottom - p.y).max(p.y - (bottom + tile_size)).max(0.0);
+            (dx * dx + dy * dy).sqrt()
+        };
+
+        let max_ring = (max_dist / tile_size).ceil() as i32 + 1;
+        let mut best: Option<((i32, i32), f32)> = None;
+
+        for ring in 0 ..= max_ring {
+            // every cell in this ring is at least (ring - 1) tiles away
+            let ring_min = (ring - 1).max(0) as f32 * tile_size;
+            if ring_min > max_dist || best.is_some_and(|(_, d)| d <= ring_min) {
+                break;
+            }
+
+            for y in cy - ring ..= cy + ring {
+                // rows in between only have the two end cells in the ring
+                let on_edge = y == cy - ring || y == cy + ring;
+                let stride = if on_edge { 1 } else { 2 * ring as usize };
+                for x in (cx - ring ..= cx + ring).step_by(stride) {
+                    if self.tiles.get(x, y).is_none() {
+                        continue;
+                    }
+                    let d = dist_to(x, y);
+                    if d <= max_dist && best.is_none_or(|(_, bd)| d < bd) {
+                        best = Some(((x, y), d));
+                    }
+                }
+            }
+        }
+
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::game::map::tests::{temp_dir, write_map},
+    };
+
+    fn walk(a: (f32, f32), b: (f32, f32)) -> Vec<(i32, i32)> {
+        CellWalk::new(P2::new(a.0, a.1), P2::new(b.0, b.1)).collect()
+    }
+
+    fn map(name: &str, rows: &[&[u32]]) -> Map {
+        let path = temp_dir(name).join("map.tmx");
+        write_map(&path, rows);
+        Map::load_headless(&path).unwrap()
+    }
+
+    #[test]
+    fn walks_cells_in_order() {
+        assert_eq!(walk((8.0, 8.0), (40.0, 8.0)), [(0, 0), (1, 0), (2, 0)]);
+        assert_eq!(walk((40.0, 8.0), (8.0, 8.0)), [(2, 0), (1, 0), (0, 0)]);
+        assert_eq!(walk((8.0, 8.0), (12.0, 4.0)), [(0, 0)]);
+        assert_eq!(walk((4.0, 4.0), (20.0, 40.0)), [(0, 0), (0, 1), (1, 1), (1, 2)]);
+    }
+
+    #[test]
+    fn steps_y_first_through_corners() {
+        assert_eq!(
+            walk((8.0, 8.0), (40.0, 40.0)),
+            [(0, 0), (0, 1), (1, 1), (1, 2), (2, 2)],
+        );
+        assert_eq!(
+            walk((40.0, 8.0), (8.0, 40.0)),
+            [(2, 0), (2, 1), (1, 1), (1, 2), (0, 2)],
+        );
+    }
+
+    #[test]
+    fn walks_from_negative_cells() {
+        assert_eq!(walk((-20.0, 4.0), (20.0, 4.0)), [(-2, 0), (-1, 0), (0, 0), (1, 0)]);
+        assert_eq!(walk((-8.0, -8.0), (8.0, 8.0)), [(-1, -1), (-1, 0), (0, 0)]);
+        assert_eq!(walk((-0.5, -16.0), (-0.5, -40.0)), [(-1, -1), (-1, -2), (-1, -3)]);
+    }
+
+    #[test]
+    fn finds_tiles_along_segments() {
+        let map = map("along", &[
+            &[0, 0, 0, 0, 0],
+            &[0, 1, 0, 1, 0],
+        ]);
+        let cells = |a: (f32, f32), b: (f32, f32)| -> Vec<(i32, i32)> {
+            map.tiles_along(P2::new(a.0, a.1), P2::new(b.0, b.1))
+                .map(|(cell, _)| cell)
+                .collect()
+        };
+        assert_eq!(cells((-24.0, 8.0), (72.0, 8.0)), [(1, 0), (3, 0)]);
+        assert_eq!(cells((72.0, 8.0), (-24.0, 8.0)), [(3, 0), (1, 0)]);
+        assert_eq!(cells((8.0, 24.0), (72.0, 24.0)), []);
+    }
+
+    #[test]
+    fn finds_nearer_tiles_on_later_rings() {
+        let map = map("rings", &[
+            &[0, 0, 0, 0, 0, 1],
+            &[0, 0, 0, 0, 0, 0],
+            &[1, 0, 0, 0, 0, 0],
+        ]);
+        // from the right of cell (2, 2), the tile at (0, 0) on ring 2 is
+        // about 39.6 away, and the one at (5, 2) on ring 3 only 32.5, so
+        // the search can't stop at the end of ring 2
+        let p = P2::new(47.5, 40.0);
+        assert_eq!(map.nearest_solid(p, 100.0), Some(((5, 2), 32.5)));
+
+        // exactly at the cutoff counts, just beyond doesn't
+        assert_eq!(map.nearest_solid(p, 32.5), Some(((5, 2), 32.5)));
+        assert_eq!(map.nearest_solid(p, 32.0), None);
+
+        // inside a tile is no distance at all
+        assert_eq!(map.nearest_solid(P2::new(8.0, 8.0), 0.0), Some(((0, 0), 0.0)));
+    }
+
+    #[test]
+    fn matches_brute_force_nearest() {
+        let map = map("brute", &[
+            &[0, 0, 0, 0, 0, 0, 0, 1],
+            &[0, 0, 0, 0, 0, 0, 0, 0],
+            &[0, 1, 0, 0, 0, 0, 0, 0],
+            &[0, 0, 0, 0, 0, 1, 0, 0],
+            &[0, 0, 0, 0, 0, 0, 0, 0],
+            &[1, 0, 0, 0, 0, 0, 0, 0],
+        ]);
+        let tile_size = TILE_SIZE as f32;
+        let dist = |p: P2, (x, y): (i32, i32)| {
+            let (left, bottom) = (x as f32 * tile_size, y as f32 * tile_size);
+            let dx = (left - p.x).max(p.x - (left + tile_size)).max(0.0);
+            let dy = (bottom - p.y).max(p.y - (bottom + tile_size)).max(0.0);
+            (dx * dx + dy * dy).sqrt()
+        };
+
+        for &max_dist in &[20.0, 45.0, 1000.0] {
+            for py in (-40 .. 130).step_by(7) {
+                for px in (-40 .. 170).step_by(9) {
+                    let p = P2::new(px as f32, py as f32);
+                    let expected = map.tiles.iter()
+                        .map(|(cell, _)| dist(p, cell))
+                        .filter(|&d| d <= max_dist)
+                        .fold(None, |best: Option<f32>, d| Some(best.map_or(d, |b| b.min(d))));
+                    let found = map.nearest_solid(p, max_dist);
+                    assert_eq!(found.map(|(_, d)| d), expected, "from {}", p);
+                    if let Some((cell, d)) = found {
+                        assert_eq!(dist(p, cell), d);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,1393 @@
+
+// Reader and writer for Tiled's TMX maps and TSX tilesets, including infinite
+// maps with chunked layer data.
+
+use {
+    std::{
+        error::Error,
+        fs::File,
+        io::{self, BufReader, BufWriter, Read, Write},
+        path::{Component, Path, PathBuf},
+        str::FromStr,
+    },
+    xml::reader::{EventReader, XmlEvent},
+};
+
+#[derive(Debug)]
+pub enum TmxError {
+    Io(io::Error),
+    Xml(xml::reader::Error),
+    UnexpectedRoot(String),
+    MissingAttribute(String, &'static str),
+    BadAttribute(String, &'static str, String),
+    UnsupportedEncoding(String),
+    UnsupportedCompression(String),
+    BadData(String),
+}
+
+impl std::fmt::Display for TmxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for TmxError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TmxError::Io(e)  => Some(e),
+            TmxError::Xml(e) => Some(e),
+            _                => None
+        }
+    }
+}
+
+impl From<io::Error> for TmxError {
+    fn from(e: io::Error) -> TmxError {
+        TmxError::Io(e)
+    }
+}
+
+impl From<xml::reader::Error> for TmxError {
+    fn from(e: xml::reader::Error) -> TmxError {
+        TmxError::Xml(e)
+    }
+}
+
+// minimal XML tree, enough to walk TMX documents
+#[derive(Clone, Debug)]
+pub struct Element {
+    pub name:       String,
+    pub attributes: Vec<(String, String)>,
+    pub children:   Vec<Element>,
+    pub text:       String,
+}
+
+impl Element {
+    pub fn new(name: &str) -> Element {
+        Element {
+            name:       name.to_string(),
+            attributes: Vec::new(),
+            children:   Vec::new(),
+            text:       String::new(),
+        }
+    }
+
+    pub fn parse(reader: impl Read) -> Result<Element, TmxError> {
+        let mut stack: Vec<Element> = Vec::new();
+
+        for event in EventReader::new(reader) {
+            match event? {
+                XmlEvent::StartElement { name, attributes, .. } => {
+                    let mut element = Element::new(&name.local_name);
+                    element.attributes = attributes.into_iter()
+                        .map(|attr| (attr.name.local_name, attr.value))
+                        .collect();
+                    stack.push(element);
+                }
+
+                XmlEvent::EndElement { .. } => {
+                    let element = stack.pop().unwrap();
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(element),
+                        None         => return Ok(element)
+                    }
+                }
+
+                XmlEvent::Characters(text) | XmlEvent::CData(text) => {
+                    if let Some(element) = stack.last_mut() {
+                        element.text.push_str(&text);
+                    }
+                }
+
+                _ => { }
+            }
+        }
+
+        Err(TmxError::UnexpectedRoot("<none>".to_string()))
+    }
+
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.attributes.iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn parse_attr<T: FromStr>(&self, name: &'static str)
+        -> Result<Option<T>, TmxError>
+    {
+        match self.attr(name) {
+            None        => Ok(None),
+            Some(value) => value.parse()
+                .map(Some)
+                .map_err(|_| TmxError::BadAttribute(
+                    self.name.clone(), name, value.to_string()
+                ))
+        }
+    }
+
+    fn req_attr<T: FromStr>(&self, name: &'static str) -> Result<T, TmxError> {
+        self.parse_attr(name)?
+            .ok_or_else(|| TmxError::MissingAttribute(self.name.clone(), name))
+    }
+
+    fn attr_or<T: FromStr>(&self, name: &'static str, default: T)
+        -> Result<T, TmxError>
+    {
+        Ok(self.parse_attr(name)?.unwrap_or(default))
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    pub fn children_named<'a>(&'a self, name: &'a str)
+        -> impl Iterator<Item = &'a Element> + 'a
+    {
+        self.children.iter().filter(move |child| child.name == name)
+    }
+
+    pub fn with_attr(mut self, name: &str, value: impl ToString) -> Element {
+        self.attributes.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn with_child(mut self, child: Element) -> Element {
+        self.children.push(child);
+        self
+    }
+
+    pub fn write(&self, out: &mut impl Write, depth: usize) -> io::Result<()> {
+        let indent = " ".repeat(depth);
+        write!(out, "{}<{}", indent, self.name)?;
+        for (name, value) in &self.attributes {
+            write!(out, " {}=\"{}\"", name, escape(value, true))?;
+        }
+
+        if self.children.is_empty() && self.text.is_empty() {
+            return writeln!(out, "/>");
+        }
+
+        write!(out, ">")?;
+        if !self.text.is_empty() {
+            write!(out, "{}", escape(&self.text, false))?;
+        }
+        if !self.children.is_empty() {
+            writeln!(out)?;
+            for child in &self.children {
+                child.write(out, depth + 1)?;
+            }
+            write!(out, "{}", indent)?;
+        }
+        writeln!(out, "</{}>", self.name)
+    }
+}
+
+fn escape(s: &str, in_attr: bool) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&'  => escaped.push_str("&amp;"),
+            '<'  => escaped.push_str("&lt;"),
+            '>'  => escaped.push_str("&gt;"),
+            '"'  => escaped.push_str("&quot;"),
+            '\n' if in_attr => escaped.push_str("&#10;"),
+            _    => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn load_root(path: &Path, expected: &str) -> Result<Element, TmxError> {
+    let file = File::open(path)?;
+    let root = Element::parse(BufReader::new(file))?;
+    if root.name != expected {
+        return Err(TmxError::UnexpectedRoot(root.name));
+    }
+    Ok(root)
+}
+
+#[derive(Clone, Debug)]
+pub struct Property {
+    pub name:  String,
+    pub kind:  String,
+    pub value: String,
+}
+
+pub type Properties = Vec<Property>;
+
+pub fn property<'a>(properties: &'a [Property], name: &str) -> Option<&'a str> {
+    properties.iter()
+        .find(|prop| prop.name == name)
+        .map(|prop| prop.value.as_str())
+}
+
+fn read_properties(el: &Element) -> Properties {
+    let props = match el.child("properties") {
+        Some(props) => props,
+        None        => return Properties::new()
+    };
+
+    props.children_named("property")
+        .map(|prop| Property {
+            name:  prop.attr("name").unwrap_or("").to_string(),
+            kind:  prop.attr("type").unwrap_or("string").to_string(),
+            value: prop.attr("value")
+                .map(|v| v.to_string())
+                .unwrap_or_else(|| prop.text.clone()),
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug)]
+pub enum ObjectShape {
+    Rect,
+    Ellipse,
+    Point,
+    Polygon(Vec<(f32, f32)>),
+    Polyline(Vec<(f32, f32)>),
+    // the <text> element, kept verbatim
+    Text(Element),
+}
+
+// A file an element refers to: the path as written, relative to the file it
+// was read from, and the same path resolved against that file's directory.
+#[derive(Clone, Debug)]
+pub struct FileRef {
+    pub source: String,
+    pub path:   PathBuf,
+}
+
+impl FileRef {
+    fn new(source: &str, base_dir: &Path) -> FileRef {
+        FileRef { source: source.to_string(), path: base_dir.join(source) }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Object {
+    pub id:         u32,
+    pub name:       String,
+    pub kind:       String,
+    pub gid:        Option<u32>,
+    pub x:          f32,
+    pub y:          f32,
+    pub width:      f32,
+    pub height:     f32,
+    pub rotation:   f32,
+    pub visible:    bool,
+    // attributes the object doesn't set come from its template
+    pub template:   Option<FileRef>,
+    pub shape:      ObjectShape,
+    pub properties: Properties,
+}
+
+fn read_points(el: &Element) -> Result<Vec<(f32, f32)>, TmxError> {
+    let points: &str = el.attr("points").unwrap_or("");
+    points.split_whitespace()
+        .map(|pair| {
+            let mut coords = pair.split(',').map(|c| c.parse::<f32>());
+            match (coords.next(), coords.next()) {
+                (Some(Ok(x)), Some(Ok(y))) => Ok((x, y)),
+                _ => Err(TmxError::BadAttribute(
+                    el.name.clone(), "points", pair.to_string()
+                ))
+            }
+        })
+        .collect()
+}
+
+impl Object {
+    fn read(el: &Element, base_dir: &Path) -> Result<Object, TmxError> {
+        let shape =
+            if let Some(text) = el.child("text") {
+                ObjectShape::Text(text.clone())
+            }
+            else if let Some(poly) = el.child("polygon") {
+                ObjectShape::Polygon(read_points(poly)?)
+            }
+            else if let Some(poly) = el.child("polyline") {
+                ObjectShape::Polyline(read_points(poly)?)
+            }
+            else if el.child("ellipse").is_some() {
+                ObjectShape::Ellipse
+            }
+            else if el.child("point").is_some() {
+                ObjectShape::Point
+            }
+            else {
+                ObjectShape::Rect
+            };
+
+        Ok(Object {
+            id:         el.attr_or("id", 0)?,
+            name:       el.attr("name").unwrap_or("").to_string(),
+            kind:       el.attr("type").unwrap_or("").to_string(),
+            gid:        el.parse_attr("gid")?,
+            x:          el.attr_or("x", 0.0)?,
+            y:          el.attr_or("y", 0.0)?,
+            width:      el.attr_or("width", 0.0)?,
+            height:     el.attr_or("height", 0.0)?,
+            rotation:   el.attr_or("rotation", 0.0)?,
+            visible:    el.attr_or("visible", 1u32)? != 0,
+            template:   el.attr("template").map(|source| FileRef::new(source, base_dir)),
+            shape,
+            properties: read_properties(el),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ObjectGroup {
+    pub id:         Option<u32>,
+    pub name:       String,
+    pub draw_order: Option<String>,
+    pub display:    LayerDisplay,
+    pub properties: Properties,
+    pub objects:    Vec<Object>,
+}
+
+impl ObjectGroup {
+    fn read(el: &Element, base_dir: &Path) -> Result<ObjectGroup, TmxError> {
+        let objects = el.children_named("object")
+            .map(|obj| Object::read(obj, base_dir))
+            .collect::<Result<_, _>>()?;
+
+        Ok(ObjectGroup {
+            id:         el.parse_attr("id")?,
+            name:       el.attr("name").unwrap_or("").to_string(),
+            draw_order: el.attr("draworder").map(|s| s.to_string()),
+            display:    LayerDisplay::read(el)?,
+            properties: read_properties(el),
+            objects,
+        })
+    }
+}
+
+// Tiled keeps a placed tile's flips in the top bits of its gid
+const FLIPPED_HORIZONTALLY: u32 = 0x8000_0000;
+const FLIPPED_VERTICALLY:   u32 = 0x4000_0000;
+const FLIPPED_DIAGONALLY:   u32 = 0x2000_0000;
+const FLIP_BITS: u32 = FLIPPED_HORIZONTALLY | FLIPPED_VERTICALLY | FLIPPED_DIAGONALLY;
+
+// How a placed tile is mirrored. A diagonal flip swaps x and y and happens
+// before the other two.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Flip {
+    pub horizontal: bool,
+    pub vertical:   bool,
+    pub diagonal:   bool,
+}
+
+impl Flip {
+    // Splits a gid as written by Tiled into the tile's gid and its flips.
+    pub fn split(raw: u32) -> (u32, Flip) {
+        let flip = Flip {
+            horizontal: raw & FLIPPED_HORIZONTALLY != 0,
+            vertical:   raw & FLIPPED_VERTICALLY   != 0,
+            diagonal:   raw & FLIPPED_DIAGONALLY   != 0,
+        };
+        (raw & !FLIP_BITS, flip)
+    }
+
+    // `gid` with these flips, as Tiled writes it.
+    pub fn join(self, gid: u32) -> u32 {
+        let mut raw = gid & !FLIP_BITS;
+        if self.horizontal { raw |= FLIPPED_HORIZONTALLY; }
+        if self.vertical   { raw |= FLIPPED_VERTICALLY; }
+        if self.diagonal   { raw |= FLIPPED_DIAGONALLY; }
+        raw
+    }
+}
+
+// A rectangle of gids in Tiled's y-down tile coordinates. Finite maps hold a
+// single chunk covering the whole layer.
+#[derive(Clone, Debug)]
+pub struct Chunk {
+    pub x:      i32,
+    pub y:      i32,
+    pub width:  u32,
+    pub height: u32,
+    // without flip bits, which are in `flips`
+    pub gids:   Vec<u32>,
+    pub flips:  Vec<Flip>,
+}
+
+impl Chunk {
+    fn new(x: i32, y: i32, width: u32, height: u32, raw_gids: Vec<u32>) -> Chunk {
+        let (gids, flips) = raw_gids.into_iter().map(Flip::split).unzip();
+        Chunk { x, y, width, height, gids, flips }
+    }
+
+    pub fn gid_at(&self, col: u32, row: u32) -> u32 {
+        self.gids[(row * self.width + col) as usize]
+    }
+
+    pub fn flip_at(&self, col: u32, row: u32) -> Flip {
+        self.flips[(row * self.width + col) as usize]
+    }
+}
+
+// How a layer of any kind is drawn.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LayerDisplay {
+    pub visible: bool,
+    pub opacity: f32,
+    // in pixels
+    pub offset:  (f32, f32),
+}
+
+impl Default for LayerDisplay {
+    fn default() -> LayerDisplay {
+        LayerDisplay { visible: true, opacity: 1.0, offset: (0.0, 0.0) }
+    }
+}
+
+impl LayerDisplay {
+    fn read(el: &Element) -> Result<LayerDisplay, TmxError> {
+        Ok(LayerDisplay {
+            visible: el.attr_or("visible", 1u32)? != 0,
+            opacity: el.attr_or("opacity", 1.0)?,
+            offset:  (el.attr_or("offsetx", 0.0)?, el.attr_or("offsety", 0.0)?),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TileLayer {
+    pub id:         Option<u32>,
+    pub name:       String,
+    pub width:      u32,
+    pub height:     u32,
+    pub display:    LayerDisplay,
+    pub properties: Properties,
+    pub chunks:     Vec<Chunk>,
+}
+
+fn decode_gids(
+    el:          &Element,
+    encoding:    Option<&str>,
+    compression: Option<&str>,
+    count:       usize)
+    -> Result<Vec<u32>, TmxError>
+{
+    let gids: Vec<u32> = match encoding {
+        None => {
+            el.children_named("tile")
+                .map(|tile| tile.attr_or("gid", 0))
+                .collect::<Result<_, _>>()?
+        }
+
+        Some("csv") => {
+            el.text.split(',')
+                .map(|s| s.trim())
+                .filter(|s| !s.is_empty())
+                .map(|s| s.parse::<u32>()
+                    .map_err(|_| TmxError::BadData(s.to_string())))
+                .collect::<Result<_, _>>()?
+        }
+
+        Some("base64") => {
+            let text: String = el.text.chars()
+                .filter(|c| !c.is_whitespace())
+                .collect();
+            let raw = base64::decode(&text)
+                .map_err(|e| TmxError::BadData(e.to_string()))?;
+
+            let mut bytes = Vec::new();
+            match compression {
+                None => bytes = raw,
+                Some("zlib") => {
+                    flate2::read::ZlibDecoder::new(&raw[..])
+                        .read_to_end(&mut bytes)?;
+                }
+                Some("gzip") => {
+                    flate2::read::GzDecoder::new(&raw[..])
+                        .read_to_end(&mut bytes)?;
+                }
+                Some(other) => {
+                    return Err(TmxError::UnsupportedCompression(other.to_string()));
+                }
+            }
+
+            bytes.chunks_exact(4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect()
+        }
+
+        Some(other) => {
+            return Err(TmxError::UnsupportedEncoding(other.to_string()));
+        }
+    };
+
+    if gids.len() != count {
+        return Err(TmxError::BadData(format!(
+            "expected {} tiles, found {}", count, gids.len()
+        )));
+    }
+
+    Ok(gids)
+}
+
+impl TileLayer {
+    fn read(el: &Element) -> Result<TileLayer, TmxError> {
+        let width:  u32 = el.req_attr("width")?;
+        let height: u32 = el.req_attr("height")?;
+
+        let mut chunks = Vec::new();
+        if let Some(data) = el.child("data") {
+            let encoding    = data.attr("encoding");
+            let compression = data.attr("compression");
+
+            if data.child("chunk").is_some() {
+                for chunk in data.children_named("chunk") {
+                    let chunk_width:  u32 = chunk.req_attr("width")?;
+                    let chunk_height: u32 = chunk.req_attr("height")?;
+                    let count = (chunk_width * chunk_height) as usize;
+                    chunks.push(Chunk::new(
+                        chunk.req_attr("x")?,
+                        chunk.req_attr("y")?,
+                        chunk_width,
+                        chunk_height,
+                        decode_gids(chunk, encoding, compression, count)?,
+                    ));
+                }
+            }
+            else {
+                let count = (width * height) as usize;
+                let gids = decode_gids(data, encoding, compression, count)?;
+                chunks.push(Chunk::new(0, 0, width, height, gids));
+            }
+        }
+
+        Ok(TileLayer {
+            id:         el.parse_attr("id")?,
+            name:       el.attr("name").unwrap_or("").to_string(),
+            width,
+            height,
+            display:    LayerDisplay::read(el)?,
+            properties: read_properties(el),
+            chunks,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ImageLayer {
+    pub id:         Option<u32>,
+    pub name:       String,
+    pub display:    LayerDisplay,
+    pub properties: Properties,
+    pub image:      Option<Image>,
+}
+
+impl ImageLayer {
+    fn read(el: &Element, base_dir: &Path) -> Result<ImageLayer, TmxError> {
+        Ok(ImageLayer {
+            id:         el.parse_attr("id")?,
+            name:       el.attr("name").unwrap_or("").to_string(),
+            display:    LayerDisplay::read(el)?,
+            properties: read_properties(el),
+            image:      el.child("image")
+                .map(|im| Image::read(im, base_dir))
+                .transpose()?,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct GroupLayer {
+    pub id:         Option<u32>,
+    pub name:       String,
+    pub display:    LayerDisplay,
+    pub properties: Properties,
+    pub layers:     Vec<Layer>,
+}
+
+impl GroupLayer {
+    fn read(el: &Element, base_dir: &Path) -> Result<GroupLayer, TmxError> {
+        Ok(GroupLayer {
+            id:         el.parse_attr("id")?,
+            name:       el.attr("name").unwrap_or("").to_string(),
+            display:    LayerDisplay::read(el)?,
+            properties: read_properties(el),
+            layers:     Layer::read_all(el, base_dir)?,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Layer {
+    Tiles(TileLayer),
+    Objects(ObjectGroup),
+    Image(ImageLayer),
+    Group(GroupLayer),
+}
+
+impl Layer {
+    // The layers among `el`'s children, in order.
+    fn read_all(el: &Element, base_dir: &Path) -> Result<Vec<Layer>, TmxError> {
+        let mut layers = Vec::new();
+        for child in &el.children {
+            let layer = match child.name.as_str() {
+                "layer"       => Layer::Tiles(TileLayer::read(child)?),
+                "objectgroup" => Layer::Objects(ObjectGroup::read(child, base_dir)?),
+                "imagelayer"  => Layer::Image(ImageLayer::read(child, base_dir)?),
+                "group"       => Layer::Group(GroupLayer::read(child, base_dir)?),
+                _             => continue,
+            };
+            layers.push(layer);
+        }
+        Ok(layers)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Image {
+    pub source: String,
+    pub path:   PathBuf,
+    pub width:  u32,
+    pub height: u32,
+}
+
+impl Image {
+    fn read(el: &Element, base_dir: &Path) -> Result<Image, TmxError> {
+        let source: String = el.req_attr("source")?;
+        Ok(Image {
+            path:   base_dir.join(&source),
+            source,
+            width:  el.attr_or("width", 0)?,
+            height: el.attr_or("height", 0)?,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TileDef {
+    pub id:         u32,
+    pub kind:       Option<String>,
+    // corner terrains as written by Tiled, e.g. "0,0,,1"
+    pub terrain:    Option<String>,
+    pub properties: Properties,
+    pub objects:    Option<ObjectGroup>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Tileset {
+    pub name:        String,
+    pub tile_width:  u32,
+    pub tile_height: u32,
+    pub tile_count:  u32,
+    pub columns:     u32,
+    pub spacing:     u32,
+    pub margin:      u32,
+    pub image:       Option<Image>,
+    pub properties:  Properties,
+    pub tiles:       Vec<TileDef>,
+    // children not interpreted here, such as terrain types and Wang sets,
+    // kept verbatim
+    pub extra:       Vec<Element>,
+}
+
+impl Tileset {
+    pub fn load(path: impl AsRef<Path>) -> Result<Tileset, TmxError> {
+        let path = path.as_ref();
+        let root = load_root(path, "tileset")?;
+        Tileset::read(&root, path.parent().unwrap_or(Path::new("")))
+    }
+
+    fn read(el: &Element, base_dir: &Path) -> Result<Tileset, TmxError> {
+        let image = el.child("image")
+            .map(|im| Image::read(im, base_dir))
+            .transpose()?;
+
+        let tiles = el.children_named("tile")
+            .map(|tile| -> Result<TileDef, TmxError> {
+                Ok(TileDef {
+                    id:         tile.req_attr("id")?,
+                    kind:       tile.attr("type").map(|s| s.to_string()),
+                    terrain:    tile.attr("terrain").map(|s| s.to_string()),
+                    properties: read_properties(tile),
+                    objects:    tile.child("objectgroup")
+                        .map(|group| ObjectGroup::read(group, base_dir))
+                        .transpose()?,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        let extra = el.children.iter()
+            .filter(|child| !matches!(child.name.as_str(), "image" | "tile" | "properties"))
+            .cloned()
+            .collect();
+
+        Ok(Tileset {
+            name:        el.attr("name").unwrap_or("").to_string(),
+            tile_width:  el.req_attr("tilewidth")?,
+            tile_height: el.req_attr("tileheight")?,
+            tile_count:  el.attr_or("tilecount", 0)?,
+            columns:     el.attr_or("columns", 0)?,
+            spacing:     el.attr_or("spacing", 0)?,
+            margin:      el.attr_or("margin", 0)?,
+            image,
+            properties:  read_properties(el),
+            tiles,
+            extra,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TilesetRef {
+    pub first_gid: u32,
+    pub source:    Option<String>,
+    pub path:      Option<PathBuf>,
+    pub tileset:   Tileset,
+}
+
+#[derive(Clone, Debug)]
+pub struct Document {
+    pub version:        String,
+    pub tiled_version:  Option<String>,
+    pub orientation:    String,
+    pub render_order:   String,
+    pub width:          u32,
+    pub height:         u32,
+    pub tile_width:     u32,
+    pub tile_height:    u32,
+    pub infinite:       bool,
+    pub next_layer_id:  u32,
+    pub next_object_id: u32,
+    // as written by Tiled, e.g. "#ff204060"
+    pub background:     Option<String>,
+    pub properties:     Properties,
+    pub tilesets:       Vec<TilesetRef>,
+    pub layers:         Vec<Layer>,
+}
+
+impl Document {
+    pub fn load(path: impl AsRef<Path>) -> Result<Document, TmxError> {
+        let path = path.as_ref();
+        let root = load_root(path, "map")?;
+        let base_dir = path.parent().unwrap_or(Path::new(""));
+
+        let mut tilesets = Vec::new();
+        for child in root.children_named("tileset") {
+            let first_gid = child.req_attr("firstgid")?;
+            let tileset_ref = match child.attr("source") {
+                Some(source) => {
+                    let path = base_dir.join(source);
+                    TilesetRef {
+                        first_gid,
+                        source:  Some(source.to_string()),
+                        tileset: Tileset::load(&path)?,
+                        path:    Some(path),
+                    }
+                }
+
+                None => TilesetRef {
+                    first_gid,
+                    source:  None,
+                    path:    None,
+                    tileset: Tileset::read(child, base_dir)?,
+                }
+            };
+            tilesets.push(tileset_ref);
+        }
+
+        let layers = Layer::read_all(&root, base_dir)?;
+
+        Ok(Document {
+            version:        root.attr("version").unwrap_or("1.2").to_string(),
+            tiled_version:  root.attr("tiledversion").map(|s| s.to_string()),
+            orientation:    root.attr("orientation").unwrap_or("orthogonal").to_string(),
+            render_order:   root.attr("renderorder").unwrap_or("right-down").to_string(),
+            width:          root.req_attr("width")?,
+            height:         root.req_attr("height")?,
+            tile_width:     root.req_attr("tilewidth")?,
+            tile_height:    root.req_attr("tileheight")?,
+            infinite:       root.attr_or("infinite", 0u32)? != 0,
+            next_layer_id:  root.attr_or("nextlayerid", 1)?,
+            next_object_id: root.attr_or("nextobjectid", 1)?,
+            background:     root.attr("backgroundcolor").map(|s| s.to_string()),
+            properties:     read_properties(&root),
+            tilesets,
+            layers,
+        })
+    }
+
+    pub fn tile_layer(&self, name: &str) -> Option<&TileLayer> {
+        self.layers.iter().find_map(|layer| match layer {
+            Layer::Tiles(tiles) if tiles.name == name => Some(tiles),
+            _ => None
+        })
+    }
+
+    pub fn tile_layer_mut(&mut self, name: &str) -> Option<&mut TileLayer> {
+        self.layers.iter_mut().find_map(|layer| match layer {
+            Layer::Tiles(tiles) if tiles.name == name => Some(tiles),
+            _ => None
+        })
+    }
+
+    pub fn object_group(&self, name: &str) -> Option<&ObjectGroup> {
+        self.layers.iter().find_map(|layer| match layer {
+            Layer::Objects(group) if group.name == name => Some(group),
+            _ => None
+        })
+    }
+
+    // Tiled stores rows top-down; maps flip them so that y increases
+    // upwards. Finite maps keep their bottom row at y = 0, infinite maps flip
+    // about the origin.
+    pub fn flip_base(&self) -> i32 {
+        if self.infinite { -1 } else { self.height as i32 - 1 }
+    }
+}
+
+// writing
+
+fn properties_element(properties: &[Property]) -> Option<Element> {
+    if properties.is_empty() {
+        return None;
+    }
+
+    let props = properties.iter()
+        .map(|prop| {
+            let el = Element::new("property").with_attr("name", &prop.name);
+            let el = if prop.kind == "string" { el }
+                     else { el.with_attr("type", &prop.kind) };
+            el.with_attr("value", &prop.value)
+        })
+        .fold(Element::new("properties"), Element::with_child);
+
+    Some(props)
+}
+
+fn push_properties(el: &mut Element, properties: &[Property]) {
+    if let Some(props) = properties_element(properties) {
+        el.children.push(props);
+    }
+}
+
+impl LayerDisplay {
+    fn write(&self, mut el: Element) -> Element {
+        if !self.visible { el = el.with_attr("visible", 0); }
+        if self.opacity != 1.0 { el = el.with_attr("opacity", self.opacity); }
+        let (x, y) = self.offset;
+        if x != 0.0 { el = el.with_attr("offsetx", x); }
+        if y != 0.0 { el = el.with_attr("offsety", y); }
+        el
+    }
+}
+
+// Removes `.` and resolves `..` without touching the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for part in path.components() {
+        match part {
+            Component::CurDir => { }
+            Component::ParentDir => match out.components().next_back() {
+                Some(Component::Normal(_)) => { out.pop(); }
+                _                          => out.push(part),
+            },
+            _ => out.push(part),
+        }
+    }
+    out
+}
+
+// `target` relative to the directory `dir`, with '/' separators as Tiled
+// writes them. Relative paths are taken to start at the working directory.
+fn relative_path(target: &Path, dir: &Path) -> String {
+    let cwd = std::env::current_dir().unwrap_or_default();
+    let target = normalize(&cwd.join(target));
+    let dir = normalize(&cwd.join(dir));
+
+    let target_parts: Vec<Component> = target.components().collect();
+    let dir_parts: Vec<Component> = dir.components().collect();
+    let common = target_parts.iter().zip(&dir_parts)
+        .take_while(|(a, b)| a == b)
+        .count();
+    // e.g. on another drive
+    if common == 0 {
+        return target.to_string_lossy().into_owned();
+    }
+
+    let ups = std::iter::repeat_n("..".to_string(), dir_parts.len() - common);
+    let downs = target_parts[common..].iter()
+        .map(|part| part.as_os_str().to_string_lossy().into_owned());
+    ups.chain(downs).collect::<Vec<_>>().join("/")
+}
+
+fn points_attr(points: &[(f32, f32)]) -> String {
+    points.iter()
+        .map(|(x, y)| format!("{},{}", x, y))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl Object {
+    fn to_element(&self, dir: &Path) -> Element {
+        let mut el = Element::new("object").with_attr("id", self.id);
+        if !self.name.is_empty() { el = el.with_attr("name", &self.name); }
+        if !self.kind.is_empty() { el = el.with_attr("type", &self.kind); }
+        if let Some(gid) = self.gid { el = el.with_attr("gid", gid); }
+        el = el.with_attr("x", self.x).with_attr("y", self.y);
+        if self.width  != 0.0 { el = el.with_attr("width",  self.width); }
+        if self.height != 0.0 { el = el.with_attr("height", self.height); }
+        if self.rotation != 0.0 { el = el.with_attr("rotation", self.rotation); }
+        if !self.visible { el = el.with_attr("visible", 0); }
+        if let Some(template) = &self.template {
+            el = el.with_attr("template", relative_path(&template.path, dir));
+        }
+
+        push_properties(&mut el, &self.properties);
+
+        match &self.shape {
+            ObjectShape::Rect    => el,
+            ObjectShape::Ellipse => el.with_child(Element::new("ellipse")),
+            ObjectShape::Point   => el.with_child(Element::new("point")),
+            ObjectShape::Polygon(points) => el.with_child(
+                Element::new("polygon").with_attr("points", points_attr(points))
+            ),
+            ObjectShape::Polyline(points) => el.with_child(
+                Element::new("polyline").with_attr("points", points_attr(points))
+            ),
+            ObjectShape::Text(text) => el.with_child(text.clone()),
+        }
+    }
+}
+
+impl ObjectGroup {
+    fn to_element(&self, dir: &Path) -> Element {
+        let mut el = Element::new("objectgroup");
+        if let Some(order) = &self.draw_order { el = el.with_attr("draworder", order); }
+        if let Some(id) = self.id { el = el.with_attr("id", id); }
+        if !self.name.is_empty() { el = el.with_attr("name", &self.name); }
+        el = self.display.write(el);
+
+        push_properties(&mut el, &self.properties);
+        self.objects.iter()
+            .map(|obj| obj.to_element(dir))
+            .fold(el, Element::with_child)
+    }
+}
+
+impl Chunk {
+    fn csv(&self) -> String {
+        let raw: Vec<u32> = self.gids.iter().zip(&self.flips)
+            .map(|(&gid, flip)| flip.join(gid))
+            .collect();
+        let rows: Vec<String> = raw
+            .chunks(self.width.max(1) as usize)
+            .map(|row| row.iter()
+                .map(|gid| gid.to_string())
+                .collect::<Vec<_>>()
+                .join(","))
+            .collect();
+        format!("\n{}\n", rows.join(",\n"))
+    }
+}
+
+impl TileLayer {
+    fn to_element(&self, infinite: bool) -> Element {
+        let mut el = Element::new("layer");
+        if let Some(id) = self.id { el = el.with_attr("id", id); }
+        el = el
+            .with_attr("name",   &self.name)
+            .with_attr("width",  self.width)
+            .with_attr("height", self.height);
+        el = self.display.write(el);
+
+        push_properties(&mut el, &self.properties);
+
+        let mut data = Element::new("data").with_attr("encoding", "csv");
+        if infinite {
+            for chunk in &self.chunks {
+                let mut chunk_el = Element::new("chunk")
+                    .with_attr("x",      chunk.x)
+                    .with_attr("y",      chunk.y)
+                    .with_attr("width",  chunk.width)
+                    .with_attr("height", chunk.height);
+                chunk_el.text = chunk.csv();
+                data.children.push(chunk_el);
+            }
+        }
+        else if let Some(chunk) = self.chunks.first() {
+            data.text = chunk.csv();
+        }
+
+        el.with_child(data)
+    }
+}
+
+impl Image {
+    fn to_element(&self, dir: &Path) -> Element {
+        let source = relative_path(&self.path, dir);
+        let mut el = Element::new("image").with_attr("source", source);
+        if self.width  != 0 { el = el.with_attr("width",  self.width); }
+        if self.height != 0 { el = el.with_attr("height", self.height); }
+        el
+    }
+}
+
+impl ImageLayer {
+    fn to_element(&self, dir: &Path) -> Element {
+        let mut el = Element::new("imagelayer");
+        if let Some(id) = self.id { el = el.with_attr("id", id); }
+        el = self.display.write(el.with_attr("name", &self.name));
+
+        push_properties(&mut el, &self.properties);
+        if let Some(image) = &self.image {
+            el.children.push(image.to_element(dir));
+        }
+        el
+    }
+}
+
+impl GroupLayer {
+    fn to_element(&self, infinite: bool, dir: &Path) -> Element {
+        let mut el = Element::new("group");
+        if let Some(id) = self.id { el = el.with_attr("id", id); }
+        el = self.display.write(el.with_attr("name", &self.name));
+
+        push_properties(&mut el, &self.properties);
+        self.layers.iter()
+            .map(|layer| layer.to_element(infinite, dir))
+            .fold(el, Element::with_child)
+    }
+}
+
+impl Layer {
+    fn to_element(&self, infinite: bool, dir: &Path) -> Element {
+        match self {
+            Layer::Tiles(tiles)   => tiles.to_element(infinite),
+            Layer::Objects(group) => group.to_element(dir),
+            Layer::Image(image)   => image.to_element(dir),
+            Layer::Group(group)   => group.to_element(infinite, dir),
+        }
+    }
+}
+
+impl Tileset {
+    // `dir` is the directory of the file the tileset is written into.
+    fn to_element(&self, first_gid: u32, dir: &Path) -> Element {
+        let mut el = Element::new("tileset")
+            .with_attr("firstgid",   first_gid)
+            .with_attr("name",       &self.name)
+            .with_attr("tilewidth",  self.tile_width)
+            .with_attr("tileheight", self.tile_height);
+        if self.spacing != 0 { el = el.with_attr("spacing", self.spacing); }
+        if self.margin  != 0 { el = el.with_attr("margin",  self.margin); }
+        el = el
+            .with_attr("tilecount", self.tile_count)
+            .with_attr("columns",   self.columns);
+
+        push_properties(&mut el, &self.properties);
+
+        if let Some(image) = &self.image {
+            el.children.push(image.to_element(dir));
+        }
+
+        let (before_tiles, after_tiles): (Vec<&Element>, Vec<&Element>) = self.extra.iter()
+            .partition(|extra| extra.name == "tileoffset" || extra.name == "grid"
+                || extra.name == "terraintypes");
+        el.children.extend(before_tiles.into_iter().cloned());
+
+        for tile in &self.tiles {
+            let mut tile_el = Element::new("tile").with_attr("id", tile.id);
+            if let Some(kind) = &tile.kind { tile_el = tile_el.with_attr("type", kind); }
+            if let Some(terrain) = &tile.terrain { tile_el = tile_el.with_attr("terrain", terrain); }
+            push_properties(&mut tile_el, &tile.properties);
+            if let Some(objects) = &tile.objects {
+                tile_el.children.push(objects.to_element(dir));
+            }
+            el.children.push(tile_el);
+        }
+
+        el.children.extend(after_tiles.into_iter().cloned());
+        el
+    }
+}
+
+impl TilesetRef {
+    fn to_element(&self, dir: &Path) -> Element {
+        match (&self.source, &self.path) {
+            (Some(_), Some(path)) => Element::new("tileset")
+                .with_attr("firstgid", self.first_gid)
+                .with_attr("source",   relative_path(path, dir)),
+            // e.g. from a cooked map, which doesn't know where the file is
+            (Some(source), None) => Element::new("tileset")
+                .with_attr("firstgid", self.first_gid)
+                .with_attr("source",   source),
+            (None, _) => self.tileset.to_element(self.first_gid, dir),
+        }
+    }
+}
+
+impl Document {
+    // Paths to other files are written relative to `dir`, the directory the
+    // document is going to be saved in.
+    pub fn to_element(&self, dir: &Path) -> Element {
+        let mut el = Element::new("map").with_attr("version", &self.version);
+        if let Some(tiled_version) = &self.tiled_version {
+            el = el.with_attr("tiledversion", tiled_version);
+        }
+        el = el
+            .with_attr("orientation",  &self.orientation)
+            .with_attr("renderorder",  &self.render_order)
+            .with_attr("width",        self.width)
+            .with_attr("height",       self.height)
+            .with_attr("tilewidth",    self.tile_width)
+            .with_attr("tileheight",   self.tile_height)
+            .with_attr("infinite",     if self.infinite { 1 } else { 0 })
+            .with_attr("nextlayerid",  self.next_layer_id)
+            .with_attr("nextobjectid", self.next_object_id);
+        if let Some(background) = &self.background {
+            el = el.with_attr("backgroundcolor", background);
+        }
+
+        push_properties(&mut el, &self.properties);
+
+        for tileset in &self.tilesets {
+            el.children.push(tileset.to_element(dir));
+        }
+
+        for layer in &self.layers {
+            el.children.push(layer.to_element(self.infinite, dir));
+        }
+
+        el
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), TmxError> {
+        let path = path.as_ref();
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+        self.to_element(dir).write(&mut out, 0)?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::fs};
+
+    fn parse(xml: &str) -> Element {
+        Element::parse(xml.as_bytes()).unwrap()
+    }
+
+    // An empty directory of its own for each test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("tmx-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn written(doc: &Document, dir: &Path) -> String {
+        let mut out = Vec::new();
+        doc.to_element(dir).write(&mut out, 0).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn same_file(a: &Path, b: &Path) -> bool {
+        let cwd = std::env::current_dir().unwrap();
+        normalize(&cwd.join(a)) == normalize(&cwd.join(b))
+    }
+
+    #[test]
+    fn loads_test_map() {
+        let doc = Document::load("test.tmx").unwrap();
+        assert_eq!((doc.width, doc.height), (80, 15));
+        assert_eq!((doc.tile_width, doc.tile_height), (16, 16));
+        assert!(!doc.infinite);
+
+        assert_eq!(doc.tilesets.len(), 1);
+        let ts_ref = &doc.tilesets[0];
+        assert_eq!(ts_ref.first_gid, 1);
+        assert_eq!(ts_ref.source.as_deref(), Some("tiles.tsx"));
+        assert!(ts_ref.tileset.image.is_some());
+
+        let main = doc.tile_layer("main").unwrap();
+        assert_eq!(main.display, LayerDisplay::default());
+        assert_eq!(main.chunks.len(), 1);
+        let chunk = &main.chunks[0];
+        assert_eq!((chunk.width, chunk.height), (80, 15));
+        assert_eq!(chunk.gids.len(), 80 * 15);
+        assert_eq!(chunk.flips.len(), 80 * 15);
+        // the bottom row is solid
+        assert!((0..80).all(|col| chunk.gid_at(col, 14) != 0));
+        assert!((0..80).all(|col| chunk.gid_at(col, 0) == 0));
+
+        let entities = doc.object_group("entities").unwrap();
+        let start = entities.objects.iter().find(|obj| obj.name == "start").unwrap();
+        assert!(matches!(start.shape, ObjectShape::Point));
+        assert_eq!((start.x, start.y), (32.0, 208.0));
+    }
+
+    #[test]
+    fn masks_flip_bits() {
+        let el = parse(r#"
+            <layer id="1" name="flipped" width="4" height="1">
+             <data encoding="csv">2147483650,1073741827,536870916,3758096389</data>
+            </layer>"#);
+        let layer = TileLayer::read(&el).unwrap();
+        let chunk = &layer.chunks[0];
+
+        assert_eq!(chunk.gids, vec![2, 3, 4, 5]);
+        let flip = |horizontal, vertical, diagonal| Flip { horizontal, vertical, diagonal };
+        assert_eq!(chunk.flips, vec![
+            flip(true,  false, false),
+            flip(false, true,  false),
+            flip(false, false, true),
+            flip(true,  true,  true),
+        ]);
+
+        for (i, raw) in [2147483650u32, 1073741827, 536870916, 3758096389].iter().enumerate() {
+            assert_eq!(chunk.flips[i].join(chunk.gids[i]), *raw);
+        }
+    }
+
+    #[test]
+    fn reads_layer_display() {
+        let el = parse(r#"
+            <objectgroup id="2" name="hidden" visible="0" opacity="0.5" offsetx="3" offsety="-4"/>"#);
+        let group = ObjectGroup::read(&el, Path::new("")).unwrap();
+        assert_eq!(group.display, LayerDisplay {
+            visible: false,
+            opacity: 0.5,
+            offset:  (3.0, -4.0),
+        });
+
+        let bad = parse(r#"<layer name="x" width="1" height="1" opacity="half"/>"#);
+        assert!(TileLayer::read(&bad).is_err());
+    }
+
+    #[test]
+    fn reads_image_and_group_layers() {
+        let el = parse(r#"
+            <map>
+             <imagelayer id="1" name="sky" offsetx="8">
+              <image source="sky.png" width="64" height="32"/>
+             </imagelayer>
+             <group id="2" name="decor" opacity="0.75">
+              <layer id="3" name="vines" width="1" height="1">
+               <data encoding="csv">0</data>
+              </layer>
+              <group id="4" name="empty"/>
+             </group>
+            </map>"#);
+        let layers = Layer::read_all(&el, Path::new("maps")).unwrap();
+        assert_eq!(layers.len(), 2);
+
+        match &layers[0] {
+            Layer::Image(layer) => {
+                assert_eq!(layer.name, "sky");
+                assert_eq!(layer.display.offset, (8.0, 0.0));
+                let image = layer.image.as_ref().unwrap();
+                assert_eq!(image.path, Path::new("maps").join("sky.png"));
+                assert_eq!((image.width, image.height), (64, 32));
+            }
+            other => panic!("expected an image layer, got {:?}", other),
+        }
+
+        match &layers[1] {
+            Layer::Group(group) => {
+                assert_eq!(group.display.opacity, 0.75);
+                assert_eq!(group.layers.len(), 2);
+                assert!(match &group.layers[0] { Layer::Tiles(l) => l.name == "vines", _ => false });
+                assert!(match &group.layers[1] { Layer::Group(g) => g.layers.is_empty(), _ => false });
+            }
+            other => panic!("expected a group, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn round_trips_test_map() {
+        let doc = Document::load("test.tmx").unwrap();
+        let root = temp_dir("test-map");
+        let dir = root.join("nested");
+        fs::create_dir_all(&dir).unwrap();
+        let out = dir.join("test.tmx");
+        doc.save(&out).unwrap();
+
+        let reloaded = Document::load(&out).unwrap();
+        assert_eq!(reloaded.tiled_version.as_deref(), Some("1.3.1"));
+        let ts_path = reloaded.tilesets[0].path.as_ref().unwrap();
+        assert!(same_file(ts_path, Path::new("tiles.tsx")), "{}", ts_path.display());
+        assert_eq!(written(&reloaded, &dir), written(&doc, &dir));
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn round_trips_everything_kept() {
+        let root = temp_dir("everything");
+        let src_dir = root.join("src");
+        fs::create_dir_all(&src_dir).unwrap();
+        let src = src_dir.join("map.tmx");
+        fs::write(&src, r##"<?xml version="1.0" encoding="UTF-8"?>
+<map version="1.4" tiledversion="1.4.3" orientation="orthogonal" renderorder="right-up" width="2" height="1" tilewidth="16" tileheight="16" infinite="0" backgroundcolor="#ff204060" nextlayerid="7" nextobjectid="3">
+ <tileset firstgid="1" name="inline" tilewidth="16" tileheight="16" tilecount="4" columns="2">
+  <image source="tiles.png" width="32" height="32"/>
+ </tileset>
+ <imagelayer id="1" name="sky" opacity="0.5">
+  <image source="../art/sky.png" width="64" height="32"/>
+ </imagelayer>
+ <group id="2" name="decor" visible="0" offsetx="4" offsety="-2">
+  <layer id="3" name="main" width="2" height="1" opacity="0.25">
+   <data encoding="csv">2147483650,3</data>
+  </layer>
+ </group>
+ <objectgroup id="4" name="entities" offsetx="1">
+  <object id="1" template="enemy.tx" x="8" y="8" visible="0"/>
+  <object id="2" name="sign" x="0" y="0" width="32" height="16">
+   <text wrap="1" color="#ff0000">Hello</text>
+  </object>
+ </objectgroup>
+</map>
+"##).unwrap();
+
+        let doc = Document::load(&src).unwrap();
+        let out_dir = root.join("out").join("deep");
+        fs::create_dir_all(&out_dir).unwrap();
+        let out = out_dir.join("map.tmx");
+        doc.save(&out).unwrap();
+
+        let text = fs::read_to_string(&out).unwrap();
+        assert!(text.contains(r#"source="../../src/tiles.png""#), "{}", text);
+        assert!(text.contains(r#"source="../../art/sky.png""#), "{}", text);
+        assert!(text.contains(r#"template="../../src/enemy.tx""#), "{}", text);
+
+        let reloaded = Document::load(&out).unwrap();
+        assert_eq!(reloaded.version, "1.4");
+        assert_eq!(reloaded.background.as_deref(), Some("#ff204060"));
+
+        match &reloaded.layers[0] {
+            Layer::Image(layer) => {
+                let image = layer.image.as_ref().unwrap();
+                assert!(same_file(&image.path, &root.join("art").join("sky.png")));
+            }
+            other => panic!("expected an image layer, got {:?}", other),
+        }
+
+        match &reloaded.layers[2] {
+            Layer::Objects(group) => {
+                let enemy = &group.objects[0];
+                assert!(!enemy.visible);
+                let template = enemy.template.as_ref().unwrap();
+                assert!(same_file(&template.path, &src_dir.join("enemy.tx")));
+                assert!(match &group.objects[1].shape {
+                    ObjectShape::Text(text) => text.text == "Hello" && text.attr("wrap") == Some("1"),
+                    _ => false,
+                });
+            }
+            other => panic!("expected an object group, got {:?}", other),
+        }
+
+        assert_eq!(written(&reloaded, &out_dir), written(&doc, &out_dir));
+        let _ = fs::remove_dir_all(&root);
+    }
+}
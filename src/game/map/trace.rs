@@ -0,0 +1,316 @@
+
+// Traces tile colliders from the alpha channel of the tileset image, for
+// tilesets with the "auto_collider" property set. Opaque pixels are outlined
+// with marching squares, the outline simplified to a few vertices, and its
+// convex hull taken as the collider.
+//
+// Optional tileset properties:
+//     auto_collider_alpha      alpha at or above which a pixel is solid
+//                              (0-255, default 128)
+//     auto_collider_tolerance  how far in pixels the simplified outline may
+//                              stray from the traced one (default 1)
+
+use {
+    super::tmx,
+    crate::alg::V2,
+    std::collections::HashMap,
+};
+
+pub struct Tracing {
+    pub threshold: u8,
+    pub tolerance: f32,
+}
+
+impl Tracing {
+    pub fn from_properties(props: &[tmx::Property]) -> Option<Tracing> {
+        if tmx::property(props, "auto_collider") != Some("true") {
+            return None;
+        }
+
+        let threshold = tmx::property(props, "auto_collider_alpha")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(128);
+        let tolerance = tmx::property(props, "auto_collider_tolerance")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(1.0);
+        Some(Tracing { threshold, tolerance })
+    }
+
+    // Collider for one RGBA8 tile image, in the tile's pixel space (y-down).
+    // None if the tile has no opaque pixels. Only the largest opaque region
+    // is kept, and since colliders must be convex it's replaced by its
+    // convex hull, which also fills any holes.
+    pub fn trace(&self, pixels: &[u8], width: i32, height: i32) -> Option<Vec<V2>> {
+        let solid = |x: i32, y: i32| {
+            x >= 0 && y >= 0 && x < width && y < height
+                && pixels[((y * width + x) * 4 + 3) as usize] >= self.threshold
+        };
+
+        let loops = contours(width, height, solid);
+        let outer = loops.into_iter()
+            .map(|keys| {
+                let verts = snap(keys.iter().map(|&k| to_pixels(k)).collect(), width, height);
+                (area(&verts).abs(), verts)
+            })
+            .filter(|(area, verts)| verts.len() >= 3 && *area > 0.0)
+            .max_by(|a, b| a.0.partial_cmp(&b.0).unwrap())?
+            .1;
+
+        let verts = convex_hull(&simplify(&outer, self.tolerance));
+        if verts.len() >= 3 { Some(verts) }
+        else                { None }
+    }
+}
+
+// Contour points are edge midpoints between samples, which sit at pixel
+// centres. Keys are in half-pixel units relative to the centre of pixel
+// (0, 0).
+type Key = (i32, i32);
+
+fn to_pixels(k: Key) -> V2 {
+    V2::new(k.0 as f32 * 0.5 + 0.5, k.1 as f32 * 0.5 + 0.5)
+}
+
+// Closed contours around the solid samples, as loops of edge midpoints.
+// Diagonal-only contact counts as separate regions.
+fn contours(width: i32, height: i32, solid: impl Fn(i32, i32) -> bool) -> Vec<Vec<Key>> {
+    let mut links: HashMap<Key, Vec<Key>> = HashMap::new();
+
+    // cells span between samples; the outer ring of cells sees the empty
+    // samples beyond the image
+    for j in -1 .. height {
+        for i in -1 .. width {
+            let case = (solid(i,     j    ) as u8) << 3
+                     | (solid(i + 1, j    ) as u8) << 2
+                     | (solid(i + 1, j + 1) as u8) << 1
+                     | (solid(i,     j + 1) as u8);
+
+            let top    = (2 * i + 1, 2 * j);
+            let right  = (2 * i + 2, 2 * j + 1);
+            let bottom = (2 * i + 1, 2 * j + 2);
+            let left   = (2 * i,     2 * j + 1);
+
+            let segments: &[(Key, Key)] = match case {
+                 1 | 14 => &[(left,  bottom)],
+                 2 | 13 => &[(bottom, right)],
+                 3 | 12 => &[(left,  right)],
+                 4 | 11 => &[(top,   right)],
+                 6 |  9 => &[(top,   bottom)],
+                 7 |  8 => &[(top,   left)],
+                 5      => &[(top, right), (left, bottom)],
+                10      => &[(top, left),  (bottom, right)],
+                 _      => &[],
+            };
+
+            for &(a, b) in segments {
+                links.entry(a).or_default().push(b);
+                links.entry(b).or_default().push(a);
+            }
+        }
+    }
+
+    // every midpoint on a contour joins exactly two segments
+    let mut loops = Vec::new();
+    while let Some(&start) = links.keys().next() {
+        let mut keys = vec![start];
+        let mut prev = start;
+        let mut cur = links[&start][0];
+        while cur != start {
+            keys.push(cur);
+            let next = links[&cur].iter()
+                .cloned()
+                .find(|&k| k != prev)
+                .unwrap_or(prev);
+            prev = cur;
+            cur = next;
+        }
+
+        for k in &keys {
+            links.remove(k);
+        }
+        loops.push(keys);
+    }
+
+    loops
+}
+
+// Moves points within half a pixel of the tile's border onto it, so that
+// traced colliders of adjacent solid tiles meet without notches, and drops
+// the duplicates this makes.
+fn snap(verts: Vec<V2>, width: i32, height: i32) -> Vec<V2> {
+    let snap_axis = |v: f32, size: f32| {
+        if v <= 0.5 { 0.0 }
+        else if v >= size - 0.5 { size }
+        else { v }
+    };
+
+    let mut out: Vec<V2> = Vec::with_capacity(verts.len());
+    for v in verts {
+        let v = V2::new(snap_axis(v.x, width as f32), snap_axis(v.y, height as f32));
+        if out.last() != Some(&v) {
+            out.push(v);
+        }
+    }
+    while out.len() > 1 && out.first() == out.last() {
+        out.pop();
+    }
+    out
+}
+
+fn area(verts: &[V2]) -> f32 {
+    let n = verts.len();
+    (0..n)
+        .map(|i| {
+            let (a, b) = (verts[i], verts[(i + 1) % n]);
+            a.x * b.y - b.x * a.y
+        })
+        .sum::<f32>() * 0.5
+}
+
+fn distance_to_segment(p: V2, a: V2, b: V2) -> f32 {
+    let d = b - a;
+    let len_sq = d.norm_squared();
+    if len_sq == 0.0 {
+        return (p - a).norm();
+    }
+    let t = ((p - a).dot(&d) / len_sq).clamp(0.0, 1.0);
+    (p - (a + d * t)).norm()
+}
+
+// Douglas-Peucker over an open run, keeping both ends.
+fn simplify_run(verts: &[V2], tolerance: f32, out: &mut Vec<V2>) {
+    let (first, last) = (verts[0], verts[verts.len() - 1]);
+    let farthest = (1 .. verts.len() - 1)
+        .map(|i| (i, distance_to_segment(verts[i], first, last)))
+        .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+
+    match farthest {
+        Some((i, dist)) if dist > tolerance => {
+            simplify_run(&verts[..= i], tolerance, out);
+            simplify_run(&verts[i ..], tolerance, out);
+        }
+        _ => {
+            out.push(first);
+        }
+    }
+}
+
+// Simplifies a closed loop by splitting it at its first vertex and the vertex
+// farthest from it.
+fn simplify(verts: &[V2], tolerance: f32) -> Vec<V2> {
+    let far = (1 .. verts.len())
+        .max_by(|&a, &b| {
+            let da = (verts[a] - verts[0]).norm_squared();
+            let db = (verts[b] - verts[0]).norm_squared();
+            da.partial_cmp(&db).unwrap()
+        })
+        .unwrap_or(0);
+
+    let mut closed = verts.to_vec();
+    closed.push(verts[0]);
+
+    let mut out = Vec::with_capacity(verts.len());
+    simplify_run(&closed[..= far], tolerance, &mut out);
+    simplify_run(&closed[far ..], tolerance, &mut out);
+    out
+}
+
+// Andrew's monotone chain, wound the same way as `verts`. Points along the
+// hull's sides are dropped.
+fn convex_hull(verts: &[V2]) -> Vec<V2> {
+    let mut sorted = verts.to_vec();
+    sorted.sort_by(|a, b| (a.x, a.y).partial_cmp(&(b.x, b.y)).unwrap());
+    sorted.dedup();
+    if sorted.len() < 3 {
+        return sorted;
+    }
+
+    let cross = |o: V2, a: V2, b: V2| (a.x - o.x) * (b.y - o.y) - (a.y - o.y) * (b.x - o.x);
+    let half = |points: &mut dyn Iterator<Item = &V2>| {
+        let mut chain: Vec<V2> = Vec::new();
+        for &p in points {
+            while chain.len() >= 2 && cross(chain[chain.len() - 2], chain[chain.len() - 1], p) <= 0.0 {
+                chain.pop();
+            }
+            chain.push(p);
+        }
+        chain.pop();
+        chain
+    };
+
+    // anticlockwise in y-up terms
+    let mut hull = half(&mut sorted.iter());
+    hull.extend(half(&mut sorted.iter().rev()));
+
+    if area(verts) < 0.0 {
+        hull.reverse();
+    }
+    hull
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            alg::Shape,
+            game::map::{Tileset, TILE_SIZE},
+        },
+        std::fs,
+    };
+
+    #[test]
+    fn concave_region_traces_to_hull() {
+        // an L: the left half, plus the bottom half of the right
+        let size = TILE_SIZE;
+        let mut pixels = vec![0u8; (size * size * 4) as usize];
+        for y in 0 .. size {
+            for x in 0 .. size {
+                if x < size / 2 || y >= size / 2 {
+                    pixels[((y * size + x) * 4 + 3) as usize] = 255;
+                }
+            }
+        }
+
+        let tracing = Tracing { threshold: 128, tolerance: 1.0 };
+        let shape = Shape::new_from_vec(tracing.trace(&pixels, size, size).unwrap());
+        assert!(shape.is_convex());
+        assert!(shape.signed_area().abs() > (size * size) as f32 * 0.8);
+    }
+
+    // What maplint checks, over a tileset whose colliders are all traced.
+    #[test]
+    fn traced_tileset_colliders_are_convex() {
+        let dir = std::env::temp_dir()
+            .join(format!("trace-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let image = std::env::current_dir().unwrap().join("tiles.png");
+        fs::write(dir.join("traced.tsx"), format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<tileset version="1.2" name="traced" tilewidth="16" tileheight="16" tilecount="16" columns="4">
+ <properties>
+  <property name="auto_collider" value="true"/>
+ </properties>
+ <image source="{}" width="64" height="64"/>
+</tileset>
+"#, image.display())).unwrap();
+        fs::write(dir.join("traced.tmx"), r#"<?xml version="1.0" encoding="UTF-8"?>
+<map version="1.2" orientation="orthogonal" renderorder="right-up" width="1" height="1" tilewidth="16" tileheight="16" infinite="0">
+ <tileset firstgid="1" source="traced.tsx"/>
+ <layer id="1" name="main" width="1" height="1">
+  <data encoding="csv">1</data>
+ </layer>
+</map>
+"#).unwrap();
+
+        let doc = tmx::Document::load(dir.join("traced.tmx")).unwrap();
+        let tileset = Tileset::load_headless(&doc.tilesets[0]).unwrap();
+        assert!(tileset.tiles().next().is_some());
+        for (id, tile) in tileset.tiles() {
+            let shape = tile.collider();
+            assert!(shape.verts.len() >= 3, "tile {} is degenerate", id);
+            assert!(shape.is_convex(), "tile {} is concave", id);
+        }
+    }
+}
@@ -1,53 +1,90 @@
 
-mod map;
+pub mod map;
 mod player;
+pub mod render;
+pub mod world;
 
 use {
     self::{
         player::Player,
-        map::Map,
+        world::{World, LoadWorldError},
+        render::{Backend, ChunkCache, GlBackend, Renderer, Rect, Sprite, SpriteSheet, stroke},
     },
     crate::{
         alg::{P2, V2},
-        gfx::{shader, load_atlas_texture},
+        damage,
+        gfx::{offscreen::Offscreen, Assets},
+        watch::Watcher,
         Event,
     },
     std::{
+        collections::{HashMap, HashSet},
         error::Error,
-        mem,
+        ffi::OsStr,
+        path::{Path, PathBuf},
         time::{Duration, Instant},
-        vec::Vec,
     },
-    gl::types::*,
+    nalgebra::{Matrix4, Point3, Vector3},
 };
 
-#[derive(Debug)]
-struct Camera {
-    centre: P2,
-    scale:  f32,
+#[derive(Clone, Copy, Debug)]
+pub struct Camera {
+    pub centre:   P2,
+    // world units per pixel
+    pub scale:    f32,
+    // anticlockwise, in radians
+    pub rotation: f32,
 }
 
 impl Camera {
-    fn make_frustum(&self, screen_dims: V2) -> Frustum {
-        let centre = self.centre;
-        let half_dims = screen_dims * 0.5 * self.scale;
+    // World space to clip space: `centre` lands in the middle of the screen
+    // and the view turns about it by `rotation`. Depth passes through.
+    pub fn view_projection(&self, screen_dims: V2) -> Matrix4<f32> {
+        let projection = Matrix4::new_nonuniform_scaling(&Vector3::new(
+            2.0 / (self.scale * screen_dims.x),
+            2.0 / (self.scale * screen_dims.y),
+            1.0,
+        ));
+        let view = Matrix4::from_euler_angles(0.0, 0.0, -self.rotation)
+            * Matrix4::new_translation(&Vector3::new(-self.centre.x, -self.centre.y, 0.0));
+        projection * view
+    }
 
-        Frustum { centre, half_dims }
+    fn make_frustum(&self, screen_dims: V2) -> Frustum {
+        Frustum::from_view_projection(&self.view_projection(screen_dims))
     }
 }
 
+// The world-space box around everything on screen; bigger than the screen
+// when the camera is rotated.
 #[derive(Debug)]
 struct Frustum {
-    centre:    P2,
-    half_dims: V2,
+    min: P2,
+    max: P2,
 }
 
 impl Frustum {
+    fn from_view_projection(view_projection: &Matrix4<f32>) -> Frustum {
+        let inverse = view_projection.try_inverse()
+            .unwrap_or_else(Matrix4::identity);
+
+        let corners = [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)];
+        let mut min = P2::new(f32::INFINITY, f32::INFINITY);
+        let mut max = P2::new(f32::NEG_INFINITY, f32::NEG_INFINITY);
+        for &(x, y) in &corners {
+            let p = inverse.transform_point(&Point3::new(x, y, 0.0));
+            min = P2::new(min.x.min(p.x), min.y.min(p.y));
+            max = P2::new(max.x.max(p.x), max.y.max(p.y));
+        }
+
+        Frustum { min, max }
+    }
+
     fn int_bounds(&self, scale: f32) -> IntRect {
-        let bottom_left = ((self.centre - self.half_dims) * scale)
+        let bottom_left = (self.min * scale)
             .coords
             .map(|x| x.floor());
-        let top_right = ((self.centre + self.half_dims) * scale)
+        let top_right = (self.max * scale)
             .coords
             .map(|x| x.ceil());
         IntRect {
@@ -59,34 +96,269 @@ impl Frustum {
     }
 }
 
-#[derive(Debug)]
-struct IntRect {
-    left:   i32,
-    bottom: i32,
-    right:  i32,
-    top:    i32,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IntRect {
+    pub left:   i32,
+    pub bottom: i32,
+    pub right:  i32,
+    pub top:    i32,
+}
+
+impl IntRect {
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.left && x < self.right && y >= self.bottom && y < self.top
+    }
+
+    pub fn overlaps(&self, other: &IntRect) -> bool {
+        self.left < other.right && other.left < self.right &&
+        self.bottom < other.top && other.bottom < self.top
+    }
+
+    pub fn expand(&self, margin: i32) -> IntRect {
+        IntRect {
+            left:   self.left   - margin,
+            bottom: self.bottom - margin,
+            right:  self.right  + margin,
+            top:    self.top    + margin,
+        }
+    }
+}
+
+pub const TICK_FREQ: u64 = 60;
+pub const TICK_DURATION: Duration = Duration::from_nanos(1_000_000_000 / TICK_FREQ);
+
+// the frame of player.json the player is drawn with
+const PLAYER_FRAME: &str = "idle";
+
+// rooms this many tiles off screen are loaded ahead of time
+const STREAM_MARGIN: i32 = 16;
+
+// what the dig key does to the tile in front of the player
+const DIG_DAMAGE: damage::Values = damage::Values { kinetic: 10, thermal: 0, explosive: 0 };
+
+// Everything that's simulated and drawn, independent of the window.
+pub struct Game {
+    world:          World,
+    // watches the files of the rooms loaded when it was made
+    world_watcher:  Watcher,
+    watched_paths:  Vec<PathBuf>,
+    player:         Player,
+    inputs:         player::Inputs,
+    assets:         Assets,
+    player_sheet:   SpriteSheet,
+    // batches in the renderer passed to `draw`, one cache per loaded room by
+    // room index; always draw with the same renderer
+    chunk_caches:   HashMap<usize, ChunkCache>,
+}
+
+impl Game {
+    // Loads a .world file, or any map on its own.
+    pub fn new(path: &Path) -> Result<Game, Box<dyn Error>> {
+        let world = if path.extension() == Some(OsStr::new("world")) {
+            World::load(path)?
+        }
+        else {
+            World::single(path)
+        };
+
+        let mut assets = Assets::new();
+        let player_sheet = SpriteSheet::load("player.json", &mut assets)?;
+        player_sheet.require(PLAYER_FRAME)?;
+
+        let mut game = Game {
+            world,
+            world_watcher: Watcher::new(Vec::new()),
+            watched_paths: Vec::new(),
+            player:        Player::new(P2::new(100.0, 100.0)),
+            inputs:        player::Inputs::new(),
+            assets,
+            player_sheet,
+            chunk_caches:  HashMap::new(),
+        };
+
+        // whatever is around the player, before there's a screen to fill
+        let tile_size = map::TILE_SIZE as f32;
+        let p = game.player.position;
+        let (x, y) = ((p.x / tile_size).floor() as i32, (p.y / tile_size).floor() as i32);
+        game.stream(&IntRect { left: x, bottom: y, right: x + 1, top: y + 1 })?;
+        Ok(game)
+    }
+
+    // Loads and drops rooms around `view`, given in tiles.
+    fn stream(&mut self, view: &IntRect) -> Result<(), LoadWorldError> {
+        self.world.stream(view, STREAM_MARGIN, &mut self.assets)?;
+
+        let paths = self.world.source_paths();
+        if paths != self.watched_paths {
+            self.world_watcher = Watcher::new(paths.iter().cloned());
+            self.watched_paths = paths;
+        }
+        Ok(())
+    }
+
+    // Reloads rooms whose files were edited; the player is left untouched.
+    pub fn reload_changed(&mut self) {
+        let changed = self.world_watcher.poll_changed();
+        if changed.is_empty() {
+            return;
+        }
+
+        // an edited image must be uploaded again rather than shared
+        self.assets.invalidate(&changed);
+
+        // the old maps' atlases are freed here
+        for (path, result) in self.world.reload(&changed, &mut self.assets) {
+            match result {
+                Ok(())  => eprintln!("reloaded {}", path.display()),
+                Err(e)  => eprintln!("failed to reload {}: {}", path.display(), e),
+            }
+        }
+        eprint!("{}", self.assets.report());
+
+        self.watched_paths = self.world.source_paths();
+        self.world_watcher = Watcher::new(self.watched_paths.iter().cloned());
+    }
+
+    pub fn tick(&mut self, screen_dims: V2) {
+        let view = self.camera()
+            .make_frustum(screen_dims)
+            .int_bounds(1.0 / map::TILE_SIZE as f32);
+        if let Err(e) = self.stream(&view) {
+            eprintln!("failed to stream in a room: {}", e);
+        }
+
+        self.player.tick(&self.inputs, TICK_DURATION.as_secs_f32(), &self.world);
+    }
+
+    // The tile the player is facing, level with their feet.
+    fn facing_tile(&self) -> (i32, i32) {
+        let tile_size = map::TILE_SIZE as f32;
+        let dx = match self.player.facing {
+            player::Facing::Left  => -tile_size,
+            player::Facing::Right => tile_size,
+        };
+        let p = self.player.position + V2::new(dx, tile_size / 2.0);
+        ((p.x / tile_size).floor() as i32, (p.y / tile_size).floor() as i32)
+    }
+
+    // Hits the tile in front of the player.
+    pub fn dig(&mut self) {
+        let (x, y) = self.facing_tile();
+        self.world.damage_tile(x, y, DIG_DAMAGE);
+    }
+
+    // Fills the cell in front of the player, autotiled if the room is and
+    // otherwise with a copy of the tile the player is standing on.
+    pub fn build(&mut self) {
+        let (x, y) = self.facing_tile();
+        if self.world.tile_at(x, y).is_some() || self.world.paint(x, y) {
+            return;
+        }
+
+        let tile_size = map::TILE_SIZE as f32;
+        let feet = self.player.position - V2::new(0.0, 1.0);
+        let below = ((feet.x / tile_size).floor() as i32, (feet.y / tile_size).floor() as i32);
+        if let Some((_, _, index)) = self.world.tile_at(below.0, below.1) {
+            self.world.set_tile(x, y, index);
+        }
+    }
+
+    pub fn camera(&self) -> Camera {
+        Camera {
+            centre:   self.player.position,
+            scale:    1.0 / 4.0,
+            rotation: 0.0,
+        }
+    }
+
+    pub fn draw<B: Backend>(&mut self, renderer: &mut Renderer<B>, screen_dims: V2) {
+        let camera = self.camera();
+
+        let frustum = camera.make_frustum(screen_dims);
+        //eprint!("frustum: {:#?}", frustum);
+
+        let bounds = frustum.int_bounds(1.0 / map::TILE_SIZE as f32);
+        //eprint!("bounds: {:#?}", bounds);
+
+        renderer.begin_frame(&camera, screen_dims);
+
+        let chunk_caches = &mut self.chunk_caches;
+        for (index, origin, map) in self.world.rooms() {
+            let cache = chunk_caches.entry(index)
+                .or_insert_with(|| ChunkCache::with_origin(origin));
+            cache.update(renderer, map);
+            cache.draw(renderer, &bounds);
+        }
+
+        // release the batches of rooms that were streamed out
+        let loaded: HashSet<usize> = self.world.rooms().map(|(index, _, _)| index).collect();
+        chunk_caches.retain(|index, cache| {
+            let keep = loaded.contains(index);
+            if !keep {
+                cache.clear(renderer);
+            }
+            keep
+        });
+
+        {   let p = self.player.position;
+            let frame = self.player_sheet.frame(PLAYER_FRAME).unwrap();
+            let mut sprite = frame.sprite(P2::new(p.x - frame.size.x * 0.5, p.y));
+            // over the map
+            sprite.layer = 0.5;
+            if self.player.facing == player::Facing::Left {
+                sprite.flags |= Sprite::FLIP_X;
+            }
+            if self.player.flashing() {
+                sprite.tint = [255, 64, 64, 255];
+            }
+            renderer.sprite(self.player_sheet.texture(), sprite);
+            let (lo, hi) = self.player.bounds();
+            let rect = Rect::new(lo.x, lo.y, hi.x, hi.y);
+            renderer.lines(stroke(rect.verts(), 255, 255, 0, 255));
+        }
+
+        renderer.end_frame();
+    }
+}
+
+// Loads a map, runs `ticks` ticks with no input, and renders the result
+// offscreen. Needs a current GL context, but no window.
+pub fn screenshot(map_path: &Path, ticks: u32, width: i32, height: i32)
+    -> Result<image::RgbaImage, Box<dyn Error>>
+{
+    let mut game = Game::new(map_path)?;
+    let screen_dims = V2::new(width as f32, height as f32);
+    for _ in 0..ticks {
+        game.tick(screen_dims);
+    }
+
+    let mut renderer = Renderer::new()?;
+    let target = Offscreen::new(width, height)?;
+    target.bind();
+    game.draw(&mut renderer, screen_dims);
+    target.unbind();
+
+    Ok(target.read_rgba())
 }
 
 pub fn main_thread(
     ctx: &glutin::WindowedContext<glutin::PossiblyCurrent>,
-    event_receiver: &std::sync::mpsc::Receiver<Event>
+    event_receiver: &std::sync::mpsc::Receiver<Event>,
+    map_path: &Path,
+    shader_dir: Option<&Path>,
 )   -> Result<(), Box<dyn Error>>
 {
-    let map = Map::load("test.tmx")?;
-
-    let player_texture = load_atlas_texture("player.png", 16, 16)?;
+    let mut game = Game::new(map_path)?;
+    let mut renderer = match shader_dir {
+        Some(dir) => Renderer::with_backend(GlBackend::with_shader_dir(dir)?),
+        None      => Renderer::new()?,
+    };
 
     let mut screen_dims = V2::new(1024.0, 1024.0);
 
-    let mut player = Player::new(P2::new(100.0, 100.0));
-    let mut inputs = player::Inputs::new();
-
     let mut time_accum = Duration::from_secs(0);
     let mut prev_now = Instant::now();
 
-    const TICK_FREQ: u64 = 60;
-    const TICK_DURATION: Duration = Duration::from_nanos(1_000_000_000 / TICK_FREQ);
-
     'main_loop: loop {
         // event processing
         'event_loop: loop {
@@ -124,10 +396,17 @@ pub fn main_thread(
                         } => {
                             use glutin::event::VirtualKeyCode as VK;
                             let down = state == glutin::event::ElementState::Pressed;
+                            let inputs = &mut game.inputs;
                             match vk {
                                 VK::A => inputs.left = down,
                                 VK::D => inputs.right = down,
                                 VK::Space => inputs.jump = down,
+                                #[cfg(feature = "debug-keys")]
+                                VK::H if down => game.player.hit(),
+                                #[cfg(feature = "debug-keys")]
+                                VK::K if down => game.dig(),
+                                #[cfg(feature = "debug-keys")]
+                                VK::B if down => game.build(),
                                 _ => { }
                             }
                         }
@@ -140,6 +419,8 @@ pub fn main_thread(
             }
         }
 
+        game.reload_changed();
+
         // advance clock
         {   let now = Instant::now();
             time_accum += now - prev_now;
@@ -150,41 +431,16 @@ pub fn main_thread(
 
         // game ticks
         while time_accum > TICK_DURATION {
-            player.tick(&inputs, TICK_DURATION.as_secs_f32());
+            game.tick(screen_dims);
             time_accum -= TICK_DURATION;
         }
 
-        //eprintln!("{:?}", player);
-
-        let camera = Camera {
-            centre: player.position,
-            scale:   1.0 / 4.0
-        };
-
-        let frustum = camera.make_frustum(screen_dims);
-        //eprint!("frustum: {:#?}", frustum);
-
-        let bounds = frustum.int_bounds(1.0 / 16.0);
-        //eprint!("bounds: {:#?}", bounds);
+        // draw
+        game.draw(&mut renderer, screen_dims);
 
-        for world_y in bounds.bottom..bounds.top {
-            for world_x in bounds.left..bounds.right {
-                if let Some((set, tile, index)) = map.tile_at(world_x, world_y) {
-                    let x = world_x as f32 * 16.0;
-                    let y = world_y as f32 * 16.0;
-                    let rect = Rect::new(x, y, x + 16.0, y + 16.0);
-                    sprites.push((set.texture(), Sprite{rect, texture_index: index}));
-                }
-            }
-        }
-
-        {   let p = player.position;
-            let rect = Rect::new(p.x - 8.0, p.y, p.x + 8.0, p.y + 16.0);
-            sprites.push((player_texture, Sprite{rect, texture_index: 0}));
-            lines.extend(stroke(rect.verts(), 255, 255, 0, 255))
-        }
+        // flip
+        ctx.swap_buffers()?;
     }
 
     Ok(())
 }
-
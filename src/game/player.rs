@@ -1,5 +1,6 @@
 
 use {
+    super::{map::outline::Edge, world::World},
     crate::{
         alg::{V2, P2},
     },
@@ -24,33 +25,184 @@ impl Inputs {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Facing { Left, Right }
+
+// how long the player flashes after being hit, in seconds
+#[cfg_attr(not(feature = "debug-keys"), allow(dead_code))]
+const HIT_FLASH_TIME: f32 = 0.3;
+
+// the collision box, centred on `position` horizontally and standing on it
+pub const HALF_WIDTH: f32 = 8.0;
+pub const HEIGHT:     f32 = 16.0;
+
+// Edges whose normals point closer to level than this are walls; the rest
+// are floors or ceilings, depending on which way they face.
+const WALL_NORMAL_Y: f32 = 0.5;
+
+// how far inside the box an edge may already be without blocking, so
+// touching edges don't catch
+const SKIN: f32 = 0.01;
+
+// The part of `edge` between `lo` and `hi` along x (or y if `along_y`), as
+// the range it spans along the other axis.
+fn clip(edge: &Edge, along_y: bool, lo: f32, hi: f32) -> Option<(f32, f32)> {
+    let (a, b) = if along_y {
+        ((edge.a.y, edge.a.x), (edge.b.y, edge.b.x))
+    }
+    else {
+        ((edge.a.x, edge.a.y), (edge.b.x, edge.b.y))
+    };
+    let ((u0, v0), (u1, v1)) = if a.0 <= b.0 { (a, b) } else { (b, a) };
+    if u1 <= lo || u0 >= hi {
+        return None;
+    }
+    if u1 == u0 {
+        return Some((v0.min(v1), v0.max(v1)));
+    }
+
+    let at = |u: f32| v0 + (v1 - v0) * (u - u0) / (u1 - u0);
+    let (va, vb) = (at(u0.max(lo)), at(u1.min(hi)));
+    Some((va.min(vb), va.max(vb)))
+}
+
 #[derive(Clone, Debug)]
 pub struct Player {
     pub position: P2,
+    pub facing:   Facing,
     phys_state: PhysState,
+    // seconds of hit flash left
+    hit_flash:  f32,
 }
 
 impl Player {
     pub fn new(position: P2) -> Player {
         Player {
             position,
-            phys_state: PhysState::Falling { velocity: V2::new(0.0, 0.0) }
+            facing:     Facing::Right,
+            phys_state: PhysState::Falling { velocity: V2::new(0.0, 0.0) },
+            hit_flash:  0.0,
+        }
+    }
+
+    // only the debug keys hit the player so far
+    #[cfg_attr(not(feature = "debug-keys"), allow(dead_code))]
+    pub fn hit(&mut self) {
+        self.hit_flash = HIT_FLASH_TIME;
+    }
+
+    // Whether to draw the player flashed this tick; blinks while the flash
+    // lasts.
+    pub fn flashing(&self) -> bool {
+        self.hit_flash > 0.0 && (self.hit_flash * 20.0) as i32 % 2 == 0
+    }
+
+    // Bottom-left and top-right corners of the collision box.
+    pub fn bounds(&self) -> (P2, P2) {
+        let p = self.position;
+        (P2::new(p.x - HALF_WIDTH, p.y), P2::new(p.x + HALF_WIDTH, p.y + HEIGHT))
+    }
+
+    // Moves sideways by up to `dx`, stopping at walls. Returns whether a
+    // wall was hit.
+    fn move_x(&mut self, dx: f32, world: &World) -> bool {
+        if dx == 0.0 {
+            return false;
+        }
+
+        let (lo, hi) = self.bounds();
+        let sweep_lo = P2::new(lo.x.min(lo.x + dx), lo.y);
+        let sweep_hi = P2::new(hi.x.max(hi.x + dx), hi.y);
+
+        let mut allowed = dx;
+        for edge in world.colliders_in(sweep_lo, sweep_hi) {
+            // only walls facing the way we came from block
+            if edge.normal.y.abs() >= WALL_NORMAL_Y || edge.normal.x * dx >= 0.0 {
+                continue;
+            }
+            let (near, far) = match clip(&edge, true, lo.y + SKIN, hi.y - SKIN) {
+                Some(span) => span,
+                None       => continue,
+            };
+            if dx > 0.0 && near >= hi.x - SKIN {
+                allowed = allowed.min((near - hi.x).max(0.0));
+            }
+            else if dx < 0.0 && far <= lo.x + SKIN {
+                allowed = allowed.max((far - lo.x).min(0.0));
+            }
+        }
+
+        self.position.x += allowed;
+        allowed != dx
+    }
+
+    // Moves up or down by up to `dy`, stopping at ceilings or floors.
+    // Returns whether one was hit.
+    fn move_y(&mut self, dy: f32, world: &World) -> bool {
+        if dy == 0.0 {
+            return false;
+        }
+
+        let (lo, hi) = self.bounds();
+        let sweep_lo = P2::new(lo.x, lo.y.min(lo.y + dy));
+        let sweep_hi = P2::new(hi.x, hi.y.max(hi.y + dy));
+
+        let mut allowed = dy;
+        for edge in world.colliders_in(sweep_lo, sweep_hi) {
+            if edge.normal.y.abs() < WALL_NORMAL_Y || edge.normal.y * dy >= 0.0 {
+                continue;
+            }
+            let (low, high) = match clip(&edge, false, lo.x + SKIN, hi.x - SKIN) {
+                Some(span) => span,
+                None       => continue,
+            };
+            if dy > 0.0 && low >= hi.y - SKIN {
+                allowed = allowed.min((low - hi.y).max(0.0));
+            }
+            else if dy < 0.0 && high <= lo.y + SKIN {
+                allowed = allowed.max((high - lo.y).min(0.0));
+            }
         }
+
+        self.position.y += allowed;
+        allowed != dy
+    }
+
+    // Height of the highest floor under the box, if there is one no more
+    // than `reach` above or below the player's feet.
+    fn floor_near(&self, reach: f32, world: &World) -> Option<f32> {
+        let (lo, hi) = self.bounds();
+        let y = self.position.y;
+        let edges = world.colliders_in(P2::new(lo.x, y - reach), P2::new(hi.x, y + reach));
+        edges.iter()
+            .filter(|edge| edge.normal.y >= WALL_NORMAL_Y)
+            .filter_map(|edge| clip(edge, false, lo.x + SKIN, hi.x - SKIN))
+            .map(|(_, high)| high)
+            .filter(|high| (high - y).abs() <= reach)
+            .fold(None, |best: Option<f32>, high| Some(best.map_or(high, |b| b.max(high))))
     }
 
-    pub fn tick(&mut self, inputs: &Inputs, dt: f32) {
+    pub fn tick(&mut self, inputs: &Inputs, dt: f32, world: &World) {
+        self.hit_flash = (self.hit_flash - dt).max(0.0);
+
+        // keep facing the same way when both or neither are held
+        if inputs.left != inputs.right {
+            self.facing = if inputs.left { Facing::Left } else { Facing::Right };
+        }
+
         const AY_GRAVITY: f32 = -750.0;
         const Y_MAX_JUMP: f32 = 56.0;
-        let   VY_JUMP = (-2.0 * AY_GRAVITY * Y_MAX_JUMP).sqrt();
+        let vy_jump = (-2.0 * AY_GRAVITY * Y_MAX_JUMP).sqrt();
         const VY_TERMINAL: f32 = -200.0;
 
         const AX_AIR:       f32 = 250.0;
         const AX_WALK:      f32 = 750.0;
         const VX_MAX_AIR:   f32 = 250.0;
         const VX_MAX_WALK:  f32 = 150.0;
-        const WALK_DAMPING: f32 = 20.0;
+        // furthest the player follows the floor up or down a slope while
+        // walking
+        const Y_MAX_STEP: f32 = 4.0;
 
-        const Y_FLOOR: f32 = -32.0;
 
         match self.phys_state {
             PhysState::Walking { vx } => {
@@ -62,14 +214,20 @@ impl Player {
                     //let vx = vx * 0.8;
                     if vx.abs() < 0.001 { 0.0 } else { vx }
                 };
-                self.position.x += vx * dt;
+                let vx = if self.move_x(vx * dt, world) { 0.0 } else { vx };
+                let floor = self.floor_near(Y_MAX_STEP, world);
 
                 self.phys_state = if inputs.jump {
-                    let velocity = V2::new(vx, VY_JUMP);
+                    let velocity = V2::new(vx, vy_jump);
                     PhysState::Falling { velocity }
                 }
-                else {
+                else if let Some(y) = floor {
+                    self.position.y = y;
                     PhysState::Walking { vx }
+                }
+                else {
+                    // walked off a ledge
+                    PhysState::Falling { velocity: V2::new(vx, 0.0) }
                 };
             }
 
@@ -83,18 +241,100 @@ impl Player {
 
                 let vy = (velocity.y + AY_GRAVITY * dt).max(VY_TERMINAL);
 
-                let velocity = V2::new(vx, vy);
-                self.position += velocity * dt;
+                let vx = if self.move_x(vx * dt, world) { 0.0 } else { vx };
+                let hit = self.move_y(vy * dt, world);
 
-                self.phys_state = if self.position.y < Y_FLOOR {
-                    self.position.y = Y_FLOOR;
-                    PhysState::Walking { vx }
-                }
-                else {
-                    PhysState::Falling { velocity }
+                self.phys_state = match (hit, vy < 0.0) {
+                    (true, true)  => PhysState::Walking { vx },
+                    // bumped a ceiling
+                    (true, false) => PhysState::Falling { velocity: V2::new(vx, 0.0) },
+                    (false, _)    => PhysState::Falling { velocity: V2::new(vx, vy) },
                 };
             }
         }
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::game::{
+            map::tests::{temp_dir, write_map},
+            IntRect,
+        },
+    };
+
+    // A floor along the bottom, a wall too high to jump near the right and
+    // a ceiling over the left.
+    fn room() -> World {
+        let path = temp_dir("player").join("room.tmx");
+        write_map(&path, &[
+            &[1, 1, 1, 1, 0, 0, 1, 0],
+            &[0, 0, 0, 0, 0, 0, 1, 0],
+            &[0, 0, 0, 0, 0, 0, 1, 0],
+            &[0, 0, 0, 0, 0, 0, 1, 0],
+            &[1, 1, 1, 1, 1, 1, 1, 1],
+        ]);
+        let mut world = World::single(&path);
+        world.stream_headless(&IntRect { left: 0, bottom: 0, right: 8, top: 5 }, 0).unwrap();
+        world
+    }
+
+    fn run(player: &mut Player, inputs: &Inputs, ticks: u32, world: &World) {
+        for _ in 0 .. ticks {
+            player.tick(inputs, 1.0 / 60.0, world);
+        }
+    }
+
+    fn walking(player: &Player) -> bool {
+        match player.phys_state {
+            PhysState::Walking { .. } => true,
+            PhysState::Falling { .. } => false,
+        }
+    }
+
+    #[test]
+    fn lands_on_the_floor() {
+        let world = room();
+        let mut player = Player::new(P2::new(72.0, 40.0));
+        run(&mut player, &Inputs::new(), 60, &world);
+        assert!(walking(&player));
+        assert_eq!(player.position, P2::new(72.0, 16.0));
+    }
+
+    #[test]
+    fn stops_at_walls() {
+        let world = room();
+        let mut player = Player::new(P2::new(72.0, 16.0));
+        let inputs = Inputs { right: true, ..Inputs::new() };
+        run(&mut player, &inputs, 60, &world);
+        assert!(walking(&player));
+        assert_eq!(player.position, P2::new(96.0 - HALF_WIDTH, 16.0));
+
+        // and jumping doesn't get through either
+        let inputs = Inputs { right: true, jump: true, ..Inputs::new() };
+        run(&mut player, &inputs, 30, &world);
+        assert!(player.position.x <= 96.0 - HALF_WIDTH);
+    }
+
+    #[test]
+    fn bumps_ceilings() {
+        let world = room();
+        let mut player = Player::new(P2::new(24.0, 16.0));
+        run(&mut player, &Inputs::new(), 1, &world);
+
+        let jump = Inputs { jump: true, ..Inputs::new() };
+        player.tick(&jump, 1.0 / 60.0, &world);
+        let mut top: f32 = 0.0;
+        for _ in 0 .. 60 {
+            player.tick(&Inputs::new(), 1.0 / 60.0, &world);
+            top = top.max(player.bounds().1.y);
+        }
+        // the ceiling's underside is at 64, well under a full jump
+        assert_eq!(top, 64.0);
+        assert!(walking(&player));
+        assert_eq!(player.position.y, 16.0);
+    }
+}
@@ -0,0 +1,282 @@
+
+// Keeps the map's tiles on the GPU as one static batch per chunk, so drawing
+// them costs a draw call per visible chunk instead of work per tile.
+
+use {
+    super::{Backend, Renderer, Rect, Sprite, StaticId},
+    crate::game::{
+        IntRect,
+        map::{Map, CHUNK_SIZE, TILE_SIZE, tmx::Flip},
+    },
+    std::collections::HashMap,
+    gl::types::*,
+};
+
+// A tile's sprite placed with Tiled's `flip`. Sprites can't swap axes, but
+// for square tiles a diagonal flip is a horizontal flip followed by a quarter
+// turn, and flipping after the turn is flipping the other way before it.
+fn oriented(mut sprite: Sprite, flip: Flip) -> Sprite {
+    let (flip_x, flip_y) = if flip.diagonal {
+        sprite.rotation = std::f32::consts::FRAC_PI_2;
+        (!flip.vertical, flip.horizontal)
+    }
+    else {
+        (flip.horizontal, flip.vertical)
+    };
+
+    if flip_x { sprite.flags |= Sprite::FLIP_X; }
+    if flip_y { sprite.flags |= Sprite::FLIP_Y; }
+    sprite
+}
+
+struct CachedChunk {
+    revision: u64,
+    texture:  GLuint,
+    batch:    StaticId,
+}
+
+pub struct ChunkCache {
+    // where the map's tile (0, 0) is drawn, in global tile coordinates
+    origin:  (i32, i32),
+    chunks:  HashMap<(i32, i32), CachedChunk>,
+    sprites: Vec<Sprite>,
+}
+
+impl ChunkCache {
+    pub fn new() -> ChunkCache {
+        ChunkCache::with_origin((0, 0))
+    }
+
+    // For a map placed in a world with its tile (0, 0) at `origin`.
+    pub fn with_origin(origin: (i32, i32)) -> ChunkCache {
+        ChunkCache { origin, chunks: HashMap::new(), sprites: Vec::new() }
+    }
+
+    // Rebuilds the chunks whose tiles changed since they were cached and
+    // drops those that no longer exist. Cheap when nothing changed.
+    pub fn update<B: Backend>(&mut self, renderer: &mut Renderer<B>, map: &Map) {
+        let revisions: HashMap<(i32, i32), u64> = map.chunk_revisions().collect();
+
+        let stale: Vec<(i32, i32)> = self.chunks.iter()
+            .filter(|(coords, cached)| revisions.get(coords) != Some(&cached.revision))
+            .map(|(coords, _)| *coords)
+            .collect();
+        for coords in stale {
+            let cached = self.chunks.remove(&coords).unwrap();
+            renderer.release_static(cached.batch);
+        }
+
+        let texture = map.tileset().texture();
+        let tile_size = TILE_SIZE as f32;
+        let (ox, oy) = self.origin;
+
+        for (&(cx, cy), &revision) in &revisions {
+            if self.chunks.contains_key(&(cx, cy)) {
+                continue;
+            }
+
+            self.sprites.clear();
+            self.sprites.extend(map.chunk_tiles(cx, cy).map(|((x, y), index, flip)| {
+                let (x, y) = ((x + ox) as f32 * tile_size, (y + oy) as f32 * tile_size);
+                let rect = Rect::new(x, y, x + tile_size, y + tile_size);
+                oriented(Sprite::new(rect, index), flip)
+            }));
+
+            let batch = renderer.create_static(&self.sprites);
+            self.chunks.insert((cx, cy), CachedChunk { revision, texture, batch });
+        }
+    }
+
+    // Queues the cached chunks overlapping `view`, given in global tiles.
+    pub fn draw<B: Backend>(&self, renderer: &mut Renderer<B>, view: &IntRect) {
+        let (ox, oy) = self.origin;
+        let left   = (view.left   - ox).div_euclid(CHUNK_SIZE);
+        let bottom = (view.bottom - oy).div_euclid(CHUNK_SIZE);
+        let right  = (view.right  - ox - 1).div_euclid(CHUNK_SIZE);
+        let top    = (view.top    - oy - 1).div_euclid(CHUNK_SIZE);
+
+        // walk whichever is smaller: the view's chunks or the cached ones
+        let view_chunks = (right - left + 1).max(0) as usize * (top - bottom + 1).max(0) as usize;
+        if view_chunks <= self.chunks.len() {
+            for cy in bottom ..= top {
+                for cx in left ..= right {
+                    if let Some(cached) = self.chunks.get(&(cx, cy)) {
+                        renderer.draw_static(cached.texture, cached.batch);
+                    }
+                }
+            }
+        }
+        else {
+            let mut visible: Vec<(&(i32, i32), &CachedChunk)> = self.chunks.iter()
+                .filter(|(&(cx, cy), _)| cx >= left && cx <= right && cy >= bottom && cy <= top)
+                .collect();
+            // keep the draw order independent of hashing
+            visible.sort_by_key(|(&(cx, cy), _)| (cy, cx));
+            for (_, cached) in visible {
+                renderer.draw_static(cached.texture, cached.batch);
+            }
+        }
+    }
+
+    // Releases every batch, e.g. before dropping the renderer.
+    pub fn clear<B: Backend>(&mut self, renderer: &mut Renderer<B>) {
+        for (_, cached) in self.chunks.drain() {
+            renderer.release_static(cached.batch);
+        }
+    }
+}
+
+impl Default for ChunkCache {
+    fn default() -> ChunkCache {
+        ChunkCache::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            alg::{P2, V2},
+            game::{
+                Camera,
+                map::tests::{temp_dir, write_map},
+                render::record::{Draw, Recorder},
+            },
+        },
+    };
+
+    // A row of 40 tiles, spanning three chunks.
+    fn map() -> Map {
+        let path = temp_dir("chunks").join("map.tmx");
+        write_map(&path, &[&[1; 40]]);
+        Map::load_headless(&path).unwrap()
+    }
+
+    fn renderer() -> Renderer<Recorder> {
+        Renderer::with_backend(Recorder::new())
+    }
+
+    fn view(left: i32, right: i32) -> IntRect {
+        IntRect { left, bottom: 0, right, top: 1 }
+    }
+
+    // The left edges, in tiles, of the sprites of each chunk drawn.
+    fn drawn(renderer: &mut Renderer<Recorder>, cache: &ChunkCache, view: &IntRect)
+        -> Vec<Vec<i32>>
+    {
+        let camera = Camera { centre: P2::origin(), scale: 1.0, rotation: 0.0 };
+        renderer.begin_frame(&camera, V2::new(100.0, 100.0));
+        cache.draw(renderer, view);
+        renderer.end_frame();
+
+        renderer.backend().last_frame().unwrap().draws.iter()
+            .map(|draw| match draw {
+                Draw::Static { sprites, .. } => sprites.iter()
+                    .map(|sprite| {
+                        let rect = sprite.rect;
+                        (rect.left / TILE_SIZE as f32) as i32
+                    })
+                    .collect(),
+                other => panic!("expected only static batches, got {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn uploads_each_chunk_once() {
+        let map = map();
+        let mut renderer = renderer();
+        let mut cache = ChunkCache::new();
+        cache.update(&mut renderer, &map);
+        assert_eq!(renderer.backend().static_uploads(), 3);
+        cache.update(&mut renderer, &map);
+        assert_eq!(renderer.backend().static_uploads(), 3);
+    }
+
+    #[test]
+    fn rebuilds_only_changed_chunks() {
+        let mut map = map();
+        let mut renderer = renderer();
+        let mut cache = ChunkCache::new();
+        cache.update(&mut renderer, &map);
+
+        map.clear_tile(20, 0);
+        cache.update(&mut renderer, &map);
+        assert_eq!(renderer.backend().static_uploads(), 4);
+
+        let middle = drawn(&mut renderer, &cache, &view(16, 32));
+        assert_eq!(middle.len(), 1);
+        assert_eq!(middle[0].len(), 15);
+        assert!(!middle[0].contains(&20));
+
+        // emptying a chunk drops it without uploading anything
+        for x in 32 .. 40 {
+            map.clear_tile(x, 0);
+        }
+        cache.update(&mut renderer, &map);
+        assert_eq!(renderer.backend().static_uploads(), 4);
+        assert!(drawn(&mut renderer, &cache, &view(32, 48)).is_empty());
+    }
+
+    #[test]
+    fn draws_chunks_overlapping_the_view() {
+        let map = map();
+        let mut renderer = renderer();
+        let mut cache = ChunkCache::new();
+        cache.update(&mut renderer, &map);
+
+        let firsts = |chunks: Vec<Vec<i32>>| -> Vec<i32> {
+            chunks.iter().map(|sprites| *sprites.iter().min().unwrap()).collect()
+        };
+        assert_eq!(firsts(drawn(&mut renderer, &cache, &view(0, 10))), [0]);
+        assert_eq!(firsts(drawn(&mut renderer, &cache, &view(15, 17))), [0, 16]);
+        assert!(drawn(&mut renderer, &cache, &view(-8, 0)).is_empty());
+        // chunks are culled whole, so only past the last one is clear
+        assert!(drawn(&mut renderer, &cache, &view(48, 60)).is_empty());
+        // more chunks in view than cached walks the cache instead
+        assert_eq!(firsts(drawn(&mut renderer, &cache, &view(-1000, 1000))), [0, 16, 32]);
+
+        // placed further right in the world, the same view sees less of it
+        let mut placed = ChunkCache::with_origin((16, 0));
+        placed.update(&mut renderer, &map);
+        assert_eq!(firsts(drawn(&mut renderer, &placed, &view(15, 17))), [16]);
+        assert_eq!(firsts(drawn(&mut renderer, &placed, &view(40, 60))), [32, 48]);
+    }
+
+    // Where each corner of a unit tile's texture lands with the sprite
+    // `oriented` gives for `flip`, as the vertex shader places it, against
+    // where Tiled draws it: transposed for a diagonal flip first, then
+    // mirrored.
+    #[test]
+    fn orients_tiles_like_tiled() {
+        let round = |v: f32| (v * 1000.0).round() / 1000.0;
+        for bits in 0 .. 8 {
+            let flip = Flip { horizontal: bits & 1 != 0, vertical: bits & 2 != 0, diagonal: bits & 4 != 0 };
+            let sprite = oriented(Sprite::new(Rect::new(0.0, 0.0, 1.0, 1.0), 0), flip);
+            let (flags, rotation, uv) = (sprite.flags, sprite.rotation, sprite.uv);
+
+            for &(cx, cy) in &[(0.0, 0.0), (0.0, 1.0), (1.0, 1.0), (1.0, 0.0)] {
+                // the shader: flip the texture corner, turn the vertex
+                let tx = if flags & Sprite::FLIP_X != 0 { 1.0 - cx } else { cx };
+                let ty = if flags & Sprite::FLIP_Y != 0 { 1.0 - cy } else { cy };
+                let texel = (
+                    round(uv.left + (uv.right - uv.left) * tx),
+                    round(uv.bottom + (uv.top - uv.bottom) * ty),
+                );
+                let (s, c) = rotation.sin_cos();
+                let (px, py) = (cx - 0.5, cy - 0.5);
+                let (x, y) = (0.5 + c * px - s * py, 0.5 + s * px + c * py);
+
+                // Tiled, in y-down tile space: undo the mirroring, then the
+                // transpose, to find which texel is drawn at (x, y)
+                let (mut u, mut v) = (round(x), round(1.0 - y));
+                if flip.vertical   { v = 1.0 - v; }
+                if flip.horizontal { u = 1.0 - u; }
+                if flip.diagonal   { std::mem::swap(&mut u, &mut v); }
+
+                assert_eq!(texel, (u, v), "corner ({}, {}) with {:?}", cx, cy, flip);
+            }
+        }
+    }
+}
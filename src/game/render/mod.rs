@@ -0,0 +1,446 @@
+
+mod opengl;
+mod record;
+mod chunks;
+mod sheet;
+
+pub use self::{
+    chunks::ChunkCache,
+    sheet::{SheetFrame, SpriteSheet},
+    opengl::GlBackend,
+    record::{Recorder, Frame, Draw},
+};
+
+use {
+    super::Camera,
+    crate::alg::{P2, V2},
+    std::error::Error,
+    gl::types::*,
+};
+
+// graphics buffer formats
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rect {
+    pub left:   f32,
+    pub bottom: f32,
+    pub right:  f32,
+    pub top:    f32,
+}
+
+static_assertions::assert_eq_size!(Rect, [f32; 4]);
+
+impl Rect {
+    pub fn new(left: f32, bottom: f32, right: f32, top: f32) -> Rect {
+        Rect { left, bottom, right, top }
+    }
+
+    pub fn verts<'a>(&'a self) -> impl Iterator<Item = P2> + Clone + 'a {
+        let mut index = 0;
+        let next_vert = move || {
+            let vert = match index {
+                0 => P2::new(self.left,  self.bottom),
+                1 => P2::new(self.right, self.bottom),
+                2 => P2::new(self.right, self.top),
+                3 => P2::new(self.left,  self.top),
+                _ => { return None; }
+            };
+            index += 1;
+            Some(vert)
+        };
+        std::iter::from_fn(next_vert)
+    }
+}
+
+// Line list outlining the closed polygon through `vs`.
+pub fn stroke(vs: impl Iterator<Item = P2> + Clone, r: u8, g: u8, b: u8, a: u8) -> Vec<LineVert> {
+    let count = vs.clone().count();
+    let verts = vs
+        .map(|v| LineVert { x: v.x, y: v.y, r, g, b, a })
+        .cycle();
+
+    let pairs: Vec<_> = verts.clone().zip(verts.skip(1))
+        .map(|(a, b)| [a, b])
+        .take(count)
+        .collect();
+
+    pairs.iter()
+        .flatten()
+        .copied()
+        .collect()
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct Sprite {
+    pub rect:          Rect,
+    pub texture_index: u32,
+    // multiplies the texture's colour and alpha
+    pub tint:          [u8; 4],
+    // where the sprite turns about, as a fraction of the rect
+    pub pivot:         [f32; 2],
+    // anticlockwise, in radians
+    pub rotation:      f32,
+    // 0 to 1; sprites on higher layers cover lower ones
+    pub layer:         f32,
+    // FLIP_X, FLIP_Y
+    pub flags:         u32,
+    // the texture coordinates at each edge of `rect`, within the layer;
+    // v runs down the image
+    pub uv:            Rect,
+}
+
+static_assertions::assert_eq_size!(Sprite, [u32; 15]);
+
+impl Sprite {
+    pub const FLIP_X: u32 = 1;
+    pub const FLIP_Y: u32 = 2;
+
+    // The whole of a layer.
+    pub const FULL_UV: Rect = Rect { left: 0.0, bottom: 1.0, right: 1.0, top: 0.0 };
+
+    // Untinted, unflipped and upright on layer 0, pivoting about its centre
+    // and showing the whole layer.
+    pub fn new(rect: Rect, texture_index: u32) -> Sprite {
+        Sprite {
+            rect,
+            texture_index,
+            tint:     [255, 255, 255, 255],
+            pivot:    [0.5, 0.5],
+            rotation: 0.0,
+            layer:    0.0,
+            flags:    0,
+            uv:       Sprite::FULL_UV,
+        }
+    }
+
+    // Whether the sprite's tint lets what's behind it show through.
+    pub fn is_translucent(&self) -> bool {
+        let tint = self.tint;
+        tint[3] < 255
+    }
+
+    // The world-space box the sprite covers once rotated.
+    pub fn bounds(&self) -> Rect {
+        // copied out, since fields of a packed struct can't be borrowed
+        let rect = self.rect;
+        let rotation = self.rotation;
+        if rotation == 0.0 {
+            return rect;
+        }
+
+        let Rect { left, bottom, right, top } = rect;
+        let [px, py] = self.pivot;
+        let pivot = V2::new(left + (right - left) * px, bottom + (top - bottom) * py);
+        let (sin, cos) = rotation.sin_cos();
+
+        let mut bounds = Rect::new(
+            f32::INFINITY,     f32::INFINITY,
+            f32::NEG_INFINITY, f32::NEG_INFINITY
+        );
+        for corner in rect.verts() {
+            let d = corner.coords - pivot;
+            let x = pivot.x + cos * d.x - sin * d.y;
+            let y = pivot.y + sin * d.x + cos * d.y;
+            bounds = Rect::new(
+                bounds.left.min(x),  bounds.bottom.min(y),
+                bounds.right.max(x), bounds.top.max(y)
+            );
+        }
+        bounds
+    }
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct LineVert {
+    pub x: f32, pub y: f32,
+    pub r: u8, pub g: u8, pub b: u8, pub a: u8
+}
+
+static_assertions::assert_eq_size!(LineVert, [u32; 3]);
+
+// Where a frame's draws go. Textures are only ever used as keys by the
+// renderer, so backends that don't draw can treat them as opaque.
+pub trait Backend {
+    fn begin(&mut self, camera: &Camera, screen_dims: V2);
+    // a batch of sprites all sampling from `texture`
+    fn draw_sprites(&mut self, texture: GLuint, sprites: &[Sprite]);
+    // like draw_sprites, for sprites blended over what's already drawn; they
+    // are depth tested but mustn't write depth, so they can't hide anything
+    // drawn after them
+    fn draw_translucent(&mut self, texture: GLuint, sprites: &[Sprite]);
+    // a line list
+    fn draw_lines(&mut self, verts: &[LineVert]);
+    fn end(&mut self);
+
+    // Sprites uploaded once and drawn on many frames.
+    type Static;
+    fn create_static(&mut self, sprites: &[Sprite]) -> Self::Static;
+    fn draw_static(&mut self, texture: GLuint, batch: &Self::Static);
+}
+
+// Refers to a static batch held by a Renderer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StaticId(usize);
+
+// Collects a frame's sprites and lines and hands them to the backend in as
+// few batches as it can. Static batches are drawn first, in submission
+// order. Then sprites off screen are culled, the opaque ones are batched by
+// texture in submission order, and the translucent ones are drawn after
+// them from the lowest layer up, since blending needs what's behind them
+// drawn first. Lines go over everything. Among opaque sprites, what covers
+// what is up to their layers, not the order they're drawn in.
+pub struct Renderer<B: Backend = GlBackend> {
+    backend: B,
+
+    statics:        Vec<Option<B::Static>>,
+    queued_statics: Vec<(GLuint, StaticId)>,
+
+    sprites:     Vec<(GLuint, Sprite)>,
+    translucent: Vec<(GLuint, Sprite)>,
+    batch:       Vec<Sprite>,
+    lines:   Vec<LineVert>,
+
+    camera:      Camera,
+    screen_dims: V2,
+}
+
+impl Renderer<GlBackend> {
+    pub fn new() -> Result<Renderer, Box<dyn Error>> {
+        Ok(Renderer::with_backend(GlBackend::new()?))
+    }
+}
+
+impl<B: Backend> Renderer<B> {
+    pub fn with_backend(backend: B) -> Renderer<B> {
+        Renderer {
+            backend,
+            statics:        Vec::new(),
+            queued_statics: Vec::new(),
+            sprites:     Vec::new(),
+            translucent: Vec::new(),
+            batch:       Vec::new(),
+            lines:       Vec::new(),
+            camera:      Camera { centre: P2::origin(), scale: 1.0, rotation: 0.0 },
+            screen_dims: V2::new(1.0, 1.0),
+        }
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    pub fn begin_frame(&mut self, camera: &Camera, screen_dims: V2) {
+        self.camera = *camera;
+        self.screen_dims = screen_dims;
+        self.queued_statics.clear();
+        self.sprites.clear();
+        self.lines.clear();
+    }
+
+    // Uploads sprites to be drawn on later frames with `draw_static`.
+    pub fn create_static(&mut self, sprites: &[Sprite]) -> StaticId {
+        let batch = Some(self.backend.create_static(sprites));
+        match self.statics.iter().position(|slot| slot.is_none()) {
+            Some(index) => {
+                self.statics[index] = batch;
+                StaticId(index)
+            }
+            None => {
+                self.statics.push(batch);
+                StaticId(self.statics.len() - 1)
+            }
+        }
+    }
+
+    pub fn release_static(&mut self, id: StaticId) {
+        self.statics[id.0] = None;
+    }
+
+    pub fn draw_static(&mut self, texture: GLuint, id: StaticId) {
+        assert!(self.statics[id.0].is_some(), "Drawing a released static batch");
+        self.queued_statics.push((texture, id));
+    }
+
+    pub fn sprite(&mut self, texture: GLuint, sprite: Sprite) {
+        self.sprites.push((texture, sprite));
+    }
+
+    pub fn lines(&mut self, verts: impl IntoIterator<Item = LineVert>) {
+        self.lines.extend(verts);
+    }
+
+    // Draws everything submitted since begin_frame.
+    pub fn end_frame(&mut self) {
+        let Renderer { camera, screen_dims, .. } = *self;
+        self.backend.begin(&camera, screen_dims);
+
+        for (texture, id) in &self.queued_statics {
+            if let Some(batch) = &self.statics[id.0] {
+                self.backend.draw_static(*texture, batch);
+            }
+        }
+
+        let frustum = camera.make_frustum(screen_dims);
+        let (lo, hi) = (frustum.min, frustum.max);
+        let translucent = &mut self.translucent;
+        translucent.clear();
+        self.sprites.retain(|&(texture, sprite)| {
+            let Rect { left, bottom, right, top } = sprite.bounds();
+            let visible = left < hi.x && right > lo.x && bottom < hi.y && top > lo.y;
+            if visible && sprite.is_translucent() {
+                translucent.push((texture, sprite));
+                false
+            }
+            else {
+                visible
+            }
+        });
+
+        // stable, so sprites sharing a texture keep their submission order
+        self.sprites.sort_by_key(|(texture, _)| *texture);
+        draw_runs(&mut self.backend, &mut self.batch, &self.sprites, B::draw_sprites);
+
+        // back to front, in submission order within a layer
+        self.translucent.sort_by(|(_, a), (_, b)| {
+            let (a, b) = (a.layer, b.layer);
+            a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal)
+        });
+        draw_runs(&mut self.backend, &mut self.batch, &self.translucent, B::draw_translucent);
+
+        if !self.lines.is_empty() {
+            self.backend.draw_lines(&self.lines);
+        }
+
+        self.backend.end();
+    }
+}
+
+// Draws each run of consecutive sprites sharing a texture as one batch.
+fn draw_runs<B: Backend>(
+    backend: &mut B,
+    batch:   &mut Vec<Sprite>,
+    sprites: &[(GLuint, Sprite)],
+    draw:    fn(&mut B, GLuint, &[Sprite]))
+{
+    let mut rest = sprites;
+    while let Some(&(texture, _)) = rest.first() {
+        let count = rest.iter()
+            .position(|(t, _)| *t != texture)
+            .unwrap_or(rest.len());
+
+        batch.clear();
+        batch.extend(rest[.. count].iter().map(|(_, sprite)| *sprite));
+        draw(backend, texture, batch);
+
+        rest = &rest[count ..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 100x100 pixel view of the world from (-50, -50) to (50, 50).
+    fn renderer() -> Renderer<Recorder> {
+        let mut renderer = Renderer::with_backend(Recorder::new());
+        let camera = Camera { centre: P2::origin(), scale: 1.0, rotation: 0.0 };
+        renderer.begin_frame(&camera, V2::new(100.0, 100.0));
+        renderer
+    }
+
+    fn square(x: f32, y: f32, id: u32) -> Sprite {
+        Sprite::new(Rect::new(x, y, x + 10.0, y + 10.0), id)
+    }
+
+    fn drawn(renderer: &Renderer<Recorder>) -> &Frame {
+        renderer.backend().last_frame().expect("no frame recorded")
+    }
+
+    #[test]
+    fn culls_off_screen_sprites() {
+        let mut renderer = renderer();
+        renderer.sprite(1, square(0.0, 0.0, 0));
+        renderer.sprite(1, square(45.0, -55.0, 1));
+        renderer.sprite(1, square(200.0, 0.0, 2));
+        renderer.sprite(1, square(-60.0, -60.0, 3));
+        renderer.end_frame();
+
+        let ids: Vec<u32> = drawn(&renderer).sprite_batches()
+            .flat_map(|(_, sprites)| sprites.iter().map(|sprite| sprite.texture_index))
+            .collect();
+        assert_eq!(ids, vec![0, 1]);
+    }
+
+    #[test]
+    fn batches_by_texture_in_submission_order() {
+        let mut renderer = renderer();
+        for (id, &texture) in [2, 1, 2, 1, 2].iter().enumerate() {
+            renderer.sprite(texture, square(0.0, 0.0, id as u32));
+        }
+        renderer.end_frame();
+
+        let batches: Vec<(GLuint, Vec<u32>)> = drawn(&renderer).sprite_batches()
+            .map(|(texture, sprites)| {
+                (texture, sprites.iter().map(|sprite| sprite.texture_index).collect())
+            })
+            .collect();
+        assert_eq!(batches.len(), 2);
+        assert!(batches.contains(&(1, vec![1, 3])));
+        assert!(batches.contains(&(2, vec![0, 2, 4])));
+    }
+
+    #[test]
+    fn draws_translucent_sprites_last_back_to_front() {
+        let mut renderer = renderer();
+        let faded = |id, layer| {
+            let mut sprite = square(0.0, 0.0, id);
+            sprite.tint = [255, 255, 255, 128];
+            sprite.layer = layer;
+            sprite
+        };
+        renderer.sprite(1, faded(0, 0.8));
+        renderer.sprite(2, square(0.0, 0.0, 1));
+        renderer.sprite(2, faded(2, 0.2));
+        renderer.sprite(1, faded(3, 0.2));
+        renderer.sprite(2, faded(4, 0.5));
+        renderer.end_frame();
+
+        let frame = drawn(&renderer);
+        match frame.draws.first() {
+            Some(Draw::Sprites { texture: 2, sprites }) => assert_eq!(sprites.len(), 1),
+            other => panic!("expected the opaque sprite first, got {:?}", other),
+        }
+
+        let order: Vec<(GLuint, Vec<u32>)> = frame.translucent_batches()
+            .map(|(texture, sprites)| {
+                (texture, sprites.iter().map(|sprite| sprite.texture_index).collect())
+            })
+            .collect();
+        assert_eq!(order, vec![(2, vec![2]), (1, vec![3]), (2, vec![4]), (1, vec![0])]);
+        assert_eq!(frame.sprite_count(), 5);
+    }
+
+    #[test]
+    fn draws_lines_after_sprites() {
+        let mut renderer = renderer();
+        let outline = Rect::new(0.0, 0.0, 10.0, 10.0);
+        renderer.lines(stroke(outline.verts(), 255, 0, 0, 255));
+        renderer.sprite(1, square(0.0, 0.0, 0));
+        renderer.sprite(2, square(5.0, 5.0, 1));
+        renderer.end_frame();
+
+        let draws = &drawn(&renderer).draws;
+        assert_eq!(draws.len(), 3);
+        match draws.last() {
+            Some(Draw::Lines(verts)) => assert_eq!(verts.len(), 8),
+            other                    => panic!("expected lines last, got {:?}", other),
+        }
+        assert_eq!(drawn(&renderer).line_verts().count(), 8);
+    }
+}
@@ -0,0 +1,377 @@
+
+// The OpenGL 4.5 backend.
+
+use {
+    super::{Backend, Sprite, LineVert},
+    crate::{
+        alg::V2,
+        game::Camera,
+        gfx::{shader, program_cache::ProgramCache, stream::StreamBuffer, Buffer, VertexArray},
+    },
+    std::{
+        error::Error,
+        mem,
+        path::Path,
+        ptr,
+    },
+    nalgebra::Matrix4,
+    gl::types::*,
+};
+
+mod shader_src {
+    pub static SPRITE_V: &str = "sprite-vert.glsl";
+    pub static SPRITE_F: &str = "sprite-frag.glsl";
+
+    pub static LINE_V: &str = "line-vert.glsl";
+    pub static LINE_F: &str = "line-frag.glsl";
+
+    // every source, includes too, for builds that don't read from disk
+    pub static EMBEDDED: &[(&str, &str)] = &[
+        ("sprite-vert.glsl", include_str!("../../sprite-vert.glsl")),
+        ("sprite-frag.glsl", include_str!("../../sprite-frag.glsl")),
+        ("line-vert.glsl",   include_str!("../../line-vert.glsl")),
+        ("line-frag.glsl",   include_str!("../../line-frag.glsl")),
+        ("camera.glsl",      include_str!("../../camera.glsl")),
+    ];
+}
+
+// The uniform buffer binding every program reads the camera from, as
+// declared in camera.glsl.
+const CAMERA_BINDING: GLuint = 0;
+
+// What the backend sets and feeds each program; vertex formats below rely on
+// the attribute locations.
+static SPRITE_INTERFACE: shader::Interface = shader::Interface {
+    uniforms: &[
+        ("tex", gl::SAMPLER_2D_ARRAY),
+    ],
+    blocks: &[
+        ("Camera", CAMERA_BINDING),
+    ],
+    attributes: &[
+        ("attr_rect",      gl::FLOAT_VEC4,   0),
+        ("attr_tex_index", gl::UNSIGNED_INT, 1),
+        ("attr_tint",      gl::FLOAT_VEC4,   2),
+        ("attr_pivot",     gl::FLOAT_VEC2,   3),
+        ("attr_rotation",  gl::FLOAT,        4),
+        ("attr_layer",     gl::FLOAT,        5),
+        ("attr_flags",     gl::UNSIGNED_INT, 6),
+        ("attr_uv",        gl::FLOAT_VEC4,   7),
+    ],
+};
+
+static LINE_INTERFACE: shader::Interface = shader::Interface {
+    uniforms: &[],
+    blocks: &[
+        ("Camera", CAMERA_BINDING),
+    ],
+    attributes: &[
+        ("attr_vert",   gl::FLOAT_VEC2, 0),
+        ("attr_colour", gl::FLOAT_VEC4, 1),
+    ],
+};
+
+enum Prog {
+    Embedded(shader::Program),
+    Hot(shader::HotProgram),
+}
+
+impl Prog {
+    fn embedded(vert: &str, frag: &str, interface: &shader::Interface, cache: &ProgramCache)
+        -> Result<Prog, Box<dyn Error>>
+    {
+        let library = shader::Library::Embedded(shader_src::EMBEDDED);
+        let stages = [(shader::Stage::Vertex, vert), (shader::Stage::Fragment, frag)];
+        let (program, _) = shader::build(&library, &stages, DEFINES, interface, Some(cache))?;
+        Ok(Prog::Embedded(program))
+    }
+
+    fn hot(dir: &Path, vert: &str, frag: &str, interface: &shader::Interface)
+        -> Result<Prog, Box<dyn Error>>
+    {
+        let stages = [(shader::Stage::Vertex, vert), (shader::Stage::Fragment, frag)];
+        Ok(Prog::Hot(shader::HotProgram::load(dir, &stages, DEFINES, interface)?))
+    }
+
+    fn reload_changed(&mut self) {
+        if let Prog::Hot(program) = self {
+            program.reload_changed();
+        }
+    }
+
+    fn program(&self) -> &shader::Program {
+        match self {
+            Prog::Embedded(program) => program,
+            Prog::Hot(program)      => program.program(),
+        }
+    }
+}
+
+// where linked embedded programs are kept between runs
+const PROGRAM_CACHE_DIR: &str = "shader-cache";
+
+// Fragments more transparent than this are discarded rather than blended,
+// so they don't hide what's behind them in the depth buffer.
+const ALPHA_CUTOFF: &str = "0.05";
+
+// shader defines shared by every program
+static DEFINES: &[(&str, &str)] = &[
+    ("ALPHA_CUTOFF", ALPHA_CUTOFF),
+];
+
+// starting size of the stream buffer; it grows to fit busy frames
+const STREAM_CAPACITY: usize = 1 << 20;
+
+// Instances in a buffer of their own.
+pub struct GlStatic {
+    buf:   Buffer,
+    count: usize,
+}
+
+pub struct GlBackend {
+    sprite_prog: Prog,
+    line_prog:   Prog,
+
+    sprite_vao: VertexArray,
+    line_vao:   VertexArray,
+
+    // per-frame sprite instances and line vertices
+    stream: StreamBuffer,
+
+    // the camera block: one std140 mat4
+    camera_ubo: Buffer,
+}
+
+impl GlBackend {
+    // Uses the shaders built into the executable.
+    pub fn new() -> Result<GlBackend, Box<dyn Error>> {
+        use self::shader_src::*;
+        let cache = ProgramCache::new(PROGRAM_CACHE_DIR);
+        let sprite_prog = Prog::embedded(SPRITE_V, SPRITE_F, &SPRITE_INTERFACE, &cache)?;
+        let line_prog   = Prog::embedded(LINE_V,   LINE_F,   &LINE_INTERFACE,   &cache)?;
+        GlBackend::with_programs(sprite_prog, line_prog)
+    }
+
+    // Reads the shaders from `dir` and rebuilds them whenever they're edited.
+    pub fn with_shader_dir(dir: &Path) -> Result<GlBackend, Box<dyn Error>> {
+        let sprite_prog = Prog::hot(dir, shader_src::SPRITE_V, shader_src::SPRITE_F, &SPRITE_INTERFACE)?;
+        let line_prog   = Prog::hot(dir, shader_src::LINE_V,   shader_src::LINE_F,   &LINE_INTERFACE)?;
+        GlBackend::with_programs(sprite_prog, line_prog)
+    }
+
+    fn with_programs(sprite_prog: Prog, line_prog: Prog) -> Result<GlBackend, Box<dyn Error>> {
+        unsafe {
+            gl::ClearColor(0.1, 0.0, 0.1, 1.0);
+            gl::Disable(gl::CULL_FACE);
+            gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
+            gl::Enable(gl::BLEND);
+            // equal depths draw in submission order
+            gl::DepthFunc(gl::LEQUAL);
+            gl::Enable(gl::DEPTH_TEST);
+            gl::LineWidth(2.0);
+        }
+
+        let sprite_vao = VertexArray::new();
+        unsafe {
+            let vao = sprite_vao.handle();
+
+            gl::EnableVertexArrayAttrib(vao, 0);
+            gl::VertexArrayAttribFormat(vao, 0, 4, gl::FLOAT, gl::FALSE, 0);
+            gl::VertexArrayAttribBinding(vao, 0, 0);
+
+            gl::EnableVertexArrayAttrib(vao, 1);
+            gl::VertexArrayAttribIFormat(vao, 1, 1, gl::UNSIGNED_INT, 16);
+            gl::VertexArrayAttribBinding(vao, 1, 0);
+
+            gl::EnableVertexArrayAttrib(vao, 2);
+            gl::VertexArrayAttribFormat(vao, 2, 4, gl::UNSIGNED_BYTE, gl::TRUE, 20);
+            gl::VertexArrayAttribBinding(vao, 2, 0);
+
+            gl::EnableVertexArrayAttrib(vao, 3);
+            gl::VertexArrayAttribFormat(vao, 3, 2, gl::FLOAT, gl::FALSE, 24);
+            gl::VertexArrayAttribBinding(vao, 3, 0);
+
+            gl::EnableVertexArrayAttrib(vao, 4);
+            gl::VertexArrayAttribFormat(vao, 4, 1, gl::FLOAT, gl::FALSE, 32);
+            gl::VertexArrayAttribBinding(vao, 4, 0);
+
+            gl::EnableVertexArrayAttrib(vao, 5);
+            gl::VertexArrayAttribFormat(vao, 5, 1, gl::FLOAT, gl::FALSE, 36);
+            gl::VertexArrayAttribBinding(vao, 5, 0);
+
+            gl::EnableVertexArrayAttrib(vao, 6);
+            gl::VertexArrayAttribIFormat(vao, 6, 1, gl::UNSIGNED_INT, 40);
+            gl::VertexArrayAttribBinding(vao, 6, 0);
+
+            gl::EnableVertexArrayAttrib(vao, 7);
+            gl::VertexArrayAttribFormat(vao, 7, 4, gl::FLOAT, gl::FALSE, 44);
+            gl::VertexArrayAttribBinding(vao, 7, 0);
+
+            gl::VertexArrayBindingDivisor(vao, 0, 1);
+        }
+
+        let line_vao = VertexArray::new();
+        unsafe {
+            let vao = line_vao.handle();
+
+            gl::EnableVertexArrayAttrib(vao, 0);
+            gl::VertexArrayAttribFormat(vao, 0, 2, gl::FLOAT, gl::FALSE, 0);
+            gl::VertexArrayAttribBinding(vao, 0, 0);
+
+            gl::EnableVertexArrayAttrib(vao, 1);
+            gl::VertexArrayAttribFormat(vao, 1, 4, gl::UNSIGNED_BYTE, gl::TRUE, 8);
+            gl::VertexArrayAttribBinding(vao, 1, 0);
+        }
+
+        let camera_ubo = Buffer::new();
+        unsafe {
+            gl::NamedBufferStorage(
+                camera_ubo.handle(),
+                mem::size_of::<Matrix4<f32>>() as isize,
+                ptr::null(),
+                gl::DYNAMIC_STORAGE_BIT
+            );
+            gl::BindBufferBase(gl::UNIFORM_BUFFER, CAMERA_BINDING, camera_ubo.handle());
+        }
+
+        Ok(GlBackend {
+            sprite_prog,
+            line_prog,
+            sprite_vao,
+            line_vao,
+            stream: StreamBuffer::new(STREAM_CAPACITY),
+            camera_ubo,
+        })
+    }
+}
+
+impl Backend for GlBackend {
+    fn begin(&mut self, camera: &Camera, screen_dims: V2) {
+        unsafe {
+            gl::Viewport(0, 0, screen_dims.x as i32, screen_dims.y as i32);
+            gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+        }
+
+        self.sprite_prog.reload_changed();
+        self.line_prog.reload_changed();
+
+        self.stream.begin_frame();
+
+        // a mat4 in std140 is four vec4 columns, as nalgebra stores it
+        let view_projection = camera.view_projection(screen_dims);
+        unsafe {
+            gl::NamedBufferSubData(
+                self.camera_ubo.handle(), 0,
+                mem::size_of::<Matrix4<f32>>() as isize,
+                view_projection.as_slice().as_ptr() as *const _
+            );
+        }
+    }
+
+    // Batches larger than the stream buffer are drawn in several parts.
+    fn draw_sprites(&mut self, texture: GLuint, sprites: &[Sprite]) {
+        if sprites.is_empty() {
+            return;
+        }
+
+        unsafe {
+            self.sprite_prog.program().bind();
+            gl::BindVertexArray(self.sprite_vao.handle());
+            gl::BindTextureUnit(0, texture);
+        }
+
+        for part in sprites.chunks(self.stream.max_items::<Sprite>()) {
+            let offset = match self.stream.write(part) {
+                Ok(offset) => offset,
+                Err(e) => {
+                    eprintln!("skipping {} sprites: {}", sprites.len(), e);
+                    return;
+                }
+            };
+            unsafe {
+                gl::VertexArrayVertexBuffer(
+                    self.sprite_vao.handle(), 0,
+                    self.stream.buffer(), offset as isize, mem::size_of::<Sprite>() as i32
+                );
+                gl::DrawArraysInstanced(gl::TRIANGLE_FAN, 0, 4, part.len() as GLsizei);
+            }
+            self.stream.fence();
+        }
+    }
+
+    fn draw_translucent(&mut self, texture: GLuint, sprites: &[Sprite]) {
+        unsafe { gl::DepthMask(gl::FALSE); }
+        self.draw_sprites(texture, sprites);
+        unsafe { gl::DepthMask(gl::TRUE); }
+    }
+
+    fn draw_lines(&mut self, verts: &[LineVert]) {
+        if verts.is_empty() {
+            return;
+        }
+
+        unsafe {
+            self.line_prog.program().bind();
+            gl::BindVertexArray(self.line_vao.handle());
+        }
+
+        // parts hold whole lines
+        let part_len = self.stream.max_items::<LineVert>() & !1;
+        for part in verts.chunks(part_len) {
+            let offset = match self.stream.write(part) {
+                Ok(offset) => offset,
+                Err(e) => {
+                    eprintln!("skipping {} lines: {}", verts.len() / 2, e);
+                    return;
+                }
+            };
+            unsafe {
+                gl::VertexArrayVertexBuffer(
+                    self.line_vao.handle(), 0,
+                    self.stream.buffer(), offset as isize, mem::size_of::<LineVert>() as i32
+                );
+                gl::DrawArrays(gl::LINES, 0, part.len() as i32);
+            }
+            self.stream.fence();
+        }
+    }
+
+    fn end(&mut self) {
+    }
+
+    type Static = GlStatic;
+
+    fn create_static(&mut self, sprites: &[Sprite]) -> GlStatic {
+        // storage can't be empty, so an empty batch gets room for one
+        // sprite, left uninitialised; an empty slice's pointer is dangling
+        let data = if sprites.is_empty() { ptr::null() } else { sprites.as_ptr() as *const _ };
+        let buf = Buffer::new();
+        unsafe {
+            gl::NamedBufferStorage(
+                buf.handle(),
+                (sprites.len().max(1) * mem::size_of::<Sprite>()) as isize,
+                data,
+                0
+            );
+        }
+        GlStatic { buf, count: sprites.len() }
+    }
+
+    fn draw_static(&mut self, texture: GLuint, batch: &GlStatic) {
+        if batch.count == 0 {
+            return;
+        }
+
+        unsafe {
+            self.sprite_prog.program().bind();
+
+            gl::VertexArrayVertexBuffer(
+                self.sprite_vao.handle(), 0,
+                batch.buf.handle(), 0, mem::size_of::<Sprite>() as i32
+            );
+            gl::BindVertexArray(self.sprite_vao.handle());
+            gl::BindTextureUnit(0, texture);
+            gl::DrawArraysInstanced(gl::TRIANGLE_FAN, 0, 4, batch.count as GLsizei);
+        }
+    }
+}
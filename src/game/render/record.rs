@@ -0,0 +1,130 @@
+
+// A backend that draws nothing and keeps what it was given, for checking
+// what frames would draw without a GPU.
+
+use {
+    super::{Backend, Sprite, LineVert},
+    crate::{
+        alg::V2,
+        game::Camera,
+    },
+    gl::types::*,
+};
+
+#[derive(Clone, Debug)]
+pub enum Draw {
+    Sprites { texture: GLuint, sprites: Vec<Sprite> },
+    Translucent { texture: GLuint, sprites: Vec<Sprite> },
+    Static  { texture: GLuint, sprites: Vec<Sprite> },
+    Lines(Vec<LineVert>),
+}
+
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub camera:      Camera,
+    pub screen_dims: V2,
+    // in the order the backend received them
+    pub draws:       Vec<Draw>,
+}
+
+impl Frame {
+    pub fn sprite_batches(&self) -> impl Iterator<Item = (GLuint, &[Sprite])> + '_ {
+        self.draws.iter().filter_map(|draw| match draw {
+            Draw::Sprites { texture, sprites } => Some((*texture, &sprites[..])),
+            _                                  => None,
+        })
+    }
+
+    pub fn translucent_batches(&self) -> impl Iterator<Item = (GLuint, &[Sprite])> + '_ {
+        self.draws.iter().filter_map(|draw| match draw {
+            Draw::Translucent { texture, sprites } => Some((*texture, &sprites[..])),
+            _                                      => None,
+        })
+    }
+
+    // Sprites drawn outside of static batches, opaque or not.
+    pub fn sprite_count(&self) -> usize {
+        self.sprite_batches()
+            .chain(self.translucent_batches())
+            .map(|(_, sprites)| sprites.len())
+            .sum()
+    }
+
+    pub fn line_verts(&self) -> impl Iterator<Item = &LineVert> + '_ {
+        self.draws.iter()
+            .filter_map(|draw| match draw {
+                Draw::Lines(verts) => Some(verts),
+                _                  => None,
+            })
+            .flatten()
+    }
+}
+
+#[derive(Default)]
+pub struct Recorder {
+    frames:         Vec<Frame>,
+    static_uploads: usize,
+}
+
+impl Recorder {
+    pub fn new() -> Recorder {
+        Recorder::default()
+    }
+
+    // Completed frames, oldest first.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn last_frame(&self) -> Option<&Frame> {
+        self.frames.last()
+    }
+
+    // Static batches created so far.
+    pub fn static_uploads(&self) -> usize {
+        self.static_uploads
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    fn current(&mut self) -> &mut Frame {
+        self.frames.last_mut().expect("draw outside of a frame")
+    }
+}
+
+impl Backend for Recorder {
+    fn begin(&mut self, camera: &Camera, screen_dims: V2) {
+        self.frames.push(Frame { camera: *camera, screen_dims, draws: Vec::new() });
+    }
+
+    fn draw_sprites(&mut self, texture: GLuint, sprites: &[Sprite]) {
+        let sprites = sprites.to_vec();
+        self.current().draws.push(Draw::Sprites { texture, sprites });
+    }
+
+    fn draw_translucent(&mut self, texture: GLuint, sprites: &[Sprite]) {
+        let sprites = sprites.to_vec();
+        self.current().draws.push(Draw::Translucent { texture, sprites });
+    }
+
+    fn draw_lines(&mut self, verts: &[LineVert]) {
+        self.current().draws.push(Draw::Lines(verts.to_vec()));
+    }
+
+    fn end(&mut self) {
+    }
+
+    type Static = Vec<Sprite>;
+
+    fn create_static(&mut self, sprites: &[Sprite]) -> Vec<Sprite> {
+        self.static_uploads += 1;
+        sprites.to_vec()
+    }
+
+    fn draw_static(&mut self, texture: GLuint, batch: &Vec<Sprite>) {
+        let sprites = batch.clone();
+        self.current().draws.push(Draw::Static { texture, sprites });
+    }
+}
@@ -0,0 +1,255 @@
+
+// Sprite sheets packed by TexturePacker or Aseprite, with frames of any size
+// picked out of one texture by their texture coordinates.
+
+use {
+    super::{Rect, Sprite},
+    crate::{
+        alg::{P2, V2},
+        gfx::{Assets, TextureHandle},
+    },
+    serde::Deserialize,
+    std::{
+        collections::HashMap,
+        error::Error,
+        fs::File,
+        io::BufReader,
+        path::{Path, PathBuf},
+    },
+    gl::types::*,
+};
+
+// on-disk layout of a sheet exported as a JSON hash
+#[derive(Deserialize)]
+struct SheetFile {
+    frames: HashMap<String, FrameEntry>,
+    meta:   Meta,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FrameEntry {
+    frame:              PixelRect,
+    #[serde(default)]
+    rotated:            bool,
+    // where the trimmed frame sat in the untrimmed one
+    sprite_source_size: Option<PixelRect>,
+    source_size:        Option<Size>,
+    // Aseprite only, in milliseconds
+    duration:           Option<u32>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+struct PixelRect {
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+struct Size {
+    w: u32,
+    h: u32,
+}
+
+#[derive(Deserialize)]
+struct Meta {
+    image: String,
+    size:  Size,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SheetFrame {
+    pub uv:       Rect,
+    // in pixels, of the trimmed frame
+    pub size:     V2,
+    // from the untrimmed frame's bottom-left corner to the trimmed one's
+    pub offset:   V2,
+    pub duration: Option<u32>,
+}
+
+impl SheetFrame {
+    // The frame at one world unit per pixel, placed as if it hadn't been
+    // trimmed with its bottom-left corner at `origin`.
+    pub fn sprite(&self, origin: P2) -> Sprite {
+        let min = origin + self.offset;
+        let max = min + self.size;
+        let rect = Rect::new(min.x, min.y, max.x, max.y);
+        Sprite { uv: self.uv, ..Sprite::new(rect, 0) }
+    }
+}
+
+pub struct SpriteSheet {
+    path:    PathBuf,
+    texture: TextureHandle,
+    frames:  HashMap<String, SheetFrame>,
+}
+
+impl SpriteSheet {
+    // Reads a sheet's JSON and loads the image it names, which is relative
+    // to the JSON file.
+    pub fn load(path: impl AsRef<Path>, assets: &mut Assets)
+        -> Result<SpriteSheet, Box<dyn Error>>
+    {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        let sheet: SheetFile = serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        let image_path = path.parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(&sheet.meta.image);
+        let texture = assets.sheet(&image_path)?;
+
+        let (width, height) = (sheet.meta.size.w as f32, sheet.meta.size.h as f32);
+        let mut frames = HashMap::with_capacity(sheet.frames.len());
+        for (name, entry) in sheet.frames {
+            if entry.rotated {
+                return Err(format!("{}: frame {} is rotated, which isn't supported",
+                    path.display(), name).into());
+            }
+
+            let PixelRect { x, y, w, h } = entry.frame;
+            let uv = Rect::new(
+                x as f32 / width,
+                (y + h) as f32 / height,
+                (x + w) as f32 / width,
+                y as f32 / height,
+            );
+
+            // sheets are y-down, sprites y-up
+            let offset = match (entry.sprite_source_size, entry.source_size) {
+                (Some(trimmed), Some(source)) => V2::new(
+                    trimmed.x as f32,
+                    source.h as f32 - (trimmed.y + trimmed.h) as f32,
+                ),
+                _ => V2::new(0.0, 0.0),
+            };
+
+            let size = V2::new(w as f32, h as f32);
+            frames.insert(name, SheetFrame { uv, size, offset, duration: entry.duration });
+        }
+
+        Ok(SpriteSheet { path: path.to_owned(), texture, frames })
+    }
+
+    pub fn texture(&self) -> GLuint {
+        self.texture.handle()
+    }
+
+    pub fn frame(&self, name: &str) -> Option<&SheetFrame> {
+        self.frames.get(name)
+    }
+
+    // Like `frame`, for frames the game can't do without.
+    pub fn require(&self, name: &str) -> Result<&SheetFrame, Box<dyn Error>> {
+        self.frame(name)
+            .ok_or_else(|| format!("{} has no frame {}", self.path.display(), name).into())
+    }
+
+    pub fn frames(&self) -> impl Iterator<Item = (&str, &SheetFrame)> {
+        self.frames.iter().map(|(name, frame)| (name.as_str(), frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            game::map::tests::temp_dir,
+            gfx::assets::tests::fake_gl,
+        },
+        std::fs,
+    };
+
+    // A 64x32 sheet over the repo's player image: "idle" fills the top-left
+    // cell, and "jump" was trimmed 3 pixels in from the left and 1 down from
+    // the top of a 16x16 frame.
+    fn write_sheet(dir: &Path, jump_rotated: bool) -> PathBuf {
+        let image = std::env::current_dir().unwrap().join("player.png");
+        let path = dir.join("sheet.json");
+        fs::write(&path, format!(r#"{{
+            "frames": {{
+                "idle": {{ "frame": {{ "x": 0, "y": 0, "w": 16, "h": 16 }} }},
+                "jump": {{
+                    "frame": {{ "x": 20, "y": 8, "w": 10, "h": 12 }},
+                    "rotated": {rotated},
+                    "trimmed": true,
+                    "spriteSourceSize": {{ "x": 3, "y": 1, "w": 10, "h": 12 }},
+                    "sourceSize": {{ "w": 16, "h": 16 }},
+                    "duration": 100
+                }}
+            }},
+            "meta": {{ "image": "{image}", "size": {{ "w": 64, "h": 32 }} }}
+        }}"#, rotated = jump_rotated, image = image.display())).unwrap();
+        path
+    }
+
+    fn load(name: &str) -> SpriteSheet {
+        fake_gl();
+        let path = write_sheet(&temp_dir(name), false);
+        SpriteSheet::load(path, &mut Assets::new()).unwrap()
+    }
+
+    #[test]
+    fn reads_frames() {
+        let sheet = load("sheet-read");
+        let mut names: Vec<&str> = sheet.frames().map(|(name, _)| name).collect();
+        names.sort_unstable();
+        assert_eq!(names, ["idle", "jump"]);
+
+        let idle = sheet.frame("idle").unwrap();
+        assert_eq!(idle.size, V2::new(16.0, 16.0));
+        assert_eq!(idle.offset, V2::new(0.0, 0.0));
+        assert_eq!(idle.duration, None);
+
+        // the 12 pixel frame ends 3 pixels above the untrimmed frame's bottom
+        let jump = sheet.frame("jump").unwrap();
+        assert_eq!(jump.size, V2::new(10.0, 12.0));
+        assert_eq!(jump.offset, V2::new(3.0, 3.0));
+        assert_eq!(jump.duration, Some(100));
+
+        let sprite = jump.sprite(P2::new(100.0, 50.0));
+        assert_eq!(sprite.rect, Rect::new(103.0, 53.0, 113.0, 65.0));
+        assert_eq!(sprite.uv, jump.uv);
+    }
+
+    // UVs sit on texel edges with v flipped, so sampling the centre of each
+    // of the sprite's pixels, bottom up as the shader does, lands on the
+    // centre of the texel the sheet puts there, rows top down.
+    #[test]
+    fn samples_texel_centres() {
+        let sheet = load("sheet-uv");
+        let frame = sheet.frame("jump").unwrap();
+        let uv = frame.uv;
+        let (w, h) = (10, 12);
+        for j in 0 .. h {
+            for i in 0 .. w {
+                let (fx, fy) = ((i as f32 + 0.5) / w as f32, (j as f32 + 0.5) / h as f32);
+                let u = (uv.left + (uv.right - uv.left) * fx) * 64.0;
+                let v = (uv.bottom + (uv.top - uv.bottom) * fy) * 32.0;
+                assert!((u.fract() - 0.5).abs() < 1e-3 && (v.fract() - 0.5).abs() < 1e-3,
+                    "pixel ({}, {}) samples ({}, {})", i, j, u, v);
+                assert_eq!((u as i32, v as i32), (20 + i, 8 + h - 1 - j));
+            }
+        }
+    }
+
+    #[test]
+    fn names_missing_frames() {
+        let sheet = load("sheet-missing");
+        assert!(sheet.require("idle").is_ok());
+        let error = sheet.require("run").unwrap_err().to_string();
+        assert!(error.ends_with("sheet.json has no frame run"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_rotated_frames() {
+        fake_gl();
+        let path = write_sheet(&temp_dir("sheet-rotated"), true);
+        let error = SpriteSheet::load(path, &mut Assets::new()).err().unwrap().to_string();
+        assert!(error.ends_with("frame jump is rotated, which isn't supported"), "{}", error);
+    }
+}
@@ -0,0 +1,445 @@
+
+use {
+    super::{
+        map::{Map, Tileset, Tile, TILE_SIZE, outline::Edge},
+        IntRect,
+    },
+    crate::{
+        alg::{V2, P2},
+        damage,
+        gfx::Assets,
+    },
+    serde::Deserialize,
+    std::{
+        error::Error,
+        fs::File,
+        io::BufReader,
+        path::{Path, PathBuf},
+    },
+};
+
+#[derive(Debug)]
+pub enum LoadWorldError {
+    MisalignedMap(PathBuf),
+    // the map's size in pixels isn't the one the world file gives it
+    SizeMismatch { path: PathBuf, expected: (i32, i32), found: (i32, i32) },
+    Nested(Box<dyn Error>),
+}
+
+impl LoadWorldError {
+    fn nest(inner: impl Into<Box<dyn Error>>) -> LoadWorldError {
+        LoadWorldError::Nested(inner.into())
+    }
+}
+
+impl std::fmt::Display for LoadWorldError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Error for LoadWorldError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LoadWorldError::Nested(e) => Some(e.as_ref() as _),
+            _                         => None
+        }
+    }
+}
+
+// on-disk layout of a Tiled .world file
+#[derive(Deserialize)]
+struct WorldFile {
+    maps:     Vec<WorldFileMap>,
+    #[serde(default)]
+    patterns: Vec<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WorldFileMap {
+    file_name: String,
+    x:         i32,
+    y:         i32,
+    width:     i32,
+    height:    i32,
+}
+
+struct Room {
+    path:   PathBuf,
+    // global tile coordinates of the map's tile (0, 0)
+    origin: (i32, i32),
+    bounds: IntRect,
+    // size in pixels according to the world file, checked against the map
+    // when it's loaded
+    size:   Option<(i32, i32)>,
+    map:    Option<Map>,
+}
+
+impl Room {
+    // Without `assets` the map is loaded headless.
+    fn load(&self, assets: Option<&mut Assets>) -> Result<Map, LoadWorldError> {
+        let map = match assets {
+            Some(assets) => Map::load(&self.path, assets),
+            None         => Map::load_headless(&self.path),
+        };
+        let map = map.map_err(LoadWorldError::nest)?;
+
+        if let (Some(expected), Some((width, height))) = (self.size, map.pixel_size()) {
+            let found = (width as i32, height as i32);
+            if found != expected {
+                let path = self.path.clone();
+                return Err(LoadWorldError::SizeMismatch { path, expected, found });
+            }
+        }
+
+        Ok(map)
+    }
+}
+
+// A set of maps placed at fixed offsets in one global tile space. Global
+// coordinates are y-up like `Map`'s, so a room placed at pixel offset (x, y)
+// in Tiled (y-down) has its bottom-left tile at (x / 16, -y / 16 - rows).
+pub struct World {
+    rooms: Vec<Room>,
+}
+
+impl World {
+    pub fn load(path: impl AsRef<Path>) -> Result<World, LoadWorldError> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .map_err(LoadWorldError::nest)?;
+        let world: WorldFile = serde_json::from_reader(BufReader::new(file))
+            .map_err(LoadWorldError::nest)?;
+
+        if !world.patterns.is_empty() {
+            eprintln!(
+                "{}: map patterns are not supported, ignoring",
+                path.display()
+            );
+        }
+
+        let base_dir = path.parent().unwrap_or(Path::new(""));
+
+        let rooms = world.maps.into_iter()
+            .map(|entry| {
+                let map_path = base_dir.join(&entry.file_name);
+
+                let aligned = [entry.x, entry.y, entry.width, entry.height]
+                    .iter()
+                    .all(|v| v % TILE_SIZE == 0);
+                if !aligned {
+                    return Err(LoadWorldError::MisalignedMap(map_path));
+                }
+
+                let left   =   entry.x / TILE_SIZE;
+                let top    = -(entry.y / TILE_SIZE);
+                let right  = left + entry.width  / TILE_SIZE;
+                let bottom = top  - entry.height / TILE_SIZE;
+
+                let bounds = IntRect { left, bottom, right, top };
+                Ok(Room {
+                    path:   map_path,
+                    origin: (left, bottom),
+                    bounds,
+                    size:   Some((entry.width, entry.height)),
+                    map:    None,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(World { rooms })
+    }
+
+    // A world of one map with its tile (0, 0) at the global origin. The room
+    // covers all of space, so it's never streamed out.
+    pub fn single(path: impl AsRef<Path>) -> World {
+        const HALF: i32 = i32::MAX / 2;
+        let room = Room {
+            path:   path.as_ref().to_owned(),
+            origin: (0, 0),
+            bounds: IntRect { left: -HALF, bottom: -HALF, right: HALF, top: HALF },
+            size:   None,
+            map:    None,
+        };
+        World { rooms: vec![room] }
+    }
+
+    // Loads rooms within `margin` tiles of `view` and drops rooms further
+    // than twice that, so rooms near the edge don't thrash. Rooms sharing a
+    // tileset share its texture through `assets`.
+    pub fn stream(&mut self, view: &IntRect, margin: i32, assets: &mut Assets)
+        -> Result<(), LoadWorldError>
+    {
+        self.stream_with(view, margin, Some(assets))
+    }
+
+    // Like `stream`, but loads maps without touching GL, for tools and
+    // tests.
+    pub fn stream_headless(&mut self, view: &IntRect, margin: i32) -> Result<(), LoadWorldError> {
+        self.stream_with(view, margin, None)
+    }
+
+    fn stream_with(&mut self, view: &IntRect, margin: i32, mut assets: Option<&mut Assets>)
+        -> Result<(), LoadWorldError>
+    {
+        let load_area = view.expand(margin);
+        let keep_area = view.expand(margin * 2);
+
+        for room in &mut self.rooms {
+            if room.map.is_none() && room.bounds.overlaps(&load_area) {
+                room.map = Some(room.load(assets.as_deref_mut())?);
+            }
+            else if room.map.is_some() && !room.bounds.overlaps(&keep_area) {
+                room.map = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Reloads every loaded room whose files are in `changed`, returning how
+    // each went by the room's map path. A room that fails to load is left
+    // as it was, and the others are reloaded regardless.
+    pub fn reload(&mut self, changed: &[PathBuf], assets: &mut Assets)
+        -> Vec<(PathBuf, Result<(), LoadWorldError>)>
+    {
+        self.reload_with(changed, Some(assets))
+    }
+
+    fn reload_with(&mut self, changed: &[PathBuf], mut assets: Option<&mut Assets>)
+        -> Vec<(PathBuf, Result<(), LoadWorldError>)>
+    {
+        let mut results = Vec::new();
+        for room in &mut self.rooms {
+            let stale = room.map.as_ref().is_some_and(|map| {
+                map.source_paths().iter().any(|path| changed.contains(path))
+            });
+            if !stale {
+                continue;
+            }
+
+            let result = room.load(assets.as_deref_mut())
+                .map(|map| room.map = Some(map));
+            results.push((room.path.clone(), result));
+        }
+        results
+    }
+
+    // Every file the loaded rooms were built from.
+    pub fn source_paths(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = self.rooms.iter()
+            .filter_map(|room| room.map.as_ref())
+            .flat_map(|map| map.source_paths())
+            .collect();
+        paths.sort();
+        paths.dedup();
+        paths
+    }
+
+    // Loaded rooms, each with its index among all the world's rooms and the
+    // global tile coordinates of its map's tile (0, 0).
+    pub fn rooms(&self) -> impl Iterator<Item = (usize, (i32, i32), &Map)> {
+        self.rooms.iter()
+            .enumerate()
+            .filter_map(|(index, room)| {
+                room.map.as_ref().map(|map| (index, room.origin, map))
+            })
+    }
+
+    // The loaded room containing global tile (x, y), with (x, y) in its
+    // map's coordinates.
+    fn room_at(&self, x: i32, y: i32) -> Option<(&Map, i32, i32)> {
+        self.rooms.iter()
+            .filter(|room| room.bounds.contains(x, y))
+            .filter_map(|room| {
+                let (ox, oy) = room.origin;
+                room.map.as_ref().map(|map| (map, x - ox, y - oy))
+            })
+            .next()
+    }
+
+    fn room_at_mut(&mut self, x: i32, y: i32) -> Option<(&mut Map, i32, i32)> {
+        self.rooms.iter_mut()
+            .filter(|room| room.bounds.contains(x, y))
+            .filter_map(|room| {
+                let (ox, oy) = room.origin;
+                room.map.as_mut().map(|map| (map, x - ox, y - oy))
+            })
+            .next()
+    }
+
+    pub fn tile_at(&self, x: i32, y: i32) -> Option<(&Tileset, &Tile, u32)> {
+        self.room_at(x, y)
+            .and_then(|(map, x, y)| map.tile_at(x, y))
+    }
+
+    // Solid edges crossing the cell at global tile coordinates (x, y), in
+    // global pixels.
+    pub fn collider_at(&self, x: i32, y: i32) -> Vec<Edge> {
+        let tile_size = TILE_SIZE as f32;
+        let min = P2::new(x as f32 * tile_size, y as f32 * tile_size);
+        self.colliders_in(min, min + V2::new(tile_size, tile_size))
+    }
+
+    // The tile editing functions of Map at global tile coordinates. Tiles
+    // in rooms that aren't loaded can't be changed.
+    pub fn set_tile(&mut self, x: i32, y: i32, index: u32) -> bool {
+        self.room_at_mut(x, y)
+            .is_some_and(|(map, x, y)| map.set_tile(x, y, index))
+    }
+
+    pub fn paint(&mut self, x: i32, y: i32) -> bool {
+        self.room_at_mut(x, y)
+            .is_some_and(|(map, x, y)| map.paint(x, y))
+    }
+
+    pub fn damage_tile(&mut self, x: i32, y: i32, damage: damage::Values) -> bool {
+        self.room_at_mut(x, y)
+            .is_some_and(|(map, x, y)| map.damage_tile(x, y, damage))
+    }
+
+    // Edges of the merged tile colliders overlapping the box from `min` to
+    // `max`, in global pixels.
+    pub fn colliders_in(&self, min: P2, max: P2) -> Vec<Edge> {
+        let tile_size = TILE_SIZE as f32;
+        let mut edges = Vec::new();
+
+        for (_, (ox, oy), map) in self.rooms() {
+            let offset = V2::new(ox as f32 * tile_size, oy as f32 * tile_size);
+            let local = map.colliders_in(min - offset, max - offset);
+            edges.extend(local.map(|edge| Edge {
+                a: edge.a + offset,
+                b: edge.b + offset,
+                ..*edge
+            }));
+        }
+
+        edges
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::game::map::tests::{temp_dir, write_map},
+        std::fs,
+    };
+
+    // Two 4x2 rooms, "a" at the world's origin and "b" `gap` tiles to its
+    // right, with their tops level.
+    fn two_rooms(dir: &Path, gap: i32) -> World {
+        write_map(&dir.join("a.tmx"), &[&[1, 0, 0, 0], &[1, 1, 1, 1]]);
+        write_map(&dir.join("b.tmx"), &[&[0, 0, 0, 1], &[1, 1, 1, 1]]);
+        let b_x = (4 + gap) * TILE_SIZE;
+        fs::write(dir.join("two.world"), format!(r#"{{
+    "maps": [
+        {{ "fileName": "a.tmx", "x": 0,  "y": 0, "width": 64, "height": 32 }},
+        {{ "fileName": "b.tmx", "x": {}, "y": 0, "width": 64, "height": 32 }}
+    ],
+    "type": "world"
+}}"#, b_x)).unwrap();
+        World::load(dir.join("two.world")).unwrap()
+    }
+
+    fn everything() -> IntRect {
+        IntRect { left: -1000, bottom: -1000, right: 1000, top: 1000 }
+    }
+
+    fn loaded(world: &World) -> Vec<usize> {
+        world.rooms().map(|(index, _, _)| index).collect()
+    }
+
+    #[test]
+    fn maps_global_tiles_to_rooms() {
+        let dir = temp_dir("coords");
+        let mut world = two_rooms(&dir, 2);
+        world.stream_headless(&everything(), 0).unwrap();
+
+        // rooms hang down from y = 0, so their bottom rows are at -2
+        let origins: Vec<(i32, i32)> = world.rooms().map(|(_, origin, _)| origin).collect();
+        assert_eq!(origins, vec![(0, -2), (6, -2)]);
+
+        let (map, x, y) = world.room_at(9, -1).unwrap();
+        assert_eq!((x, y), (3, 1));
+        assert!(map.tile_at(x, y).is_some());
+
+        assert!(world.tile_at(0, -1).is_some());
+        assert!(world.tile_at(1, -1).is_none());
+        assert!(world.tile_at(8, -1).is_none());
+        assert!(world.tile_at(9, -1).is_some());
+        // the gap between the rooms
+        assert!(world.room_at(4, -2).is_none());
+        assert!(world.tile_at(5, -2).is_none());
+    }
+
+    #[test]
+    fn offsets_edges_to_global_pixels() {
+        let dir = temp_dir("edges");
+        let mut world = two_rooms(&dir, 2);
+        world.stream_headless(&everything(), 0).unwrap();
+
+        let tile = TILE_SIZE as f32;
+        // the top of b's floor, under its empty cells
+        let probe = P2::new(7.5 * tile, -tile);
+        let floors: Vec<Edge> = world.colliders_in(probe, probe).into_iter()
+            .filter(|edge| edge.normal.y > 0.5)
+            .collect();
+        assert_eq!(floors.len(), 1);
+        assert_eq!(floors[0].a.y, -tile);
+        assert!(floors[0].a.x.min(floors[0].b.x) <= 6.0 * tile);
+
+        let cell = world.collider_at(9, -1);
+        assert!(cell.iter().any(|edge| edge.normal.y > 0.5 && edge.a.y == 0.0));
+    }
+
+    #[test]
+    fn streams_rooms_in_and_out() {
+        let dir = temp_dir("stream");
+        let mut world = two_rooms(&dir, 100);
+        assert!(loaded(&world).is_empty());
+
+        let near_a = IntRect { left: 0, bottom: -2, right: 2, top: 0 };
+        world.stream_headless(&near_a, 8).unwrap();
+        assert_eq!(loaded(&world), vec![0]);
+
+        let near_b = IntRect { left: 104, bottom: -2, right: 106, top: 0 };
+        world.stream_headless(&near_b, 8).unwrap();
+        assert_eq!(loaded(&world), vec![1]);
+
+        // b stays loaded until the view is well clear of it
+        let just_left_of_b = IntRect { left: 90, bottom: -2, right: 92, top: 0 };
+        world.stream_headless(&just_left_of_b, 8).unwrap();
+        assert_eq!(loaded(&world), vec![1]);
+        let between = IntRect { left: 50, bottom: -2, right: 52, top: 0 };
+        world.stream_headless(&between, 8).unwrap();
+        assert!(loaded(&world).is_empty());
+    }
+
+    #[test]
+    fn reloads_rooms_independently() {
+        let dir = temp_dir("reload");
+        let mut world = two_rooms(&dir, 2);
+        world.stream_headless(&everything(), 0).unwrap();
+        assert!(world.tile_at(1, -1).is_none());
+
+        let (a, b) = (dir.join("a.tmx"), dir.join("b.tmx"));
+        fs::write(&a, "not a map").unwrap();
+        write_map(&b, &[&[1, 1, 0, 1], &[1, 1, 1, 1]]);
+
+        let changed: Vec<PathBuf> = world.rooms()
+            .flat_map(|(_, _, map)| map.source_paths())
+            .filter(|path| path.extension() == Some(std::ffi::OsStr::new("tmx")))
+            .collect();
+        let results = world.reload_with(&changed, None);
+        assert_eq!(results.len(), 2);
+        assert!(results[0].1.is_err());
+        assert!(results[1].1.is_ok());
+
+        // a keeps its old map and b has its new one
+        assert_eq!(loaded(&world), vec![0, 1]);
+        assert!(world.tile_at(0, -1).is_some());
+        assert!(world.tile_at(7, -1).is_some());
+    }
+}
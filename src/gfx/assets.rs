@@ -0,0 +1,353 @@
+
+// Shares textures between everything that loads the same image. Entries are
+// held weakly, so a texture is freed as soon as the last handle to it drops.
+
+use {
+    super::{slice_atlas, upload_atlas, whole_image_atlas, AtlasImage, Texture},
+    std::{
+        collections::HashMap,
+        error::Error,
+        fs,
+        ops::Deref,
+        path::{Path, PathBuf},
+        rc::{Rc, Weak},
+    },
+};
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Key {
+    Atlas { path: PathBuf, tile_width: i32, tile_height: i32 },
+    Sheet(PathBuf),
+}
+
+impl Key {
+    fn path(&self) -> &Path {
+        match self {
+            Key::Atlas { path, .. } => path,
+            Key::Sheet(path)        => path,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CachedTexture {
+    texture: Texture,
+    bytes:   usize,
+    // the decoded image, kept for atlases whose pixels are also read on the
+    // CPU
+    image:   Option<Rc<AtlasImage>>,
+}
+
+impl CachedTexture {
+    // GPU memory taken by the texture's pixels.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+impl Deref for CachedTexture {
+    type Target = Texture;
+    fn deref(&self) -> &Texture {
+        &self.texture
+    }
+}
+
+pub type TextureHandle = Rc<CachedTexture>;
+
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryUsage {
+    pub textures: usize,
+    pub bytes:    usize,
+}
+
+// Not Send, like the textures it hands out.
+#[derive(Default)]
+pub struct Assets {
+    textures: HashMap<Key, Weak<CachedTexture>>,
+    // invalidated entries whose textures are still in use, so they're still
+    // counted until the last handle drops
+    retired:  Vec<(Key, Weak<CachedTexture>)>,
+}
+
+// The same file reached through different relative paths or links should
+// share one entry.
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+impl Assets {
+    pub fn new() -> Assets {
+        Assets::default()
+    }
+
+    fn get(&mut self, key: &Key) -> Option<TextureHandle> {
+        self.textures.retain(|_, entry| entry.strong_count() > 0);
+        self.retired.retain(|(_, entry)| entry.strong_count() > 0);
+        self.textures.get(key).and_then(Weak::upgrade)
+    }
+
+    fn insert(&mut self, key: Key, texture: Texture, bytes: usize, image: Option<Rc<AtlasImage>>)
+        -> TextureHandle
+    {
+        let handle = Rc::new(CachedTexture { texture, bytes, image });
+        self.textures.insert(key, Rc::downgrade(&handle));
+        handle
+    }
+
+    // An image cut into tiles, as an array texture with a layer per tile.
+    pub fn atlas(&mut self, path: impl AsRef<Path>, tile_width: i32, tile_height: i32)
+        -> Result<TextureHandle, Box<dyn Error>>
+    {
+        let key = Key::Atlas { path: canonical(path.as_ref()), tile_width, tile_height };
+        if let Some(handle) = self.get(&key) {
+            return Ok(handle);
+        }
+
+        let atlas = slice_atlas(path, tile_width, tile_height)?;
+        Ok(self.insert_atlas(key, &atlas))
+    }
+
+    // A whole image as an array texture with one layer, so sprites can
+    // sample packed frames from it with the same shader as atlas tiles.
+    pub fn sheet(&mut self, path: impl AsRef<Path>) -> Result<TextureHandle, Box<dyn Error>> {
+        let key = Key::Sheet(canonical(path.as_ref()));
+        if let Some(handle) = self.get(&key) {
+            return Ok(handle);
+        }
+
+        let atlas = whole_image_atlas(path)?;
+        Ok(self.insert_atlas(key, &atlas))
+    }
+
+    // Like `atlas`, also giving the decoded image, for callers that read the
+    // pixels too. The image is decoded once and kept with the texture, so
+    // later calls for the same atlas don't decode it again.
+    pub fn atlas_with_image(&mut self, path: impl AsRef<Path>, tile_width: i32, tile_height: i32)
+        -> Result<(TextureHandle, Rc<AtlasImage>), Box<dyn Error>>
+    {
+        let key = Key::Atlas { path: canonical(path.as_ref()), tile_width, tile_height };
+        if let Some(handle) = self.get(&key) {
+            if let Some(image) = handle.image.clone() {
+                return Ok((handle, image));
+            }
+        }
+
+        let image = Rc::new(slice_atlas(path, tile_width, tile_height)?);
+        let texture = upload_atlas(&image);
+        let handle = self.insert(key, texture, image.pixels.len(), Some(image.clone()));
+        Ok((handle, image))
+    }
+
+    // Like `atlas`, for callers that already sliced the image at `path`; it
+    // is only uploaded if it isn't cached yet.
+    pub fn atlas_from_image(&mut self, path: impl AsRef<Path>, atlas: &AtlasImage)
+        -> TextureHandle
+    {
+        let key = Key::Atlas {
+            path:        canonical(path.as_ref()),
+            tile_width:  atlas.tile_width,
+            tile_height: atlas.tile_height,
+        };
+        match self.get(&key) {
+            Some(handle) => handle,
+            None         => self.insert_atlas(key, atlas),
+        }
+    }
+
+    fn insert_atlas(&mut self, key: Key, atlas: &AtlasImage) -> TextureHandle {
+        let texture = upload_atlas(atlas);
+        self.insert(key, texture, atlas.pixels.len(), None)
+    }
+
+    // Forgets textures loaded from `paths`, so the next load reads the files
+    // again. Existing handles stay valid.
+    pub fn invalidate<P: AsRef<Path>>(&mut self, paths: impl IntoIterator<Item = P>) {
+        for path in paths {
+            let path = canonical(path.as_ref());
+            let stale: Vec<Key> = self.textures.keys()
+                .filter(|key| key.path() == path)
+                .cloned()
+                .collect();
+            for key in stale {
+                let entry = self.textures.remove(&key).unwrap();
+                if entry.strong_count() > 0 {
+                    self.retired.push((key, entry));
+                }
+            }
+        }
+    }
+
+    // Every texture still alive, cached or retired.
+    fn live(&self) -> impl Iterator<Item = (&Key, TextureHandle)> {
+        self.textures.iter()
+            .chain(self.retired.iter().map(|(key, entry)| (key, entry)))
+            .filter_map(|(key, entry)| entry.upgrade().map(|tex| (key, tex)))
+    }
+
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let (textures, bytes) = self.live()
+            .fold((0, 0), |(n, bytes), (_, tex)| (n + 1, bytes + tex.bytes));
+        MemoryUsage { textures, bytes }
+    }
+
+    // One line per live texture, largest first.
+    pub fn report(&self) -> String {
+        let mut live: Vec<(&Key, TextureHandle)> = self.live().collect();
+        live.sort_by_key(|(_, tex)| std::cmp::Reverse(tex.bytes));
+
+        let usage = self.memory_usage();
+        let mut out = format!(
+            "{} textures, {} KiB\n",
+            usage.textures, usage.bytes >> 10
+        );
+        for (key, tex) in live {
+            // the report's own handle isn't counted
+            let users = Rc::strong_count(&tex) - 1;
+            out += &format!(
+                "{:>8} KiB  {} ({} users)\n",
+                tex.bytes >> 10, key.path().display(), users
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use {
+        super::*,
+        gl::types::*,
+        std::{
+            cell::RefCell,
+            collections::HashSet,
+            ffi::c_void,
+            sync::{atomic::{AtomicU32, Ordering}, Once},
+        },
+    };
+
+    // Just enough of GL to create and delete textures, tracking which are
+    // alive on each test's thread.
+    static NEXT_TEXTURE: AtomicU32 = AtomicU32::new(1);
+    thread_local! {
+        static LIVE_TEXTURES: RefCell<HashSet<GLuint>> = RefCell::new(HashSet::new());
+    }
+
+    extern "system" fn create_textures(_: GLenum, n: GLsizei, textures: *mut GLuint) {
+        for i in 0 .. n as usize {
+            let id = NEXT_TEXTURE.fetch_add(1, Ordering::Relaxed);
+            LIVE_TEXTURES.with(|live| live.borrow_mut().insert(id));
+            unsafe { *textures.add(i) = id; }
+        }
+    }
+
+    extern "system" fn delete_textures(n: GLsizei, textures: *const GLuint) {
+        for i in 0 .. n as usize {
+            let id = unsafe { *textures.add(i) };
+            assert!(LIVE_TEXTURES.with(|live| live.borrow_mut().remove(&id)), "double delete");
+        }
+    }
+
+    extern "system" fn texture_parameteriv(_: GLuint, _: GLenum, _: *const GLint) { }
+
+    extern "system" fn texture_storage_3d(
+        _: GLuint, _: GLsizei, _: GLenum, _: GLsizei, _: GLsizei, _: GLsizei) { }
+
+    extern "system" fn texture_sub_image_3d(
+        _: GLuint, _: GLint, _: GLint, _: GLint, _: GLint,
+        _: GLsizei, _: GLsizei, _: GLsizei, _: GLenum, _: GLenum, _: *const c_void) { }
+
+    pub(crate) fn fake_gl() {
+        static LOAD: Once = Once::new();
+        LOAD.call_once(|| gl::load_with(|name| match name {
+            "glCreateTextures"     => create_textures as *const c_void,
+            "glDeleteTextures"     => delete_textures as *const c_void,
+            "glTextureParameteriv" => texture_parameteriv as *const c_void,
+            "glTextureStorage3D"   => texture_storage_3d as *const c_void,
+            "glTextureSubImage3D"  => texture_sub_image_3d as *const c_void,
+            _                      => std::ptr::null(),
+        }));
+    }
+
+    fn live_textures() -> usize {
+        LIVE_TEXTURES.with(|live| live.borrow().len())
+    }
+
+    // tiles.png is 64x64, so 16 KiB however it's sliced
+    const IMAGE_BYTES: usize = 64 * 64 * 4;
+
+    #[test]
+    fn shares_one_texture_between_loads() {
+        fake_gl();
+        let mut assets = Assets::new();
+        let a = assets.atlas("tiles.png", 16, 16).unwrap();
+        let b = assets.atlas("./tiles.png", 16, 16).unwrap();
+        assert!(Rc::ptr_eq(&a, &b));
+        assert_eq!(live_textures(), 1);
+
+        // sliced differently, it's a different texture
+        let c = assets.atlas("tiles.png", 32, 32).unwrap();
+        assert!(!Rc::ptr_eq(&a, &c));
+        assert_eq!(live_textures(), 2);
+    }
+
+    #[test]
+    fn frees_textures_on_last_drop() {
+        fake_gl();
+        let mut assets = Assets::new();
+        let a = assets.sheet("tiles.png").unwrap();
+        let b = assets.sheet("tiles.png").unwrap();
+
+        drop(a);
+        assert_eq!(live_textures(), 1);
+        assert_eq!(assets.memory_usage().textures, 1);
+
+        drop(b);
+        assert_eq!(live_textures(), 0);
+        assert_eq!(assets.memory_usage().textures, 0);
+
+        let _c = assets.sheet("tiles.png").unwrap();
+        assert_eq!(live_textures(), 1);
+    }
+
+    #[test]
+    fn counts_invalidated_textures_until_they_drop() {
+        fake_gl();
+        let mut assets = Assets::new();
+        let old = assets.atlas("tiles.png", 16, 16).unwrap();
+        assets.invalidate(["tiles.png"]);
+        assert_eq!(assets.memory_usage().textures, 1);
+
+        let new = assets.atlas("tiles.png", 16, 16).unwrap();
+        assert!(!Rc::ptr_eq(&old, &new));
+        assert_eq!(assets.memory_usage().textures, 2);
+        assert_eq!(assets.retired.len(), 1);
+
+        drop(old);
+        assert_eq!(live_textures(), 1);
+        assert_eq!(assets.memory_usage().textures, 1);
+        // dropped retired entries are pruned on the next load
+        let _again = assets.atlas("tiles.png", 16, 16).unwrap();
+        assert!(assets.retired.is_empty());
+    }
+
+    #[test]
+    fn reports_memory_usage() {
+        fake_gl();
+        let mut assets = Assets::new();
+        let atlas = assets.atlas("tiles.png", 16, 16).unwrap();
+        let _shared = atlas.clone();
+        let _sheet = assets.sheet("tiles.png").unwrap();
+
+        let usage = assets.memory_usage();
+        assert_eq!((usage.textures, usage.bytes), (2, 2 * IMAGE_BYTES));
+        assert_eq!(atlas.bytes(), IMAGE_BYTES);
+
+        let report = assets.report();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "2 textures, 32 KiB");
+        assert!(lines[1 ..].iter().all(|line| line.starts_with("      16 KiB  ")));
+        assert_eq!(lines.iter().filter(|line| line.ends_with("(2 users)")).count(), 1);
+        assert_eq!(lines.iter().filter(|line| line.ends_with("(1 users)")).count(), 1);
+    }
+}
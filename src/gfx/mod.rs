@@ -1,5 +1,15 @@
 
 pub mod shader;
+pub mod program_cache;
+pub mod offscreen;
+pub mod stream;
+pub mod assets;
+mod object;
+
+pub use self::{
+    assets::{Assets, TextureHandle},
+    object::{Texture, Buffer, VertexArray, Framebuffer, Renderbuffer},
+};
 
 use {
     std::error::Error,
@@ -7,48 +17,25 @@ use {
     image::GenericImageView,
 };
 
-pub fn load_texture(path: impl AsRef<std::path::Path>) -> Result<GLuint, Box<dyn Error>> {
-    let im = image::open(path)?.to_rgba();
-    let width = im.width() as i32;
-    let height = im.height() as i32;
-
-    unsafe {
-        let mut tex: GLuint = 0;
-        gl::CreateTextures(gl::TEXTURE_2D, 1, &mut tex);
-
-        let params: [(GLenum, GLuint); 5] = [
-            (gl::TEXTURE_MIN_FILTER, gl::NEAREST),
-            (gl::TEXTURE_MAG_FILTER, gl::NEAREST),
-            (gl::TEXTURE_MAX_LEVEL,  0),
-            (gl::TEXTURE_WRAP_S,     gl::CLAMP_TO_EDGE),
-            (gl::TEXTURE_WRAP_T,     gl::CLAMP_TO_EDGE),
-        ];
-        for (pname, val) in &params {
-            gl::TextureParameteriv(tex, *pname, &(*val as i32));
-        }
-
-        gl::TextureStorage2D(tex, 1, gl::RGBA8, width, height);
-        gl::TextureSubImage2D(
-            tex, 0,
-            0, 0, width, height,
-            gl::RGBA, gl::UNSIGNED_BYTE, im.into_raw().as_ptr() as *const std::ffi::c_void
-        );
-
-        Ok(tex)
-    }
+// An image cut into equally sized tiles, laid out as consecutive RGBA8
+// layers ready for upload into an array texture.
+#[derive(Debug)]
+pub struct AtlasImage {
+    pub tile_width:  i32,
+    pub tile_height: i32,
+    pub tile_count:  i32,
+    pub pixels:      Vec<u8>,
 }
 
-pub fn load_atlas_texture(
+pub fn slice_atlas(
     path: impl AsRef<std::path::Path>,
     tile_width:  i32,
     tile_height: i32,
-//  base_index:  u32,
 )
-    -> Result<GLuint, Box<dyn Error>>
+    -> Result<AtlasImage, Box<dyn Error>>
 {
     assert!(tile_width > 1 && tile_height > 1, "Invalid parameters");
 
-    eprintln!("loading {}", path.as_ref().display());
     let im = image::open(path)?.to_rgba();
     let width  = im.width()  as i32;
     let height = im.height() as i32;
@@ -61,10 +48,51 @@ pub fn load_atlas_texture(
     let rows    = height / tile_height;
     let tile_count = columns * rows;
 
-    unsafe {
-        let mut tex: GLuint = 0;
-        gl::CreateTextures(gl::TEXTURE_2D_ARRAY, 1, &mut tex);
+    let mut pixels = Vec::with_capacity((tile_count * tile_width * tile_height * 4) as usize);
+    for tile_y in 0..rows {
+        for tile_x in 0..columns {
+            let tile_image_buf = im
+                .view(
+                    (tile_x * tile_width) as u32,
+                    (tile_y * tile_height) as u32,
+                    tile_width as u32,
+                    tile_height as u32,
+                )
+                .to_image()
+                .into_vec();
+            pixels.extend_from_slice(&tile_image_buf);
+        }
+    }
+
+    Ok(AtlasImage { tile_width, tile_height, tile_count, pixels })
+}
+
+// A whole image as a one-tile atlas, for packed sheets whose frames are
+// picked out by texture coordinates rather than by layer.
+pub fn whole_image_atlas(path: impl AsRef<std::path::Path>)
+    -> Result<AtlasImage, Box<dyn Error>>
+{
+    let im = image::open(path)?.to_rgba();
+    Ok(AtlasImage {
+        tile_width:  im.width()  as i32,
+        tile_height: im.height() as i32,
+        tile_count:  1,
+        pixels:      im.into_raw(),
+    })
+}
 
+pub fn upload_atlas(atlas: &AtlasImage) -> Texture {
+    let AtlasImage { tile_width, tile_height, tile_count, .. } = *atlas;
+    assert_eq!(
+        atlas.pixels.len(),
+        (tile_width * tile_height * tile_count * 4) as usize,
+        "Atlas pixel data doesn't match its dimensions"
+    );
+
+    let texture = Texture::new(gl::TEXTURE_2D_ARRAY);
+    let tex = texture.handle();
+
+    unsafe {
         let params: [(GLenum, GLuint); 5] = [
             (gl::TEXTURE_MIN_FILTER, gl::NEAREST),
             (gl::TEXTURE_MAG_FILTER, gl::NEAREST),
@@ -77,31 +105,14 @@ pub fn load_atlas_texture(
         }
 
         gl::TextureStorage3D(tex, 1, gl::RGBA8, tile_width, tile_height, tile_count);
-
-        for tile_y in 0..rows {
-            for tile_x in 0..columns {
-                let index = tile_y * columns + tile_x;
-                let tile_image_buf = im
-                    .view(
-                        (tile_x * tile_width) as u32,
-                        (tile_y * tile_height) as u32,
-                        tile_width as u32,
-                        tile_height as u32,
-                    )
-                    .to_image()
-                    .into_vec();
-
-                gl::TextureSubImage3D(
-                    tex, 0,
-                    0, 0, index,
-                    tile_width, tile_height, 1,
-                    gl::RGBA, gl::UNSIGNED_BYTE,
-                    tile_image_buf.as_ptr() as *const std::ffi::c_void
-                );
-            }
-        }
-
-        Ok(tex)
+        gl::TextureSubImage3D(
+            tex, 0,
+            0, 0, 0,
+            tile_width, tile_height, tile_count,
+            gl::RGBA, gl::UNSIGNED_BYTE,
+            atlas.pixels.as_ptr() as *const std::ffi::c_void
+        );
     }
-}
 
+    texture
+}
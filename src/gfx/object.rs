@@ -0,0 +1,155 @@
+
+// Owning handles for GL objects. Each deletes its object when dropped, and
+// none of them are Send: GL objects belong to the context current on the
+// thread that made them, so that's the only place they may be deleted.
+
+use {
+    std::marker::PhantomData,
+    gl::types::*,
+};
+
+type NotSend = PhantomData<*const ()>;
+
+#[derive(Debug)]
+pub struct Texture {
+    handle:    GLuint,
+    _not_send: NotSend,
+}
+
+impl Texture {
+    pub fn new(target: GLenum) -> Texture {
+        let mut handle: GLuint = 0;
+        unsafe { gl::CreateTextures(target, 1, &mut handle); }
+        Texture { handle, _not_send: PhantomData }
+    }
+
+    pub fn handle(&self) -> GLuint {
+        self.handle
+    }
+}
+
+impl Drop for Texture {
+    fn drop(&mut self) {
+        unsafe { gl::DeleteTextures(1, &self.handle); }
+    }
+}
+
+#[derive(Debug)]
+pub struct Buffer {
+    handle:    GLuint,
+    _not_send: NotSend,
+}
+
+impl Buffer {
+    pub fn new() -> Buffer {
+        let mut handle: GLuint = 0;
+        unsafe { gl::CreateBuffers(1, &mut handle); }
+        Buffer { handle, _not_send: PhantomData }
+    }
+
+    pub fn handle(&self) -> GLuint {
+        self.handle
+    }
+}
+
+impl Default for Buffer {
+    fn default() -> Buffer {
+        Buffer::new()
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        unsafe { gl::DeleteBuffers(1, &self.handle); }
+    }
+}
+
+#[derive(Debug)]
+pub struct VertexArray {
+    handle:    GLuint,
+    _not_send: NotSend,
+}
+
+impl VertexArray {
+    pub fn new() -> VertexArray {
+        let mut handle: GLuint = 0;
+        unsafe { gl::CreateVertexArrays(1, &mut handle); }
+        VertexArray { handle, _not_send: PhantomData }
+    }
+
+    pub fn handle(&self) -> GLuint {
+        self.handle
+    }
+}
+
+impl Default for VertexArray {
+    fn default() -> VertexArray {
+        VertexArray::new()
+    }
+}
+
+impl Drop for VertexArray {
+    fn drop(&mut self) {
+        unsafe { gl::DeleteVertexArrays(1, &self.handle); }
+    }
+}
+
+#[derive(Debug)]
+pub struct Framebuffer {
+    handle:    GLuint,
+    _not_send: NotSend,
+}
+
+impl Framebuffer {
+    pub fn new() -> Framebuffer {
+        let mut handle: GLuint = 0;
+        unsafe { gl::CreateFramebuffers(1, &mut handle); }
+        Framebuffer { handle, _not_send: PhantomData }
+    }
+
+    pub fn handle(&self) -> GLuint {
+        self.handle
+    }
+}
+
+impl Default for Framebuffer {
+    fn default() -> Framebuffer {
+        Framebuffer::new()
+    }
+}
+
+impl Drop for Framebuffer {
+    fn drop(&mut self) {
+        unsafe { gl::DeleteFramebuffers(1, &self.handle); }
+    }
+}
+
+#[derive(Debug)]
+pub struct Renderbuffer {
+    handle:    GLuint,
+    _not_send: NotSend,
+}
+
+impl Renderbuffer {
+    pub fn new() -> Renderbuffer {
+        let mut handle: GLuint = 0;
+        unsafe { gl::CreateRenderbuffers(1, &mut handle); }
+        Renderbuffer { handle, _not_send: PhantomData }
+    }
+
+    pub fn handle(&self) -> GLuint {
+        self.handle
+    }
+}
+
+impl Default for Renderbuffer {
+    fn default() -> Renderbuffer {
+        Renderbuffer::new()
+    }
+}
+
+impl Drop for Renderbuffer {
+    fn drop(&mut self) {
+        unsafe { gl::DeleteRenderbuffers(1, &self.handle); }
+    }
+}
@@ -0,0 +1,145 @@
+
+use {
+    std::error::Error,
+    gl::types::*,
+    super::{Framebuffer, Renderbuffer},
+};
+
+// A framebuffer with an RGBA8 colour and 24-bit depth attachment, for
+// rendering without a window.
+pub struct Offscreen {
+    fbo:     Framebuffer,
+    // kept only to live as long as the framebuffer they're attached to
+    _colour: Renderbuffer,
+    _depth:  Renderbuffer,
+    width:   i32,
+    height:  i32,
+}
+
+impl Offscreen {
+    pub fn new(width: i32, height: i32) -> Result<Offscreen, Box<dyn Error>> {
+        assert!(width > 0 && height > 0, "Invalid dimensions");
+
+        let fbo = Framebuffer::new();
+        let colour = Renderbuffer::new();
+        let depth = Renderbuffer::new();
+        unsafe {
+            gl::NamedRenderbufferStorage(colour.handle(), gl::RGBA8, width, height);
+            gl::NamedRenderbufferStorage(depth.handle(), gl::DEPTH_COMPONENT24, width, height);
+
+            gl::NamedFramebufferRenderbuffer(
+                fbo.handle(), gl::COLOR_ATTACHMENT0, gl::RENDERBUFFER, colour.handle()
+            );
+            gl::NamedFramebufferRenderbuffer(
+                fbo.handle(), gl::DEPTH_ATTACHMENT, gl::RENDERBUFFER, depth.handle()
+            );
+        }
+
+        let status = unsafe { gl::CheckNamedFramebufferStatus(fbo.handle(), gl::FRAMEBUFFER) };
+        if status != gl::FRAMEBUFFER_COMPLETE {
+            return Err(format!("incomplete framebuffer (status {:#x})", status).into());
+        }
+
+        Ok(Offscreen { fbo, _colour: colour, _depth: depth, width, height })
+    }
+
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    // Directs drawing here until `unbind`.
+    pub fn bind(&self) {
+        unsafe { gl::BindFramebuffer(gl::DRAW_FRAMEBUFFER, self.fbo.handle()); }
+    }
+
+    pub fn unbind(&self) {
+        unsafe { gl::BindFramebuffer(gl::DRAW_FRAMEBUFFER, 0); }
+    }
+
+    // Reads the colour attachment back, top row first.
+    pub fn read_rgba(&self) -> image::RgbaImage {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let row_len = width * 4;
+        let mut pixels = vec![0u8; row_len * height];
+
+        unsafe {
+            gl::BindFramebuffer(gl::READ_FRAMEBUFFER, self.fbo.handle());
+            gl::NamedFramebufferReadBuffer(self.fbo.handle(), gl::COLOR_ATTACHMENT0);
+            gl::PixelStorei(gl::PACK_ALIGNMENT, 1);
+            gl::ReadnPixels(
+                0, 0, self.width, self.height,
+                gl::RGBA, gl::UNSIGNED_BYTE,
+                pixels.len() as GLsizei,
+                pixels.as_mut_ptr() as *mut std::ffi::c_void
+            );
+            gl::BindFramebuffer(gl::READ_FRAMEBUFFER, 0);
+        }
+
+        // GL's rows go bottom to top
+        let flipped: Vec<u8> = pixels.chunks_exact(row_len)
+            .rev()
+            .flatten()
+            .cloned()
+            .collect();
+
+        image::RgbaImage::from_raw(self.width as u32, self.height as u32, flipped)
+            .expect("Read-back size doesn't match the image")
+    }
+}
+
+// How far a frame is from the image it should match.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageDiff {
+    // pixels with a channel further off than the tolerance
+    pub pixels:    usize,
+    // the largest difference in any channel
+    pub max_delta: u8,
+}
+
+// Compares two images channel by channel, allowing each to be off by up to
+// `tolerance` to absorb rounding differences between GL implementations.
+// None if the images aren't the same size.
+pub fn diff_images(a: &image::RgbaImage, b: &image::RgbaImage, tolerance: u8)
+    -> Option<ImageDiff>
+{
+    if a.dimensions() != b.dimensions() {
+        return None;
+    }
+
+    let mut diff = ImageDiff { pixels: 0, max_delta: 0 };
+    for (pa, pb) in a.pixels().zip(b.pixels()) {
+        let delta = pa.0.iter().zip(pb.0.iter())
+            .map(|(&ca, &cb)| ca.abs_diff(cb))
+            .max()
+            .unwrap_or(0);
+        diff.max_delta = diff.max_delta.max(delta);
+        if delta > tolerance {
+            diff.pixels += 1;
+        }
+    }
+    Some(diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diffs_within_tolerance() {
+        let a = image::RgbaImage::from_pixel(4, 4, image::Rgba([10, 20, 30, 255]));
+        let mut b = a.clone();
+        assert_eq!(diff_images(&a, &b, 0), Some(ImageDiff { pixels: 0, max_delta: 0 }));
+
+        b.put_pixel(1, 2, image::Rgba([12, 20, 30, 255]));
+        b.put_pixel(3, 0, image::Rgba([10, 20, 25, 255]));
+        assert_eq!(diff_images(&a, &b, 2), Some(ImageDiff { pixels: 1, max_delta: 5 }));
+        assert_eq!(diff_images(&a, &b, 5), Some(ImageDiff { pixels: 0, max_delta: 5 }));
+
+        let small = image::RgbaImage::new(2, 4);
+        assert_eq!(diff_images(&a, &small, 255), None);
+    }
+}
@@ -0,0 +1,147 @@
+
+// Linked program binaries kept on disk between runs, so starting up doesn't
+// recompile every shader. Entries are keyed by a hash of the preprocessed
+// sources and the driver, and a binary the driver rejects anyway (after a
+// driver update, say) is just rebuilt from source.
+
+use {
+    super::shader::{self, Program, Stage},
+    std::{
+        error::Error,
+        ffi::CStr,
+        fs,
+        io::Read,
+        path::{Path, PathBuf},
+    },
+    byteorder::{LittleEndian as LE, ReadBytesExt, WriteBytesExt},
+    gl::types::*,
+};
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME:  u64 = 0x0000_0100_0000_01b3;
+
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &b| (hash ^ b as u64).wrapping_mul(FNV_PRIME))
+}
+
+fn gl_string(name: GLenum) -> String {
+    unsafe {
+        let ptr = gl::GetString(name);
+        if ptr.is_null() {
+            return String::new();
+        }
+        CStr::from_ptr(ptr as *const _).to_string_lossy().into_owned()
+    }
+}
+
+pub struct ProgramCache {
+    dir:    PathBuf,
+    driver: String,
+}
+
+impl ProgramCache {
+    // Needs a current context, to ask which driver it's caching for.
+    pub fn new(dir: impl AsRef<Path>) -> ProgramCache {
+        let driver = format!(
+            "{} / {} / {}",
+            gl_string(gl::VENDOR), gl_string(gl::RENDERER), gl_string(gl::VERSION)
+        );
+        ProgramCache { dir: dir.as_ref().to_path_buf(), driver }
+    }
+
+    pub fn key(&self, sources: &[(Stage, &str)]) -> u64 {
+        let mut hash = fnv1a(FNV_OFFSET, self.driver.as_bytes());
+        for (stage, text) in sources {
+            hash = fnv1a(hash, &[*stage as u8]);
+            hash = fnv1a(hash, text.as_bytes());
+            // keeps ("ab", "c") and ("a", "bc") apart
+            hash = fnv1a(hash, &[0]);
+        }
+        hash
+    }
+
+    fn path(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{:016x}.bin", key))
+    }
+
+    // None if there's no entry or the driver won't take it.
+    pub fn load(&self, key: u64) -> Option<Program> {
+        let data = fs::read(self.path(key)).ok()?;
+        let mut reader = &data[..];
+        let format = reader.read_u32::<LE>().ok()?;
+        let mut binary = Vec::new();
+        reader.read_to_end(&mut binary).ok()?;
+
+        match shader::load_binary(format, &binary) {
+            Ok(program) => Some(program),
+            Err(_) => {
+                eprintln!("cached program {:016x} rejected, rebuilding", key);
+                None
+            }
+        }
+    }
+
+    pub fn store(&self, key: u64, program: &Program) -> Result<(), Box<dyn Error>> {
+        let (format, binary) = program.binary()
+            .ok_or("driver has no program binary format")?;
+
+        let mut data = Vec::with_capacity(4 + binary.len());
+        data.write_u32::<LE>(format)?;
+        data.extend_from_slice(&binary);
+
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.path(key), data)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::gfx::shader::Library,
+    };
+
+    static SOURCES: &[(&str, &str)] = &[
+        ("a.glsl", "#version 450\nvoid main() {}\n"),
+        ("b.glsl", "#version 450\nvoid main() { }\n"),
+    ];
+    const LIBRARY: Library = Library::Embedded(SOURCES);
+
+    // Doesn't ask GL for the driver, so works without a context.
+    fn cache(driver: &str) -> ProgramCache {
+        ProgramCache { dir: PathBuf::from("unused"), driver: driver.to_string() }
+    }
+
+    fn key(cache: &ProgramCache, stages: &[(Stage, &str)], defines: &[(&str, &str)]) -> u64 {
+        let sources: Vec<(Stage, String)> = stages.iter()
+            .map(|(stage, name)| (*stage, LIBRARY.preprocess(name, defines).unwrap().text))
+            .collect();
+        let texts: Vec<(Stage, &str)> = sources.iter()
+            .map(|(stage, text)| (*stage, text.as_str()))
+            .collect();
+        cache.key(&texts)
+    }
+
+    #[test]
+    fn keys_cover_sources_defines_and_driver() {
+        let nvidia = cache("NVIDIA / GTX / 4.5");
+        let base = key(&nvidia, &[(Stage::Vertex, "a.glsl")], &[]);
+        assert_eq!(base, key(&nvidia, &[(Stage::Vertex, "a.glsl")], &[]));
+
+        assert_ne!(base, key(&nvidia, &[(Stage::Vertex, "b.glsl")], &[]));
+        assert_ne!(base, key(&nvidia, &[(Stage::Fragment, "a.glsl")], &[]));
+        assert_ne!(base, key(&nvidia, &[(Stage::Vertex, "a.glsl")], &[("DEBUG", "1")]));
+        assert_ne!(
+            key(&nvidia, &[(Stage::Vertex, "a.glsl")], &[("DEBUG", "1")]),
+            key(&nvidia, &[(Stage::Vertex, "a.glsl")], &[("DEBUG", "0")]),
+        );
+        assert_ne!(base, key(&cache("NVIDIA / GTX / 4.6"), &[(Stage::Vertex, "a.glsl")], &[]));
+
+        // the same text split differently between stages
+        assert_ne!(
+            nvidia.key(&[(Stage::Vertex, "ab"), (Stage::Fragment, "c")]),
+            nvidia.key(&[(Stage::Vertex, "a"), (Stage::Fragment, "bc")]),
+        );
+    }
+}
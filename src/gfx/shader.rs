@@ -6,14 +6,28 @@ use {
     std::ptr,
     std::str,
     std::fmt,
+    std::fs,
+    std::collections::HashMap,
+    std::marker::PhantomData,
+    std::path::{Path, PathBuf},
 
     gl::types::*,
+    nalgebra::Matrix4,
+
+    crate::{
+        alg::V2,
+        gfx::program_cache::ProgramCache,
+        watch::Watcher,
+    },
 };
 
-pub enum Stage { Vertex, Fragment }
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage { Vertex, Geometry, Fragment, Compute }
 
+// Like the objects in gfx::object, shaders and programs aren't Send, so
+// they're deleted on the GL thread.
 #[derive(Debug)]
-pub struct Unit { handle: GLuint }
+pub struct Unit { handle: GLuint, _not_send: PhantomData<*const ()> }
 
 #[derive(Debug)]
 pub struct CompileError(Unit);
@@ -29,7 +43,9 @@ pub fn compile(stage: Stage, src: &str)
 {
     let gl_stage = match stage {
         Stage::Vertex   => gl::VERTEX_SHADER,
+        Stage::Geometry => gl::GEOMETRY_SHADER,
         Stage::Fragment => gl::FRAGMENT_SHADER,
+        Stage::Compute  => gl::COMPUTE_SHADER,
     };
 
     let handle = unsafe { gl::CreateShader(gl_stage) };
@@ -54,7 +70,7 @@ pub fn compile(stage: Stage, src: &str)
         status != (gl::FALSE as i32)
     };
 
-    let shader = Unit { handle };
+    let shader = Unit { handle, _not_send: PhantomData };
     if ok {
         Ok(shader)
     }
@@ -74,8 +90,7 @@ impl Unit {
             return "<no info log>".to_string();
         }
 
-        let mut log_buffer: Vec<u8> = Vec::new();
-        log_buffer.resize(log_length as usize, 0);
+        let mut log_buffer: Vec<u8> = vec![0; log_length as usize];
         unsafe {
             gl::GetShaderInfoLog(
                 self.handle,
@@ -90,7 +105,7 @@ impl Unit {
 }
 
 impl CompileError {
-    fn info_log(&self) -> String {
+    pub fn info_log(&self) -> String {
         let CompileError(shader) = self;
         shader.info_log()
     }
@@ -108,11 +123,27 @@ impl Error for CompileError {
     }
 }
 
+// An active uniform or attribute, as reported by GL after linking. Arrays
+// are listed by their name without the `[0]`.
+#[derive(Clone, Copy, Debug)]
+pub struct Variable {
+    pub location: GLint,
+    pub ty:       GLenum,
+    pub size:     GLint,
+}
+
 #[derive(Debug)]
-pub struct Program { handle: GLuint }
+pub struct Program {
+    handle:     GLuint,
+    uniforms:   HashMap<String, Variable>,
+    attributes: HashMap<String, Variable>,
+    // uniform blocks by name, with their binding points
+    blocks:     HashMap<String, GLuint>,
+    _not_send:  PhantomData<*const ()>,
+}
 
 #[derive(Debug)]
-pub struct LinkError(Program);
+pub struct LinkError(Box<Program>);
 
 impl Drop for Program {
     fn drop(&mut self) {
@@ -130,21 +161,75 @@ pub fn link(stages: &[Unit])
             gl::AttachShader(handle, stage.handle);
         }
 
+        // so it can go in a ProgramCache
+        gl::ProgramParameteri(handle, gl::PROGRAM_BINARY_RETRIEVABLE_HINT, gl::TRUE as GLint);
         gl::LinkProgram(handle);
     }
 
-    let ok = unsafe {
-        let mut status: GLint = 0;
-        gl::GetProgramiv(handle, gl::LINK_STATUS, &mut status);
-        status != (gl::FALSE as i32)
-    };
+    Program::from_linked(handle)
+}
 
-    let program = Program { handle };
-    if ok {
-        Ok(program)
+// Loads a binary from `Program::binary`. Drivers reject binaries from other
+// drivers or versions, which shows up as a LinkError.
+pub fn load_binary(format: GLenum, binary: &[u8]) -> Result<Program, LinkError> {
+    let handle = unsafe { gl::CreateProgram() };
+    unsafe {
+        gl::ProgramBinary(
+            handle, format,
+            binary.as_ptr() as *const _, binary.len() as GLsizei
+        );
     }
-    else {
-        Err(LinkError(program))
+
+    Program::from_linked(handle)
+}
+
+impl Program {
+    fn from_linked(handle: GLuint) -> Result<Program, LinkError> {
+        let ok = unsafe {
+            let mut status: GLint = 0;
+            gl::GetProgramiv(handle, gl::LINK_STATUS, &mut status);
+            status != (gl::FALSE as i32)
+        };
+
+        let mut program = Program {
+            handle,
+            uniforms:   HashMap::new(),
+            attributes: HashMap::new(),
+            blocks:     HashMap::new(),
+            _not_send:  PhantomData,
+        };
+        if ok {
+            program.uniforms   = reflect(handle, gl::UNIFORM);
+            program.attributes = reflect(handle, gl::PROGRAM_INPUT);
+            program.blocks     = reflect_blocks(handle);
+            Ok(program)
+        }
+        else {
+            Err(LinkError(Box::new(program)))
+        }
+    }
+
+    // The linked program in the driver's own format, if it has one.
+    pub fn binary(&self) -> Option<(GLenum, Vec<u8>)> {
+        let mut length: GLint = 0;
+        unsafe {
+            gl::GetProgramiv(self.handle, gl::PROGRAM_BINARY_LENGTH, &mut length);
+        }
+        if length <= 0 {
+            return None;
+        }
+
+        let mut binary: Vec<u8> = vec![0; length as usize];
+        let mut format: GLenum = 0;
+        let mut written: GLsizei = 0;
+        unsafe {
+            gl::GetProgramBinary(
+                self.handle, length, &mut written, &mut format,
+                binary.as_mut_ptr() as *mut _
+            );
+        }
+        binary.truncate(written as usize);
+        Some((format, binary))
     }
 }
 
@@ -159,8 +244,7 @@ impl Program {
             return "<no info log>".to_string();
         }
 
-        let mut log_buffer: Vec<u8> = Vec::new();
-        log_buffer.resize(log_length as usize, 0);
+        let mut log_buffer: Vec<u8> = vec![0; log_length as usize];
         unsafe {
             gl::GetProgramInfoLog(
                 self.handle,
@@ -173,13 +257,309 @@ impl Program {
         String::from_utf8_lossy(&log_buffer).into()
     }
 
+    // # Safety
+    // The context the program was made in must be current.
     pub unsafe fn bind(&self) {
         gl::UseProgram(self.handle);
     }
+
+    // Runs a compute program over `groups` work groups, leaving it bound.
+    // Making the results visible with a barrier is up to the caller.
+    //
+    // # Safety
+    // The context the program was made in must be current, and the program
+    // must have a compute stage.
+    pub unsafe fn dispatch(&self, groups: [GLuint; 3]) {
+        gl::UseProgram(self.handle);
+        gl::DispatchCompute(groups[0], groups[1], groups[2]);
+    }
+
+    pub fn uniforms(&self) -> &HashMap<String, Variable> {
+        &self.uniforms
+    }
+
+    pub fn attributes(&self) -> &HashMap<String, Variable> {
+        &self.attributes
+    }
+
+    pub fn blocks(&self) -> &HashMap<String, GLuint> {
+        &self.blocks
+    }
+
+    // Sets a uniform without binding the program.
+    pub fn set<T: UniformValue>(&self, name: &str, value: T) -> Result<(), InterfaceError> {
+        let uniform = self.uniforms.get(name)
+            .ok_or_else(|| InterfaceError::MissingUniform(name.to_string()))?;
+        if !T::accepts(uniform.ty) {
+            return Err(InterfaceError::UniformType {
+                name:     name.to_string(),
+                expected: T::TYPE,
+                found:    uniform.ty,
+            });
+        }
+        unsafe { value.set(self.handle, uniform.location); }
+        Ok(())
+    }
+
+    // Errors if the program doesn't take what `interface` says it does.
+    // Extra uniforms and attributes are fine.
+    pub fn check(&self, interface: &Interface) -> Result<(), InterfaceError> {
+        for &(name, ty) in interface.uniforms {
+            match self.uniforms.get(name) {
+                None => return Err(InterfaceError::MissingUniform(name.to_string())),
+                Some(uniform) if uniform.ty != ty => return Err(InterfaceError::UniformType {
+                    name:     name.to_string(),
+                    expected: ty,
+                    found:    uniform.ty,
+                }),
+                Some(_) => { }
+            }
+        }
+
+        for &(name, binding) in interface.blocks {
+            match self.blocks.get(name) {
+                None => return Err(InterfaceError::MissingBlock(name.to_string())),
+                Some(&found) if found != binding => return Err(InterfaceError::BlockBinding {
+                    name:     name.to_string(),
+                    expected: binding,
+                    found,
+                }),
+                Some(_) => { }
+            }
+        }
+
+        for &(name, ty, location) in interface.attributes {
+            match self.attributes.get(name) {
+                None => return Err(InterfaceError::MissingAttribute(name.to_string())),
+                Some(attr) if attr.ty != ty || attr.location != location => {
+                    return Err(InterfaceError::AttributeMismatch {
+                        name:     name.to_string(),
+                        expected: (ty, location),
+                        found:    (attr.ty, attr.location),
+                    });
+                }
+                Some(_) => { }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Lists a program's active resources of one kind. Built-ins and uniforms in
+// blocks have no location and are left out.
+fn reflect(handle: GLuint, interface: GLenum) -> HashMap<String, Variable> {
+    let mut count: GLint = 0;
+    let mut max_name_len: GLint = 0;
+    unsafe {
+        gl::GetProgramInterfaceiv(handle, interface, gl::ACTIVE_RESOURCES, &mut count);
+        gl::GetProgramInterfaceiv(handle, interface, gl::MAX_NAME_LENGTH,  &mut max_name_len);
+    }
+
+    let props = [gl::TYPE, gl::LOCATION, gl::ARRAY_SIZE];
+    let mut variables = HashMap::new();
+    let mut name_buf: Vec<u8> = vec![0; max_name_len.max(1) as usize];
+
+    for index in 0 .. count as GLuint {
+        let mut values: [GLint; 3] = [0; 3];
+        let mut name_len: GLsizei = 0;
+        unsafe {
+            gl::GetProgramResourceiv(
+                handle, interface, index,
+                props.len() as GLsizei, props.as_ptr(),
+                values.len() as GLsizei, ptr::null_mut(), values.as_mut_ptr()
+            );
+            gl::GetProgramResourceName(
+                handle, interface, index,
+                name_buf.len() as GLsizei, &mut name_len, name_buf.as_mut_ptr() as *mut i8
+            );
+        }
+
+        let [ty, location, size] = values;
+        if location < 0 {
+            continue;
+        }
+
+        let name = String::from_utf8_lossy(&name_buf[.. name_len as usize]);
+        let name = name.trim_end_matches("[0]").to_string();
+        variables.insert(name, Variable { location, ty: ty as GLenum, size });
+    }
+
+    variables
+}
+
+fn reflect_blocks(handle: GLuint) -> HashMap<String, GLuint> {
+    let mut count: GLint = 0;
+    let mut max_name_len: GLint = 0;
+    unsafe {
+        gl::GetProgramInterfaceiv(handle, gl::UNIFORM_BLOCK, gl::ACTIVE_RESOURCES, &mut count);
+        gl::GetProgramInterfaceiv(handle, gl::UNIFORM_BLOCK, gl::MAX_NAME_LENGTH,  &mut max_name_len);
+    }
+
+    let mut blocks = HashMap::new();
+    let mut name_buf: Vec<u8> = vec![0; max_name_len.max(1) as usize];
+
+    for index in 0 .. count as GLuint {
+        let prop = gl::BUFFER_BINDING;
+        let mut binding: GLint = 0;
+        let mut name_len: GLsizei = 0;
+        unsafe {
+            gl::GetProgramResourceiv(
+                handle, gl::UNIFORM_BLOCK, index,
+                1, &prop, 1, ptr::null_mut(), &mut binding
+            );
+            gl::GetProgramResourceName(
+                handle, gl::UNIFORM_BLOCK, index,
+                name_buf.len() as GLsizei, &mut name_len, name_buf.as_mut_ptr() as *mut i8
+            );
+        }
+
+        let name = String::from_utf8_lossy(&name_buf[.. name_len as usize]).into_owned();
+        blocks.insert(name, binding as GLuint);
+    }
+
+    blocks
+}
+
+// What a program is expected to take: uniforms by name and type, uniform
+// blocks by name and binding, and attributes by name, type and location,
+// since vertex formats are set up to match them.
+#[derive(Clone, Copy, Debug)]
+pub struct Interface {
+    pub uniforms:   &'static [(&'static str, GLenum)],
+    pub blocks:     &'static [(&'static str, GLuint)],
+    pub attributes: &'static [(&'static str, GLenum, GLint)],
+}
+
+#[derive(Debug)]
+pub enum InterfaceError {
+    MissingUniform(String),
+    UniformType { name: String, expected: GLenum, found: GLenum },
+    MissingBlock(String),
+    BlockBinding { name: String, expected: GLuint, found: GLuint },
+    MissingAttribute(String),
+    AttributeMismatch { name: String, expected: (GLenum, GLint), found: (GLenum, GLint) },
+}
+
+fn type_name(ty: GLenum) -> String {
+    match ty {
+        gl::FLOAT             => "float".into(),
+        gl::FLOAT_VEC2        => "vec2".into(),
+        gl::FLOAT_VEC3        => "vec3".into(),
+        gl::FLOAT_VEC4        => "vec4".into(),
+        gl::FLOAT_MAT4        => "mat4".into(),
+        gl::INT               => "int".into(),
+        gl::UNSIGNED_INT      => "uint".into(),
+        gl::UNSIGNED_INT_VEC2 => "uvec2".into(),
+        gl::SAMPLER_2D        => "sampler2D".into(),
+        gl::SAMPLER_2D_ARRAY  => "sampler2DArray".into(),
+        _                     => format!("type {:#x}", ty),
+    }
+}
+
+impl fmt::Display for InterfaceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::InterfaceError::*;
+        match self {
+            MissingUniform(name) =>
+                write!(f, "No active uniform {}", name),
+            UniformType { name, expected, found } =>
+                write!(f, "Uniform {} is {}, expected {}",
+                    name, type_name(*found), type_name(*expected)),
+            MissingBlock(name) =>
+                write!(f, "No active uniform block {}", name),
+            BlockBinding { name, expected, found } =>
+                write!(f, "Uniform block {} is at binding {}, expected {}",
+                    name, found, expected),
+            MissingAttribute(name) =>
+                write!(f, "No active attribute {}", name),
+            AttributeMismatch { name, expected, found } =>
+                write!(f, "Attribute {} is {} at location {}, expected {} at location {}",
+                    name, type_name(found.0), found.1, type_name(expected.0), expected.1),
+        }
+    }
+}
+
+impl Error for InterfaceError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+// Rust types that can be uploaded to a uniform of GL type TYPE, or of any
+// other type `accepts` allows.
+pub trait UniformValue {
+    const TYPE: GLenum;
+
+    fn accepts(ty: GLenum) -> bool {
+        ty == Self::TYPE
+    }
+
+    // # Safety
+    // A GL context must be current, and `location` must be an active
+    // uniform of `program` whose type `accepts` this value.
+    unsafe fn set(&self, program: GLuint, location: GLint);
+}
+
+// Samplers are set to a texture unit with an int.
+const SAMPLER_TYPES: &[GLenum] = &[
+    gl::SAMPLER_1D,
+    gl::SAMPLER_2D,
+    gl::SAMPLER_3D,
+    gl::SAMPLER_CUBE,
+    gl::SAMPLER_1D_ARRAY,
+    gl::SAMPLER_2D_ARRAY,
+    gl::SAMPLER_2D_SHADOW,
+    gl::SAMPLER_2D_ARRAY_SHADOW,
+    gl::SAMPLER_2D_MULTISAMPLE,
+    gl::SAMPLER_BUFFER,
+    gl::INT_SAMPLER_2D,
+    gl::INT_SAMPLER_2D_ARRAY,
+    gl::UNSIGNED_INT_SAMPLER_2D,
+    gl::UNSIGNED_INT_SAMPLER_2D_ARRAY,
+];
+
+impl UniformValue for f32 {
+    const TYPE: GLenum = gl::FLOAT;
+    unsafe fn set(&self, program: GLuint, location: GLint) {
+        gl::ProgramUniform1f(program, location, *self);
+    }
+}
+
+impl UniformValue for V2 {
+    const TYPE: GLenum = gl::FLOAT_VEC2;
+    unsafe fn set(&self, program: GLuint, location: GLint) {
+        gl::ProgramUniform2f(program, location, self.x, self.y);
+    }
+}
+
+impl UniformValue for Matrix4<f32> {
+    const TYPE: GLenum = gl::FLOAT_MAT4;
+    unsafe fn set(&self, program: GLuint, location: GLint) {
+        // nalgebra stores matrices column-major, as GL expects
+        gl::ProgramUniformMatrix4fv(program, location, 1, gl::FALSE, self.as_ptr());
+    }
+}
+
+impl UniformValue for i32 {
+    const TYPE: GLenum = gl::INT;
+    fn accepts(ty: GLenum) -> bool {
+        ty == Self::TYPE || SAMPLER_TYPES.contains(&ty)
+    }
+    unsafe fn set(&self, program: GLuint, location: GLint) {
+        gl::ProgramUniform1i(program, location, *self);
+    }
+}
+
+impl UniformValue for u32 {
+    const TYPE: GLenum = gl::UNSIGNED_INT;
+    unsafe fn set(&self, program: GLuint, location: GLint) {
+        gl::ProgramUniform1ui(program, location, *self);
+    }
 }
 
 impl LinkError {
-    fn info_log(&self) -> String {
+    pub fn info_log(&self) -> String {
         let LinkError(program) = self;
         program.info_log()
     }
@@ -197,3 +577,391 @@ impl Error for LinkError {
     }
 }
 
+
+// Where shader sources and the snippets they include are read from.
+pub enum Library {
+    // (name, source) pairs built into the executable
+    Embedded(&'static [(&'static str, &'static str)]),
+    Dir(PathBuf),
+}
+
+// A preprocessed shader. `#line` directives number the files it was made
+// from in `files` order, so compile logs can be mapped back to them.
+pub struct Source {
+    pub text:  String,
+    pub files: Vec<String>,
+}
+
+impl Library {
+    fn read(&self, name: &str) -> Result<String, Box<dyn Error>> {
+        match self {
+            Library::Embedded(sources) => sources.iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, src)| src.to_string())
+                .ok_or_else(|| format!("no shader source named {}", name).into()),
+            Library::Dir(dir) => fs::read_to_string(dir.join(name))
+                .map_err(|e| format!("{}: {}", name, e).into()),
+        }
+    }
+
+    // Resolves `#include "name"` lines and puts `defines` after the
+    // `#version` line. Each file is included at most once.
+    pub fn preprocess(&self, name: &str, defines: &[(&str, &str)])
+        -> Result<Source, Box<dyn Error>>
+    {
+        let mut source = Source { text: String::new(), files: Vec::new() };
+        let mut stack = Vec::new();
+        self.expand(name, defines, &mut source, &mut stack)?;
+        Ok(source)
+    }
+
+    fn expand(
+        &self,
+        name:    &str,
+        defines: &[(&str, &str)],
+        out:     &mut Source,
+        stack:   &mut Vec<String>)
+        -> Result<(), Box<dyn Error>>
+    {
+        if let Some(start) = stack.iter().position(|n| n == name) {
+            let cycle = stack[start..].join(" -> ");
+            return Err(format!("include cycle: {} -> {}", cycle, name).into());
+        }
+        if out.files.iter().any(|f| f == name) {
+            return Ok(());
+        }
+
+        let src = self.read(name)?;
+        let file = out.files.len();
+        out.files.push(name.to_string());
+        stack.push(name.to_string());
+
+        // the main file's #version has to stay first
+        let is_main = file == 0;
+        if !is_main {
+            out.text += &format!("#line 1 {}\n", file);
+        }
+
+        for (index, line) in src.lines().enumerate() {
+            let line_no = index + 1;
+            let trimmed = line.trim_start();
+
+            if let Some(arg) = trimmed.strip_prefix("#include") {
+                let arg = arg.trim();
+                let included = arg.trim_matches('"');
+                if included.len() + 2 != arg.len() || !arg.starts_with('"') {
+                    return Err(format!("{}:{}: bad #include", name, line_no).into());
+                }
+                self.expand(included, &[], out, stack)?;
+                out.text += &format!("#line {} {}\n", line_no + 1, file);
+                continue;
+            }
+
+            out.text += line;
+            out.text.push('\n');
+
+            if is_main && trimmed.starts_with("#version") {
+                for (key, value) in defines {
+                    out.text += &format!("#define {} {}\n", key, value);
+                }
+                out.text += &format!("#line {} {}\n", line_no + 1, file);
+            }
+        }
+
+        stack.pop();
+        Ok(())
+    }
+}
+
+impl Source {
+    // Rewrites the `file:line` or `file(line)` references that drivers put
+    // in info logs so they name files instead of numbers.
+    pub fn map_log(&self, log: &str) -> String {
+        log.lines()
+            .map(|line| self.map_log_line(line))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn map_log_line(&self, line: &str) -> String {
+        let bytes = line.as_bytes();
+        let mut start = 0;
+        while start < bytes.len() {
+            let digits = bytes[start..].iter().take_while(|b| b.is_ascii_digit()).count();
+            let end = start + digits;
+            let at_word_start = start == 0 || !bytes[start - 1].is_ascii_alphanumeric();
+
+            if digits > 0 && at_word_start && end + 1 < bytes.len()
+                && (bytes[end] == b':' || bytes[end] == b'(')
+                && bytes[end + 1].is_ascii_digit()
+            {
+                if let Some(file) = line[start..end].parse::<usize>().ok()
+                    .and_then(|n| self.files.get(n))
+                {
+                    return format!("{}{}{}", &line[..start], file, &line[end..]);
+                }
+            }
+            start = end + 1;
+        }
+        line.to_string()
+    }
+}
+
+// Errors unless `stages` make a program GL can link: a compute stage on its
+// own, or a vertex stage with optional geometry and fragment stages, each
+// at most once.
+fn check_stages(stages: &[(Stage, &str)]) -> Result<(), String> {
+    let count = |wanted: Stage| stages.iter().filter(|(stage, _)| *stage == wanted).count();
+    if let Some((stage, name)) = stages.iter().find(|(stage, _)| count(*stage) > 1) {
+        return Err(format!("{:?} stage given more than once, by {}", stage, name));
+    }
+
+    let compute = count(Stage::Compute) > 0;
+    if compute && stages.len() > 1 {
+        Err("a compute stage can't be linked with other stages".to_string())
+    }
+    else if !compute && count(Stage::Vertex) == 0 {
+        Err("no vertex stage".to_string())
+    }
+    else {
+        Ok(())
+    }
+}
+
+// Preprocesses, compiles and links a program from `library`, and checks it
+// against `interface`. Compile errors name the original files and lines.
+// With a cache, a binary of the same sources linked by an earlier run is
+// used instead if the driver accepts it. Also returns the names of every
+// file read, includes too.
+pub fn build(
+    library:   &Library,
+    stages:    &[(Stage, &str)],
+    defines:   &[(&str, &str)],
+    interface: &Interface,
+    cache:     Option<&ProgramCache>)
+    -> Result<(Program, Vec<String>), Box<dyn Error>>
+{
+    let names: Vec<&str> = stages.iter().map(|(_, name)| *name).collect();
+    check_stages(stages)
+        .map_err(|e| format!("{}: {}", names.join(" + "), e))?;
+
+    let mut sources = Vec::with_capacity(stages.len());
+    let mut files = Vec::new();
+    for (stage, name) in stages {
+        let source = library.preprocess(name, defines)?;
+        files.extend(source.files.iter().cloned());
+        sources.push((*stage, *name, source));
+    }
+    files.sort();
+    files.dedup();
+
+    let cache = cache.map(|cache| {
+        let texts: Vec<(Stage, &str)> = sources.iter()
+            .map(|(stage, _, source)| (*stage, source.text.as_str()))
+            .collect();
+        (cache, cache.key(&texts))
+    });
+
+    if let Some(program) = cache.and_then(|(cache, key)| cache.load(key)) {
+        // the key covers the sources, but not what the caller expects
+        if program.check(interface).is_ok() {
+            return Ok((program, files));
+        }
+    }
+
+    let mut units = Vec::with_capacity(stages.len());
+    for (stage, name, source) in &sources {
+        let unit = compile(*stage, &source.text)
+            .map_err(|e| format!("{}:\n{}", name, source.map_log(&e.info_log())))?;
+        units.push(unit);
+    }
+
+    let program = link(&units)
+        .map_err(|e| format!("{}: {}", names.join(" + "), e))?;
+    if let Err(e) = program.check(interface) {
+        return Err(format!("{}: {}", names.join(" + "), e).into());
+    }
+
+    if let Some((cache, key)) = cache {
+        if let Err(e) = cache.store(key, &program) {
+            eprintln!("couldn't cache {}: {}", names.join(" + "), e);
+        }
+    }
+
+    Ok((program, files))
+}
+
+// A program built from a directory of sources, rebuilt when any file it
+// uses changes. If the new sources fail to compile, link or match the
+// interface, the error is printed and the last working program stays in use.
+pub struct HotProgram {
+    library:   Library,
+    stages:    Vec<(Stage, String)>,
+    defines:   Vec<(String, String)>,
+    interface: Interface,
+    watcher:   Watcher,
+    program:   Program,
+}
+
+impl HotProgram {
+    // Unlike a reload, the first build has nothing to fall back on, so its
+    // failure is an error.
+    pub fn load(
+        dir:       &Path,
+        stages:    &[(Stage, &str)],
+        defines:   &[(&str, &str)],
+        interface: &Interface)
+        -> Result<HotProgram, Box<dyn Error>>
+    {
+        let library = Library::Dir(dir.to_path_buf());
+        let (program, files) = build(&library, stages, defines, interface, None)?;
+        Ok(HotProgram {
+            watcher:   HotProgram::watch(dir, &files),
+            library,
+            stages:    stages.iter().map(|(stage, name)| (*stage, name.to_string())).collect(),
+            defines:   defines.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            interface: *interface,
+            program,
+        })
+    }
+
+    fn watch(dir: &Path, files: &[String]) -> Watcher {
+        Watcher::new(files.iter().map(|name| dir.join(name)))
+    }
+
+    // Rebuilds the program if a source changed. True if it was replaced.
+    pub fn reload_changed(&mut self) -> bool {
+        if !self.watcher.poll() {
+            return false;
+        }
+
+        let stages: Vec<(Stage, &str)> = self.stages.iter()
+            .map(|(stage, name)| (*stage, name.as_str()))
+            .collect();
+        let defines: Vec<(&str, &str)> = self.defines.iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+
+        // sources being edited aren't worth caching
+        match build(&self.library, &stages, &defines, &self.interface, None) {
+            Ok((program, files)) => {
+                eprintln!("reloaded shaders {}", files.join(", "));
+                // includes may have been added or removed
+                if let Library::Dir(dir) = &self.library {
+                    self.watcher = HotProgram::watch(dir, &files);
+                }
+                self.program = program;
+                true
+            }
+
+            Err(e) => {
+                eprintln!("shader reload failed, keeping the old program:\n{}", e);
+                false
+            }
+        }
+    }
+
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static SOURCES: &[(&str, &str)] = &[
+        ("main.glsl",   "#version 450\n#include \"common.glsl\"\n#include \"util.glsl\"\nvoid main() {}\n"),
+        ("common.glsl", "float common();\n"),
+        ("util.glsl",   "#include \"common.glsl\"\nfloat util();\n"),
+        ("plain.glsl",  "#version 450\nvoid main() {}\n"),
+        ("self.glsl",   "#include \"self.glsl\"\n"),
+        ("ping.glsl",   "#version 450\n#include \"pong.glsl\"\n"),
+        ("pong.glsl",   "#include \"ping.glsl\"\n"),
+        ("bad.glsl",    "#version 450\n#include <common.glsl>\n"),
+        ("lost.glsl",   "#version 450\n#include \"nowhere.glsl\"\n"),
+    ];
+    const LIBRARY: Library = Library::Embedded(SOURCES);
+
+    fn error(name: &str) -> String {
+        match LIBRARY.preprocess(name, &[]) {
+            Ok(_)  => panic!("{} preprocessed", name),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn includes_each_file_once() {
+        let source = LIBRARY.preprocess("main.glsl", &[]).unwrap();
+        assert_eq!(source.files, ["main.glsl", "common.glsl", "util.glsl"]);
+        assert_eq!(source.text, concat!(
+            "#version 450\n",
+            "#line 2 0\n",
+            "#line 1 1\n",
+            "float common();\n",
+            "#line 3 0\n",
+            "#line 1 2\n",
+            // util.glsl's include of common.glsl is dropped
+            "#line 2 2\n",
+            "float util();\n",
+            "#line 4 0\n",
+            "void main() {}\n",
+        ));
+    }
+
+    #[test]
+    fn puts_defines_after_version() {
+        let source = LIBRARY.preprocess("plain.glsl", &[("A", "1"), ("B", "x")]).unwrap();
+        assert_eq!(source.text, concat!(
+            "#version 450\n",
+            "#define A 1\n",
+            "#define B x\n",
+            "#line 2 0\n",
+            "void main() {}\n",
+        ));
+    }
+
+    #[test]
+    fn rejects_cycles_and_bad_includes() {
+        assert_eq!(error("self.glsl"), "include cycle: self.glsl -> self.glsl");
+        assert_eq!(error("ping.glsl"), "include cycle: ping.glsl -> pong.glsl -> ping.glsl");
+        assert_eq!(error("bad.glsl"), "bad.glsl:2: bad #include");
+        assert_eq!(error("lost.glsl"), "no shader source named nowhere.glsl");
+    }
+
+    #[test]
+    fn checks_stage_combinations() {
+        use self::Stage::*;
+        assert!(check_stages(&[(Vertex, "v"), (Fragment, "f")]).is_ok());
+        assert!(check_stages(&[(Vertex, "v"), (Geometry, "g"), (Fragment, "f")]).is_ok());
+        assert!(check_stages(&[(Vertex, "v")]).is_ok());
+        assert!(check_stages(&[(Compute, "c")]).is_ok());
+
+        assert!(check_stages(&[]).is_err());
+        assert!(check_stages(&[(Geometry, "g"), (Fragment, "f")]).is_err());
+        assert!(check_stages(&[(Compute, "c"), (Vertex, "v")]).is_err());
+        assert_eq!(
+            check_stages(&[(Vertex, "a"), (Fragment, "f"), (Vertex, "b")]),
+            Err("Vertex stage given more than once, by a".to_string()),
+        );
+    }
+
+    #[test]
+    fn maps_log_lines_to_files() {
+        let source = LIBRARY.preprocess("main.glsl", &[]).unwrap();
+        let log = concat!(
+            "0:4(6): error: syntax error\n",
+            "ERROR: 1:1: 'common' : redefinition\n",
+            "2(2) : warning C7050: unused\n",
+            "7:3: no such file\n",
+            "vec2:1 isn't a reference",
+        );
+        assert_eq!(source.map_log(log), concat!(
+            "main.glsl:4(6): error: syntax error\n",
+            "ERROR: common.glsl:1: 'common' : redefinition\n",
+            "util.glsl(2) : warning C7050: unused\n",
+            "7:3: no such file\n",
+            "vec2:1 isn't a reference",
+        ));
+    }
+}
@@ -0,0 +1,189 @@
+
+use {
+    super::Buffer,
+    std::{
+        collections::VecDeque,
+        error::Error,
+        fmt,
+        mem,
+        ptr,
+    },
+    gl::types::*,
+};
+
+const MAP_FLAGS: GLbitfield = gl::MAP_WRITE_BIT | gl::MAP_PERSISTENT_BIT | gl::MAP_COHERENT_BIT;
+
+// allocations start on this boundary, enough for any vertex attribute
+const ALIGN: usize = 4;
+
+const MAX_CAPACITY: usize = 64 << 20;
+
+struct Fence {
+    start: usize,
+    end:   usize,
+    sync:  GLsync,
+}
+
+// Waiting for the GPU to finish with part of the buffer failed, with the
+// given GL error.
+#[derive(Debug)]
+pub struct WaitFailed(pub GLenum);
+
+impl fmt::Display for WaitFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Waiting on a stream buffer fence failed: GL error {:#x}", self.0)
+    }
+}
+
+impl Error for WaitFailed { }
+
+// A persistently mapped buffer written as a ring, for data that changes every
+// frame. Each write is fenced once the draw using it has been issued, and a
+// write only waits for the GPU if it would overwrite data a draw still
+// needs. If a frame writes more than fits, the buffer grows at the start of
+// the next one.
+pub struct StreamBuffer {
+    buf:      Buffer,
+    ptr:      *mut u8,
+    capacity: usize,
+    head:     usize,
+    fences:   VecDeque<Fence>,
+    // the latest write, until it's fenced
+    unfenced: Option<(usize, usize)>,
+    // bytes written since begin_frame
+    written:  usize,
+}
+
+impl StreamBuffer {
+    pub fn new(capacity: usize) -> StreamBuffer {
+        let (buf, ptr) = StreamBuffer::allocate(capacity);
+        StreamBuffer {
+            buf,
+            ptr,
+            capacity,
+            head:     0,
+            fences:   VecDeque::new(),
+            unfenced: None,
+            written:  0,
+        }
+    }
+
+    fn allocate(capacity: usize) -> (Buffer, *mut u8) {
+        let buf = Buffer::new();
+        let ptr = unsafe {
+            gl::NamedBufferStorage(buf.handle(), capacity as isize, ptr::null(), MAP_FLAGS);
+            gl::MapNamedBufferRange(buf.handle(), 0, capacity as isize, MAP_FLAGS) as *mut u8
+        };
+        assert!(!ptr.is_null(), "Failed to map stream buffer");
+        (buf, ptr)
+    }
+
+    pub fn buffer(&self) -> GLuint {
+        self.buf.handle()
+    }
+
+    // The most items of type T a single write can take; larger uploads have
+    // to be split.
+    pub fn max_items<T>(&self) -> usize {
+        self.capacity / mem::size_of::<T>()
+    }
+
+    // Grows the buffer if the last frame didn't fit in it, which would have
+    // made it wait on its own draws.
+    pub fn begin_frame(&mut self) {
+        let wanted = self.written;
+        self.written = 0;
+        if wanted <= self.capacity || self.capacity >= MAX_CAPACITY {
+            return;
+        }
+
+        let mut capacity = self.capacity;
+        while capacity < wanted && capacity < MAX_CAPACITY {
+            capacity *= 2;
+        }
+
+        // draws still reading the old buffer keep it alive until they finish
+        self.release_fences();
+        let (buf, ptr) = StreamBuffer::allocate(capacity);
+        self.buf = buf;
+        self.ptr = ptr;
+        self.capacity = capacity;
+        self.head = 0;
+    }
+
+    // Copies `data` into the buffer and returns its byte offset. Call `fence`
+    // after issuing the draw that reads it. If waiting for a draw to finish
+    // with the space fails, nothing is written and the draw should be
+    // skipped.
+    pub fn write<T: Copy>(&mut self, data: &[T]) -> Result<usize, WaitFailed> {
+        let size = mem::size_of_val(data);
+        assert!(size <= self.capacity, "Write larger than the stream buffer");
+        assert!(self.unfenced.is_none(), "Previous write wasn't fenced");
+
+        let mut start = self.head.div_ceil(ALIGN) * ALIGN;
+        if start + size > self.capacity {
+            start = 0;
+        }
+        let end = start + size;
+
+        self.wait_for(start, end)?;
+
+        unsafe {
+            ptr::copy_nonoverlapping(
+                data.as_ptr() as *const u8,
+                self.ptr.add(start),
+                size
+            );
+        }
+
+        self.head = end;
+        self.written += size;
+        self.unfenced = Some((start, end));
+        Ok(start)
+    }
+
+    // Marks the latest write as in use by the draws issued so far.
+    pub fn fence(&mut self) {
+        if let Some((start, end)) = self.unfenced.take() {
+            let sync = unsafe { gl::FenceSync(gl::SYNC_GPU_COMMANDS_COMPLETE, 0) };
+            self.fences.push_back(Fence { start, end, sync });
+        }
+    }
+
+    // Waits until no pending draw reads from [start, end). Fences are
+    // waited on oldest first, which is the order the ring reuses space in.
+    fn wait_for(&mut self, start: usize, end: usize) -> Result<(), WaitFailed> {
+        while self.fences.iter().any(|f| f.start < end && start < f.end) {
+            let sync = self.fences.front().unwrap().sync;
+            unsafe {
+                loop {
+                    let status = gl::ClientWaitSync(sync, gl::SYNC_FLUSH_COMMANDS_BIT, 1_000_000);
+                    match status {
+                        gl::TIMEOUT_EXPIRED => continue,
+                        // the range may still be in use, so the fence stays
+                        // for later writes to wait on too
+                        gl::WAIT_FAILED => return Err(WaitFailed(gl::GetError())),
+                        _ => break,
+                    }
+                }
+                gl::DeleteSync(sync);
+            }
+            self.fences.pop_front();
+        }
+        Ok(())
+    }
+
+    fn release_fences(&mut self) {
+        for fence in self.fences.drain(..) {
+            unsafe { gl::DeleteSync(fence.sync); }
+        }
+        self.unfenced = None;
+    }
+}
+
+// Deleting the buffer unmaps it.
+impl Drop for StreamBuffer {
+    fn drop(&mut self) {
+        self.release_fences();
+    }
+}
@@ -1,5 +1,7 @@
 
-#![feature(clamp)]
+// Safety requirements are documented in plain comments, like everything
+// else here, and clippy only looks for them in doc comments.
+#![allow(clippy::missing_safety_doc)]
 
 type Event = glutin::event::Event<()>;
 
@@ -7,4 +9,5 @@ pub mod game;
 pub mod gfx;
 pub mod damage;
 pub mod alg;
+pub mod watch;
 
@@ -1,8 +1,7 @@
 
 #version 450
 
-layout(location = 1) uniform vec2 scale;
-layout(location = 2) uniform vec2 offset;
+#include "camera.glsl"
 
 layout(location = 0) in vec2 attr_vert;
 layout(location = 1) in vec4 attr_colour;
@@ -10,8 +9,9 @@ layout(location = 1) in vec4 attr_colour;
 smooth out vec4 colour;
 
 void main() {
-    vec2 coords = scale * (attr_vert + offset);
-    gl_Position = vec4(coords, -0.5, 1.0);
+    // over every sprite, including those on layer 1, which lines would tie
+    // with in the depth test
+    gl_Position = world_to_clip(attr_vert, 1.25);
     colour = attr_colour;
 }
 
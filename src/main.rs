@@ -3,7 +3,7 @@
 extern crate static_assertions;
 
 use {
-    std::{ error::Error, ptr, ffi, slice, str },
+    std::{ error::Error, ptr, ffi, slice, str, path::Path },
     glutin::{
         event_loop::EventLoop,
         platform::unix::EventLoopWindowTargetExtUnix
@@ -33,13 +33,160 @@ extern "system" fn on_gl_debug(
     );
 }
 
+// Loads GL functions for the current context and routes debug output to
+// stderr.
+fn init_gl(get_proc_address: impl Fn(&str) -> *const ffi::c_void) {
+    gl::load_with(get_proc_address);
+
+    {   let mut major: GLint = 0;
+        let mut minor: GLint = 0;
+        unsafe {
+            gl::GetIntegerv(gl::MAJOR_VERSION, &mut major);
+            gl::GetIntegerv(gl::MINOR_VERSION, &mut minor);
+        }
+        eprintln!("Using OpenGL {}.{} Core profile", major, minor);
+    }
+
+    unsafe {
+        gl::DebugMessageControl(
+            gl::DONT_CARE,
+            gl::DONT_CARE,
+            gl::DONT_CARE,
+            0, ptr::null(),
+            gl::TRUE
+        );
+        gl::DebugMessageCallback(on_gl_debug, ptr::null());
+    }
+}
+
+const USAGE: &str = "usage: rust-game [MAP] [--shader-dir DIR] \
+    [--screenshot OUT.png] [--golden GOLDEN.png [--tolerance N] [--update-golden]] \
+    [--ticks N] [--size WxH]";
+
+struct Args {
+    map_path:      String,
+    // read shaders from here and reload them when edited
+    shader_dir:    Option<String>,
+    screenshot:    Option<String>,
+    // compare the offscreen frame against this image
+    golden:        Option<String>,
+    // how far each channel may be off from the golden image
+    tolerance:     u8,
+    // write the frame as the golden image instead of comparing
+    update_golden: bool,
+    ticks:         u32,
+    size:          (i32, i32),
+}
+
+fn parse_args() -> Result<Args, Box<dyn Error>> {
+    let mut parsed = Args {
+        map_path:      "test.tmx".to_string(),
+        shader_dir:    None,
+        screenshot:    None,
+        golden:        None,
+        tolerance:     2,
+        update_golden: false,
+        ticks:         60,
+        size:          (1024, 1024),
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(USAGE);
+        match arg.as_str() {
+            "--shader-dir"    => parsed.shader_dir = Some(value()?),
+            "--screenshot"    => parsed.screenshot = Some(value()?),
+            "--golden"        => parsed.golden = Some(value()?),
+            "--tolerance"     => parsed.tolerance = value()?.parse()?,
+            "--ticks"         => parsed.ticks = value()?.parse()?,
+            "--update-golden" => parsed.update_golden = true,
+            "--size"          => {
+                let size = value()?;
+                let (w, h) = size.split_once('x').ok_or(USAGE)?;
+                parsed.size = (w.parse()?, h.parse()?);
+            }
+            _ if arg.starts_with("--") => return Err(USAGE.into()),
+            _ => parsed.map_path = arg,
+        }
+    }
+
+    if parsed.update_golden && parsed.golden.is_none() {
+        return Err(USAGE.into());
+    }
+
+    Ok(parsed)
+}
+
+// Regression check for rendering. On a mismatch the frame is written next
+// to the golden image for comparison. With `update` the frame replaces the
+// golden image, to be looked over and checked in.
+fn check_golden(image: &image::RgbaImage, path: &Path, tolerance: u8, update: bool)
+    -> Result<(), Box<dyn Error>>
+{
+    if update {
+        image.save(path)?;
+        eprintln!("wrote golden image {}", path.display());
+        return Ok(());
+    }
+    if !path.exists() {
+        return Err(format!(
+            "no golden image at {}; run with --update-golden to create it",
+            path.display()
+        ).into());
+    }
+
+    let expected = image::open(path)?.to_rgba();
+    let failure = match gfx::offscreen::diff_images(image, &expected, tolerance) {
+        Some(diff) if diff.pixels == 0 => {
+            eprintln!("matches {}", path.display());
+            return Ok(());
+        }
+        Some(diff) => format!(
+            "{} pixels differ from {} by up to {}",
+            diff.pixels, path.display(), diff.max_delta
+        ),
+        None => format!(
+            "frame is {}x{} but {} is {}x{}",
+            image.width(), image.height(), path.display(), expected.width(), expected.height()
+        ),
+    };
+
+    let actual = path.with_extension("actual.png");
+    image.save(&actual)?;
+    Err(format!("{}; wrote the frame to {}", failure, actual.display()).into())
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
+    let args = parse_args()?;
+
     let event_q = EventLoop::new();
     eprintln!(
         "Running on {}",
         if event_q.is_wayland() { "Wayland" } else { "X11" }
     );
 
+    // render one frame offscreen, save or check it and exit
+    if args.screenshot.is_some() || args.golden.is_some() {
+        let ctx = glutin::ContextBuilder::new()
+            .with_gl(glutin::GlRequest::Latest)
+            .with_gl_profile(glutin::GlProfile::Core)
+            .with_gl_debug_flag(true)
+            .build_headless(&event_q, glutin::dpi::PhysicalSize::new(1.0, 1.0))?;
+        let ctx = unsafe { ctx.make_current().map_err(|(_, e)| e)? };
+        init_gl(|sym| ctx.get_proc_address(sym) as *const _);
+
+        let (width, height) = args.size;
+        let image = game::screenshot(args.map_path.as_ref(), args.ticks, width, height)?;
+        if let Some(out_path) = &args.screenshot {
+            image.save(out_path)?;
+            eprintln!("wrote {}", out_path);
+        }
+        if let Some(golden) = &args.golden {
+            check_golden(&image, golden.as_ref(), args.tolerance, args.update_golden)?;
+        }
+        return Ok(());
+    }
+
     let ctx = {
         let win_builder = glutin::window::WindowBuilder::new()
             .with_title("rust game :)");
@@ -56,35 +203,18 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let (event_sender, event_receiver) = std::sync::mpsc::channel();
 
+    let map_path = args.map_path;
+    let shader_dir = args.shader_dir;
     std::thread::spawn(move || {
         let ctx = unsafe {
             ctx.make_current()
                .expect("Error making GL context current")
         };
 
-        gl::load_with(|sym| { ctx.get_proc_address(sym) as *const _ });
-
-        {   let mut major: GLint = 0;
-            let mut minor: GLint = 0;
-            unsafe {
-                gl::GetIntegerv(gl::MAJOR_VERSION, &mut major);
-                gl::GetIntegerv(gl::MINOR_VERSION, &mut minor);
-            }
-            eprintln!("Using OpenGL {}.{} Core profile", major, minor);
-        }
-
-        unsafe {
-            gl::DebugMessageControl(
-                gl::DONT_CARE,
-                gl::DONT_CARE,
-                gl::DONT_CARE,
-                0, ptr::null(),
-                gl::TRUE
-            );
-            gl::DebugMessageCallback(on_gl_debug, ptr::null());
-        }
+        init_gl(|sym| ctx.get_proc_address(sym) as *const _);
 
-        game::main_thread(&ctx, &event_receiver).unwrap();
+        let shader_dir = shader_dir.as_ref().map(AsRef::as_ref);
+        game::main_thread(&ctx, &event_receiver, map_path.as_ref(), shader_dir).unwrap();
     });
 
     event_q.run(move |event, _, flow| {
@@ -95,4 +225,3 @@ fn main() -> Result<(), Box<dyn Error>> {
         };
     });
 }
-
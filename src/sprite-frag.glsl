@@ -4,11 +4,14 @@
 layout(binding = 0) uniform sampler2DArray tex;
 
 smooth in vec3 tcoords;
+flat in vec4 tint;
 
 out vec4 frag;
 
 void main() {
 //  frag = vec4(1.0, 1.0, 1.0, 1.0);
-    frag = texture(tex, tcoords);
+    frag = texture(tex, tcoords) * tint;
+    if (frag.a < ALPHA_CUTOFF) {
+        discard;
+    }
 }
-
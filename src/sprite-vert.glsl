@@ -1,31 +1,45 @@
 
 #version 450
 
-layout(location = 1) uniform vec2 scale;
-layout(location = 2) uniform vec2 offset;
+#include "camera.glsl"
 
 layout(location = 0) in vec4 attr_rect;
 layout(location = 1) in uint attr_tex_index;
+layout(location = 2) in vec4 attr_tint;
+layout(location = 3) in vec2 attr_pivot;
+layout(location = 4) in float attr_rotation;
+layout(location = 5) in float attr_layer;
+layout(location = 6) in uint attr_flags;
+layout(location = 7) in vec4 attr_uv;
+
+const uint FLIP_X = 1u;
+const uint FLIP_Y = 2u;
 
 smooth out vec3 tcoords;
+flat out vec4 tint;
 
 void main() {
-    vec2[4] coord_tab = vec2[4] (
-        vec2(attr_rect[0], attr_rect[1]),
-        vec2(attr_rect[0], attr_rect[3]),
-        vec2(attr_rect[2], attr_rect[3]),
-        vec2(attr_rect[2], attr_rect[1])
-    );
-
-    vec2[4] tcoord_tab = vec2[4] (
-        vec2(0.0, 1.0),
+    // each vertex's place across the rect
+    vec2[4] corner_tab = vec2[4] (
         vec2(0.0, 0.0),
-        vec2(1.0, 0.0),
-        vec2(1.0, 1.0)
+        vec2(0.0, 1.0),
+        vec2(1.0, 1.0),
+        vec2(1.0, 0.0)
     );
 
-    vec2 coords = scale * (coord_tab[gl_VertexID] + offset);
-    gl_Position = vec4(coords, -0.5, 1.0);
-    tcoords = vec3(tcoord_tab[gl_VertexID], float(attr_tex_index));
-}
+    vec2 corner = corner_tab[gl_VertexID];
+    vec2 coords = mix(attr_rect.xy, attr_rect.zw, corner);
+
+    if ((attr_flags & FLIP_X) != 0u) { corner.x = 1.0 - corner.x; }
+    if ((attr_flags & FLIP_Y) != 0u) { corner.y = 1.0 - corner.y; }
+    vec2 tcoord = mix(attr_uv.xy, attr_uv.zw, corner);
 
+    vec2 pivot = mix(attr_rect.xy, attr_rect.zw, attr_pivot);
+    float c = cos(attr_rotation);
+    float s = sin(attr_rotation);
+    coords = pivot + mat2(c, s, -s, c) * (coords - pivot);
+
+    gl_Position = world_to_clip(coords, attr_layer);
+    tcoords = vec3(tcoord, float(attr_tex_index));
+    tint = attr_tint;
+}
@@ -0,0 +1,57 @@
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+// Polls a set of files' modification times. Cheap enough to call every
+// frame; the filesystem is only checked every POLL_INTERVAL.
+pub struct Watcher {
+    files:     Vec<(PathBuf, Option<SystemTime>)>,
+    last_poll: Instant,
+}
+
+impl Watcher {
+    pub fn new(paths: impl IntoIterator<Item = PathBuf>) -> Watcher {
+        let files = paths.into_iter()
+            .map(|path| {
+                let time = modified(&path);
+                (path, time)
+            })
+            .collect();
+
+        Watcher { files, last_poll: Instant::now() }
+    }
+
+    // True if any watched file changed since the last call that returned
+    // true.
+    pub fn poll(&mut self) -> bool {
+        !self.poll_changed().is_empty()
+    }
+
+    // The watched files that changed since they were last reported.
+    pub fn poll_changed(&mut self) -> Vec<PathBuf> {
+        let now = Instant::now();
+        if now - self.last_poll < POLL_INTERVAL {
+            return Vec::new();
+        }
+        self.last_poll = now;
+
+        let mut changed = Vec::new();
+        for (path, time) in &mut self.files {
+            let new_time = modified(path);
+            if new_time != *time {
+                *time = new_time;
+                changed.push(path.clone());
+            }
+        }
+        changed
+    }
+}
@@ -28,14 +28,14 @@
  <tile id="5">
   <objectgroup draworder="index" id="2">
    <object id="1" x="16" y="2">
-    <polyline points="0,-2 -16,14 0,14"/>
+    <polygon points="0,-2 -16,14 0,14"/>
    </object>
   </objectgroup>
  </tile>
  <tile id="6">
   <objectgroup draworder="index" id="2">
    <object id="1" x="0" y="2">
-    <polyline points="0,-2 16,14 0,14"/>
+    <polygon points="0,-2 16,14 0,14"/>
    </object>
   </objectgroup>
  </tile>
@@ -47,14 +47,14 @@
  <tile id="9">
   <objectgroup draworder="index" id="2">
    <object id="1" x="16" y="7">
-    <polyline points="0,1 -16,9 0,9"/>
+    <polygon points="0,1 -16,9 0,9"/>
    </object>
   </objectgroup>
  </tile>
  <tile id="10">
   <objectgroup draworder="index" id="2">
    <object id="1" x="16" y="16">
-    <polyline points="0,0 -16,-8 -16,0"/>
+    <polygon points="0,0 -16,-8 -16,0"/>
    </object>
   </objectgroup>
  </tile>